@@ -0,0 +1,189 @@
+use std::path::Path;
+
+use crate::resolve::split_version;
+
+/// What cargo-xbuild needs to know about the args forwarded to cargo.
+/// Only the part before a `--` separator is inspected.
+#[derive(Debug, Default)]
+pub struct CargoArgs {
+    /// The cargo subcommand (`build`, `test`, …), if the args start with one
+    /// (after any `+toolchain`)
+    pub command: Option<String>,
+    /// `-p`/`--package` selections
+    pub packages: Vec<String>,
+    /// `--features`/`-F` entries, split on commas and whitespace
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+    /// `--all-features`
+    pub all_features: bool,
+    /// `--workspace` (or its alias `--all`)
+    pub workspace: bool,
+    /// `--offline`/`--frozen`/`--locked`, to forward to the cargo
+    /// invocations xbuild makes itself
+    pub network_flags: Vec<String>,
+    /// `--target <triple>`
+    pub target: Option<String>,
+    /// `--target-dir <dir>`, as given
+    pub target_dir: Option<String>,
+}
+
+impl CargoArgs {
+    /// A leading rustup `+toolchain` is skipped, so the command after it
+    /// is still found.
+    pub fn parse(args: &[String]) -> Self {
+        let args = match args.first() {
+            Some(toolchain) if toolchain.starts_with('+') => &args[1..],
+            _ => args,
+        };
+        let mut parsed = CargoArgs {
+            command: args.first().filter(|a| !a.starts_with('-')).cloned(),
+            ..CargoArgs::default()
+        };
+        let mut iter = args.iter().take_while(|a| a.as_str() != "--");
+        while let Some(arg) = iter.next() {
+            let arg = arg.as_str();
+            if let Some(v) = flag_value(arg, &["-p", "--package"], &mut iter) {
+                parsed.packages.push(v);
+            } else if let Some(v) = flag_value(arg, &["-F", "--features"], &mut iter) {
+                parsed.features.extend(
+                    v.split([',', ' '])
+                        .filter(|f| !f.is_empty())
+                        .map(str::to_string),
+                );
+            } else if arg == "--no-default-features" {
+                parsed.no_default_features = true;
+            } else if arg == "--all-features" {
+                parsed.all_features = true;
+            } else if arg == "--workspace" || arg == "--all" {
+                parsed.workspace = true;
+            } else if let Some(v) = flag_value(arg, &["--target"], &mut iter) {
+                parsed.target = Some(v);
+            } else if let Some(v) = flag_value(arg, &["--target-dir"], &mut iter) {
+                parsed.target_dir = Some(v);
+            } else if matches!(arg, "--offline" | "--frozen" | "--locked") {
+                parsed.network_flags.push(arg.to_string());
+            }
+        }
+        parsed
+    }
+
+    /// Whether cargo must not touch the network (`--offline`, or
+    /// `--frozen` which implies it).
+    pub fn offline(&self) -> bool {
+        self.network_flags
+            .iter()
+            .any(|f| f == "--offline" || f == "--frozen")
+    }
+
+    /// Flags for the Phase 1 xdeps build, so the injected rlibs are built
+    /// for the same `--target` into the same `--target-dir` (resolved
+    /// against `workspace`, where the real build runs) as the crates that
+    /// use them. `CARGO_TARGET_DIR` needs nothing: it is inherited.
+    pub fn xdeps_flags(&self, workspace: &Path) -> Vec<String> {
+        let mut flags = Vec::new();
+        if self.offline() {
+            flags.push("--offline".to_string());
+        }
+        if let Some(target) = &self.target {
+            flags.extend(["--target".to_string(), target.clone()]);
+        }
+        if let Some(dir) = &self.target_dir {
+            let dir = workspace.join(dir);
+            flags.extend(["--target-dir".to_string(), dir.display().to_string()]);
+        }
+        flags
+    }
+
+    /// Whether the command builds test targets, which see dev-dependencies.
+    pub fn builds_tests(&self) -> bool {
+        matches!(self.command.as_deref(), Some("test" | "t" | "bench"))
+    }
+
+    /// Explicit features as (crate, feature) pairs. `crate/feat` names its
+    /// crate; a bare `feat` applies to each `-p` package (by name: `-p
+    /// foo@1.2` gives `foo`), or without `-p` to `default_packages`, those
+    /// cargo selects itself (`resolve::default_packages`).
+    pub fn features_by_crate(&self, default_packages: &[String]) -> Vec<(String, String)> {
+        let selected: Vec<&str> = if self.packages.is_empty() {
+            default_packages.iter().map(String::as_str).collect()
+        } else {
+            self.packages.iter().map(|p| split_version(p).0).collect()
+        };
+        let mut out = Vec::new();
+        for f in &self.features {
+            match f.split_once('/') {
+                Some((krate, feat)) => out.push((krate.to_string(), feat.to_string())),
+                None => {
+                    for p in &selected {
+                        out.push((p.to_string(), f.clone()));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Match `arg` against `names` in `--name value`, `--name=value` and
+/// `-pvalue` forms, pulling the value from `rest` when separate.
+fn flag_value<'a>(
+    arg: &str,
+    names: &[&str],
+    rest: &mut impl Iterator<Item = &'a String>,
+) -> Option<String> {
+    for name in names {
+        if arg == *name {
+            return rest.next().cloned();
+        }
+        if let Some(v) = arg.strip_prefix(name) {
+            if name.starts_with("--") {
+                if let Some(v) = v.strip_prefix('=') {
+                    return Some(v.to_string());
+                }
+            } else if !v.is_empty() {
+                return Some(v.trim_start_matches('=').to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{strings, words};
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, f)| (k.to_string(), f.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn bare_features_apply_to_each_selected_package_by_name() {
+        let args = CargoArgs::parse(&words("build -p app@1.2 -p net -F smp,log/std"));
+        assert_eq!(
+            args.features_by_crate(&strings(&["unused"])),
+            pairs(&[("app", "smp"), ("net", "smp"), ("log", "std")])
+        );
+    }
+
+    #[test]
+    fn bare_features_without_p_apply_to_the_default_packages() {
+        let args = CargoArgs::parse(&words("build --features smp"));
+        let app = args.features_by_crate(&strings(&["app"]));
+        assert_eq!(app, pairs(&[("app", "smp")]));
+        assert!(args.features_by_crate(&[]).is_empty());
+    }
+
+    #[test]
+    fn target_dir_moves_the_xdeps_build_with_the_real_one() {
+        let flags = |line: &str| CargoArgs::parse(&words(line)).xdeps_flags(Path::new("/ws"));
+        let relative = flags("build --target-dir out --target x86_64-unknown-none");
+        let expected = "--target x86_64-unknown-none --target-dir /ws/out";
+        assert_eq!(relative, words(expected));
+        let absolute = flags("build --frozen --target-dir=/cache/target");
+        assert_eq!(absolute, words("--offline --target-dir /cache/target"));
+        assert!(flags("build").is_empty());
+    }
+}
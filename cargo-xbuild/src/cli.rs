@@ -0,0 +1,272 @@
+use anyhow::{bail, Result};
+use log::LevelFilter;
+use std::path::PathBuf;
+
+use crate::edit::ConfigEdit;
+use crate::message::MessageFormat;
+
+/// Parsed command line: `cargo xbuild [OPTIONS] [SUBCOMMAND | CARGO ARGS...]`.
+///
+/// xbuild's own options must come first; parsing stops at the first
+/// argument that isn't one of them. If that argument names an xbuild
+/// subcommand it is handled here, otherwise everything from there on is
+/// forwarded to cargo untouched. A leading `--` forces the latter, e.g.
+/// `cargo xbuild -- check` runs `cargo check` rather than xbuild's `check`
+/// (likewise for `init`).
+pub struct Cli {
+    pub options: Options,
+    pub subcommand: Subcommand,
+}
+
+/// xbuild's own options, shared by every subcommand.
+#[derive(Default, Clone)]
+pub struct Options {
+    pub message_format: MessageFormat,
+    /// Log level from `-v`/`-vv`/`-q`; `None` leaves it to `RUST_LOG`
+    pub verbosity: Option<LevelFilter>,
+    /// `--allow-unknown`: warn instead of failing on metadata keys that
+    /// `defconfig.toml` doesn't define
+    pub allow_unknown: bool,
+    /// `--keep-going`: skip crate manifests that fail to parse (with a
+    /// warning) instead of failing
+    pub keep_going: bool,
+    /// `--strict`: fail instead of warning on active xconfigs that map to
+    /// no crate features
+    pub strict: bool,
+    /// `--locked`: fail if the plan differs from the committed
+    /// `.config.lock` (not to be confused with cargo's own `--locked`,
+    /// which goes after the cargo command)
+    pub locked: bool,
+    /// `--defconfig-file <path>`: use this defconfig instead of searching
+    /// upward for `defconfig.toml`; its directory becomes the project root
+    pub defconfig_file: Option<PathBuf>,
+    /// `--config-file <path>`: use this instead of `<root>/.config.toml`
+    pub config_file: Option<PathBuf>,
+    /// `--explain-externs`: log why each extern is injected
+    /// (xconfig key → crate/feature → `dep:` entry)
+    pub explain_externs: bool,
+    /// `--dump-metadata-map`: log every crate's `[package.metadata.xconfig]`
+    /// as parsed, and which of its keys are active
+    pub dump_metadata_map: bool,
+    /// `--idea`: also sync IntelliJ Rust settings even if `.idea/` does
+    /// not exist yet
+    pub idea: bool,
+    /// `--force`: overwrite a hand-edited `.cargo/config.toml` without
+    /// warning
+    pub force: bool,
+    /// `--check-sync`: write no IDE file; show how `.cargo/config.toml`
+    /// and `.vscode/settings.json` differ from what a sync would write,
+    /// and fail if they do (for CI that commits them)
+    pub check_sync: bool,
+    /// `--emit-cfgs`: write the cfgs the build passes to rustc to
+    /// `target/xbuild/cfgs.json`, for coverage and other external tools
+    pub emit_cfgs: bool,
+    /// `--dump-rustc-commands`: have the wrapper append every rustc
+    /// command, after injection, to `target/xbuild/rustc-commands.txt`
+    pub dump_rustc_commands: bool,
+    /// `--timings`: log how long each stage of xbuild itself took (config,
+    /// manifest scan, `cargo metadata`, xdeps, IDE sync, cargo), and a
+    /// summary at the end; otherwise they are only logged with `-v`. Not
+    /// cargo's `--timings`, which goes after the cargo command.
+    pub timings: bool,
+    /// `--no-summary`: don't log the table of injected features
+    pub no_summary: bool,
+    /// `--update`: add keys new in `defconfig.toml` to an existing
+    /// `.config.toml`, keeping its values and comments
+    pub update: bool,
+    /// `--config-env`: let Kconfig-style `CONFIG_*` env vars (e.g.
+    /// `CONFIG_SMP=y`) override `.config.toml`
+    pub config_env: bool,
+}
+
+pub enum Subcommand {
+    /// `init`: scaffold `defconfig.toml`, `.config.toml` and the IDE files
+    Init,
+    /// `save-config <name>`: snapshot `.config.toml` to `configs/<name>.toml`
+    SaveConfig(String),
+    /// `load-config <name>`: restore `configs/<name>.toml` and re-sync
+    LoadConfig(String),
+    /// `check [--json]`: validate and resolve without compiling
+    Check { json: bool },
+    /// `sync`: regenerate the IDE files only (no cargo build)
+    Sync,
+    /// `watch`: re-sync whenever the config files change
+    Watch,
+    /// `buildscript`: print the active cfgs as `cargo:rustc-cfg` lines,
+    /// for build scripts of crates built without the wrapper
+    BuildScript,
+    /// `toggle <KEY>`, `set <KEY>=<VALUE>`, `unset <KEY>`: change one key
+    /// of `.config.toml` and re-sync
+    Edit(ConfigEdit),
+    /// `explain <KEY> [--json]`: a key's definition, value, dependents
+    /// and what it injects
+    Explain { key: String, json: bool },
+    /// `doctor`: diagnose common setup problems
+    Doctor,
+    /// `schema`: print a JSON Schema for `defconfig.toml`
+    Schema,
+    /// `env [--json] [CARGO ARGS...]`: print the env a build would set
+    Env { json: bool, cargo_args: Vec<String> },
+    /// Anything else: run cargo with these args through the wrapper
+    Cargo(Vec<String>),
+}
+
+pub fn parse(args: Vec<String>) -> Result<Cli> {
+    let mut opts = Options::default();
+
+    let mut iter = args.into_iter().peekable();
+    while let Some(arg) = iter.peek() {
+        match arg.as_str() {
+            // -v → debug, -vv (or -v -v) → trace
+            "-v" | "--verbose" => {
+                opts.verbosity = Some(match opts.verbosity {
+                    Some(LevelFilter::Debug | LevelFilter::Trace) => LevelFilter::Trace,
+                    _ => LevelFilter::Debug,
+                });
+            }
+            "-vv" => opts.verbosity = Some(LevelFilter::Trace),
+            "-q" | "--quiet" => opts.verbosity = Some(LevelFilter::Warn),
+            "--allow-unknown" => opts.allow_unknown = true,
+            "--keep-going" => opts.keep_going = true,
+            "--strict" => opts.strict = true,
+            "--locked" => opts.locked = true,
+            "--idea" => opts.idea = true,
+            "--force" => opts.force = true,
+            "--check-sync" => opts.check_sync = true,
+            "--timings" => opts.timings = true,
+            "--emit-cfgs" => opts.emit_cfgs = true,
+            "--dump-rustc-commands" => opts.dump_rustc_commands = true,
+            "--no-summary" => opts.no_summary = true,
+            "--config-env" => opts.config_env = true,
+            "--update" => opts.update = true,
+            "--explain-externs" => opts.explain_externs = true,
+            "--dump-metadata-map" => opts.dump_metadata_map = true,
+            "--message-format" => {
+                iter.next();
+                let Some(value) = iter.peek() else {
+                    bail!("--message-format requires a value (human|json)");
+                };
+                opts.message_format = parse_message_format(value)?;
+            }
+            a if a.starts_with("--message-format=") => {
+                opts.message_format = parse_message_format(&a["--message-format=".len()..])?;
+            }
+            flag @ ("--defconfig-file" | "--config-file") => {
+                let flag = flag.to_string();
+                iter.next();
+                let Some(value) = iter.peek() else {
+                    bail!("{flag} requires a path");
+                };
+                let path = Some(PathBuf::from(value));
+                if flag == "--defconfig-file" {
+                    opts.defconfig_file = path;
+                } else {
+                    opts.config_file = path;
+                }
+            }
+            a if a.starts_with("--defconfig-file=") => {
+                opts.defconfig_file = Some(a["--defconfig-file=".len()..].into());
+            }
+            a if a.starts_with("--config-file=") => {
+                opts.config_file = Some(a["--config-file=".len()..].into());
+            }
+            _ => break,
+        }
+        iter.next();
+    }
+
+    let rest: Vec<String> = iter.collect();
+    let subcommand = match rest.first().map(|s| s.as_str()) {
+        Some(cmd @ ("save-config" | "load-config")) => {
+            let name = match &rest[1..] {
+                [name] => name.clone(),
+                _ => bail!("usage: cargo xbuild {cmd} <name>"),
+            };
+            if cmd == "save-config" {
+                Subcommand::SaveConfig(name)
+            } else {
+                Subcommand::LoadConfig(name)
+            }
+        }
+        Some("check") => match &rest[1..] {
+            [] => Subcommand::Check { json: false },
+            [flag] if flag == "--json" => Subcommand::Check { json: true },
+            _ => bail!("usage: cargo xbuild check [--json]"),
+        },
+        Some("init") => match &rest[1..] {
+            [] => Subcommand::Init,
+            _ => bail!("usage: cargo xbuild init"),
+        },
+        Some("sync") => match &rest[1..] {
+            [] => Subcommand::Sync,
+            _ => bail!("usage: cargo xbuild sync"),
+        },
+        Some("watch") => match &rest[1..] {
+            [] => Subcommand::Watch,
+            _ => bail!("usage: cargo xbuild watch"),
+        },
+        Some("buildscript") => match &rest[1..] {
+            [] => Subcommand::BuildScript,
+            _ => bail!("usage: cargo xbuild buildscript"),
+        },
+        Some("explain") => match &rest[1..] {
+            [key] => Subcommand::Explain {
+                key: key.clone(),
+                json: false,
+            },
+            [key, flag] if flag == "--json" => Subcommand::Explain {
+                key: key.clone(),
+                json: true,
+            },
+            _ => bail!("usage: cargo xbuild explain <KEY> [--json]"),
+        },
+        Some("doctor") => match &rest[1..] {
+            [] => Subcommand::Doctor,
+            _ => bail!("usage: cargo xbuild doctor"),
+        },
+        Some("schema") => match &rest[1..] {
+            [] => Subcommand::Schema,
+            _ => bail!("usage: cargo xbuild schema"),
+        },
+        Some("toggle") => match &rest[1..] {
+            [key] => Subcommand::Edit(ConfigEdit::Toggle(key.clone())),
+            _ => bail!("usage: cargo xbuild toggle <KEY>"),
+        },
+        Some("set") => match &rest[1..] {
+            [assignment] => {
+                let Some((key, value)) = assignment.split_once('=') else {
+                    bail!("usage: cargo xbuild set <KEY>=<VALUE>");
+                };
+                Subcommand::Edit(ConfigEdit::Set(key.to_string(), value.to_string()))
+            }
+            _ => bail!("usage: cargo xbuild set <KEY>=<VALUE>"),
+        },
+        Some("unset") => match &rest[1..] {
+            [key] => Subcommand::Edit(ConfigEdit::Unset(key.clone())),
+            _ => bail!("usage: cargo xbuild unset <KEY>"),
+        },
+        Some("env") => {
+            let json = rest.get(1).is_some_and(|a| a == "--json");
+            let skip = if json { 2 } else { 1 };
+            Subcommand::Env {
+                json,
+                cargo_args: rest[skip..].to_vec(),
+            }
+        }
+        Some("--") => Subcommand::Cargo(rest[1..].to_vec()),
+        _ => Subcommand::Cargo(rest),
+    };
+
+    Ok(Cli {
+        options: opts,
+        subcommand,
+    })
+}
+
+fn parse_message_format(value: &str) -> Result<MessageFormat> {
+    match value {
+        "human" => Ok(MessageFormat::Human),
+        "json" => Ok(MessageFormat::Json),
+        other => bail!("unknown --message-format `{other}` (expected human|json)"),
+    }
+}
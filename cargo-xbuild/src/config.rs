@@ -1,161 +1,1363 @@
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use crate::types::{CargoToml, DefConfig, ProjectConfig};
 
 /// Locate the workspace root by searching upward from CWD for `defconfig.toml`.
-pub fn project_root() -> PathBuf {
-    let mut dir = std::env::current_dir().expect("cannot determine current directory");
-    loop {
+///
+/// If there is none but a `.config.toml` turns up on the way, the error
+/// says so: that usually means `defconfig.toml` was deleted or never
+/// committed.
+pub fn project_root() -> Result<PathBuf> {
+    let cwd = std::env::current_dir().context("cannot determine current directory")?;
+    let mut stray_config = None;
+    for dir in cwd.ancestors() {
         if dir.join("defconfig.toml").exists() {
-            return dir;
+            return Ok(dir.to_path_buf());
+        }
+        if stray_config.is_none() && dir.join(".config.toml").exists() {
+            stray_config = Some(dir.join(".config.toml"));
         }
-        if !dir.pop() {
-            panic!("could not find defconfig.toml in any parent directory");
+    }
+    match stray_config {
+        Some(config) => anyhow::bail!(
+            "found {} but no defconfig.toml next to it or in any parent directory \
+             — run `cargo xbuild init`?",
+            config.display()
+        ),
+        None => anyhow::bail!(
+            "could not find defconfig.toml in {} or any parent directory \
+             — run `cargo xbuild init` to create one",
+            cwd.display()
+        ),
+    }
+}
+
+/// Where the xconfig files are, and the project root everything else
+/// (`xdeps/`, IDE files, `.config.lock`) is relative to.
+pub struct ConfigPaths {
+    pub root: PathBuf,
+    pub defconfig: PathBuf,
+    pub config: PathBuf,
+    /// The cargo workspace root, where cargo runs and `crates/` and
+    /// `entry/` are scanned. Usually `root`, but `defconfig.toml` may sit
+    /// above the workspace in repos that keep build config apart from code.
+    pub workspace: PathBuf,
+    /// `--config-env`: `CONFIG_*` env vars override `.config.toml` (see
+    /// `kconfig_env`)
+    pub config_env: bool,
+}
+
+impl ConfigPaths {
+    /// Resolve `--defconfig-file` / `--config-file` (relative to CWD).
+    ///
+    /// Without `--defconfig-file` the root is found by the usual upward
+    /// search for `defconfig.toml`, or is CWD itself when `search` is off
+    /// (`init`, which creates it). With it, no search happens: the root
+    /// is the directory containing the given defconfig. `.config.toml`
+    /// defaults to `<root>/.config.toml` either way.
+    pub fn discover(defconfig: Option<&Path>, config: Option<&Path>, search: bool) -> Result<Self> {
+        let absolute =
+            |p: &Path| std::path::absolute(p).with_context(|| format!("resolve {}", p.display()));
+        let (root, defconfig) = match defconfig {
+            Some(path) => {
+                let path = absolute(path)?;
+                let Some(root) = path.parent() else {
+                    anyhow::bail!("--defconfig-file `{}` is not a file", path.display());
+                };
+                (root.to_path_buf(), path)
+            }
+            None => {
+                let root = if search {
+                    project_root()?
+                } else {
+                    std::env::current_dir().context("cannot determine current directory")?
+                };
+                let defconfig = root.join("defconfig.toml");
+                (root, defconfig)
+            }
+        };
+        let config = match config {
+            Some(path) => absolute(path)?,
+            None => root.join(".config.toml"),
+        };
+        let workspace = cargo_workspace_root(&root);
+        if workspace != root {
+            debug!("cargo workspace root: {}", workspace.display());
         }
+        Ok(ConfigPaths {
+            root,
+            defconfig,
+            config,
+            workspace,
+            config_env: false,
+        })
+    }
+
+    /// `.config.toml`'s path for messages, relative to the root if inside it.
+    pub fn config_label(&self) -> String {
+        let p = self.config.strip_prefix(&self.root).unwrap_or(&self.config);
+        p.display().to_string()
     }
 }
 
-/// Parse `defconfig.toml` and return the xconfig definitions.
-pub fn load_defconfig(root: &Path) -> Result<std::collections::HashMap<String, crate::types::XConfigDef>> {
-    let path = root.join("defconfig.toml");
-    let content = std::fs::read_to_string(&path)
+/// The cargo workspace containing `root`, or else the one containing CWD
+/// (for a `defconfig.toml` above the workspace), per
+/// `cargo locate-project --workspace`. Falls back to `root` when neither
+/// is in a cargo workspace.
+fn cargo_workspace_root(root: &Path) -> PathBuf {
+    let cwd = std::env::current_dir().ok();
+    workspace_root_from(root, cwd.as_deref())
+}
+
+/// `cargo_workspace_root`, with CWD given.
+fn workspace_root_from(root: &Path, cwd: Option<&Path>) -> PathBuf {
+    let locate = |dir: &Path| -> Option<PathBuf> {
+        let output = std::process::Command::new("cargo")
+            .args(["locate-project", "--workspace", "--message-format", "plain"])
+            .current_dir(dir)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|o| o.status.success())?;
+        let manifest = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        manifest.parent().map(Path::to_path_buf)
+    };
+    locate(root)
+        .or_else(|| cwd.and_then(locate))
+        .unwrap_or_else(|| root.to_path_buf())
+}
+
+/// Parse `defconfig.toml` at `path` and return the xconfig definitions.
+pub fn load_defconfig(path: &Path) -> Result<std::collections::HashMap<String, crate::types::XConfigDef>> {
+    Ok(read_defconfig(path)?.xconfig.unwrap_or_default())
+}
+
+/// The `[xbuild]` settings from `defconfig.toml` at `path`.
+pub fn load_xbuild_settings(path: &Path) -> Result<crate::types::XBuildSettings> {
+    Ok(read_defconfig(path)?.xbuild)
+}
+
+/// Parse and validate the whole `defconfig.toml`, including the
+/// top-level `groups` order and the fragments merged into it.
+pub(crate) fn read_defconfig(path: &Path) -> Result<DefConfig> {
+    let content = std::fs::read_to_string(path)
         .with_context(|| format!("read {}", path.display()))?;
-    let def: DefConfig = toml::from_str(&content).context("parse defconfig.toml")?;
-    Ok(def.xconfig.unwrap_or_default())
+    let mut def: DefConfig =
+        toml::from_str(&content).with_context(|| format!("parse {}", path.display()))?;
+    merge_fragments(&mut def, path)?;
+    let no_defs = HashMap::new();
+    let defs = def.xconfig.as_ref().unwrap_or(&no_defs);
+
+    // Each default, explicit or implied, must agree with its declared
+    // type and range
+    let mut errors: Vec<String> = Vec::new();
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for key in keys {
+        let def = &defs[key];
+        match (def.min, def.max) {
+            (None, None) => {}
+            _ if def.typ != "int" => errors.push(format!(
+                "xconfig key `{key}`: min/max only apply to type `int`, not `{}`",
+                def.typ
+            )),
+            (Some(min), Some(max)) if min > max => errors.push(format!(
+                "xconfig key `{key}`: min {min} is greater than max {max}"
+            )),
+            _ => {}
+        }
+        match def.element.as_deref() {
+            Some(_) if def.typ != "list" => errors.push(format!(
+                "xconfig key `{key}`: element only applies to type `list`, not `{}`",
+                def.typ
+            )),
+            Some(e) if !matches!(e, "int" | "string") => errors.push(format!(
+                "xconfig key `{key}`: unsupported list element type `{e}` (int or string)"
+            )),
+            _ => {}
+        }
+        match &def.default {
+            // `.config.toml` is generated with the type's zero value
+            None => {
+                if let Some(why) = def.range_error(&def.default_value()) {
+                    errors.push(format!("xconfig key `{key}`: no default, implied {why}"));
+                }
+            }
+            Some(toml::Value::Table(t)) if t.len() != 1 || def.default_expr().is_none() => {
+                errors.push(format!(
+                    "xconfig key `{key}`: a computed default must be `{{ expr = \"...\" }}`"
+                ));
+            }
+            Some(toml::Value::Table(_)) => {}
+            Some(default) => match value_matches_type(def, default) {
+                Some(true) => {
+                    if let Some(why) = def.range_error(default) {
+                        errors.push(format!("xconfig key `{key}`: default {why}"));
+                    }
+                }
+                Some(false) => errors.push(format!(
+                    "xconfig key `{key}`: default `{default}` does not match type `{}`",
+                    def.typ
+                )),
+                None => errors.push(format!(
+                    "xconfig key `{key}`: unsupported type `{}` in defconfig.toml",
+                    def.typ
+                )),
+            },
+        }
+    }
+    // Every cfg name, including aliases and virtual cfgs, has one owner;
+    // keys differing only in case (`smp`, `SMP`) would share theirs
+    let mut cfg_owner: HashMap<String, &String> = HashMap::new();
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for &key in &keys {
+        if let Some(owner) = cfg_owner.insert(cfg_name(key), key) {
+            errors.push(format!(
+                "xconfig keys `{owner}` and `{key}` both set cfg `{}`",
+                cfg_name(key)
+            ));
+        }
+    }
+    for key in keys {
+        let def = &defs[key];
+        if def.tristate {
+            if def.typ != "bool" {
+                errors.push(format!(
+                    "xconfig key `{key}`: tristate only applies to type `bool`, not `{}`",
+                    def.typ
+                ));
+            }
+            if let Some(owner) = cfg_owner.get(&module_name(key)) {
+                errors.push(format!(
+                    "xconfig key `{key}`: its module cfg `{}` is already the cfg of `{owner}`",
+                    module_name(key)
+                ));
+            }
+            cfg_owner.insert(module_name(key), key);
+        }
+        if !matches!(def.emit.as_str(), "cfg" | "feature" | "both") {
+            errors.push(format!(
+                "xconfig key `{key}`: emit must be \"cfg\", \"feature\" or \"both\", not `{}`",
+                def.emit
+            ));
+        }
+        if !def.aliases.is_empty() && !matches!(def.typ.as_str(), "bool" | "list") {
+            errors.push(format!(
+                "xconfig key `{key}`: aliases only apply to bool and list keys, not `{}`",
+                def.typ
+            ));
+        }
+        for alias in &def.aliases {
+            match cfg_owner.get(&cfg_name(alias)) {
+                Some(owner) => errors.push(format!(
+                    "xconfig key `{key}`: alias `{alias}` is already the cfg of `{owner}`"
+                )),
+                None => {
+                    cfg_owner.insert(cfg_name(alias), key);
+                }
+            }
+        }
+    }
+    // Virtual cfgs test bool or list keys, named either way (`smp`/`SMP`)
+    let mut names: Vec<&String> = def.virtuals.keys().collect();
+    names.sort();
+    for name in names {
+        let src = &def.virtuals[name];
+        if let Some(owner) = cfg_owner.get(&cfg_name(name)) {
+            errors.push(format!(
+                "virtual cfg `{name}` is already the cfg of `{owner}`"
+            ));
+        }
+        errors.extend(cond_errors(defs, src, &format!("virtual cfg `{name}`")));
+    }
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for key in keys {
+        let def = &defs[key];
+        let Some(src) = &def.required_if else {
+            continue;
+        };
+        let what = format!("xconfig key `{key}`: required_if");
+        if def.typ == "bool" {
+            errors.push(format!("{what} does not apply to bool keys"));
+        }
+        errors.extend(cond_errors(defs, src, &what));
+    }
+    // Computed defaults are checked by evaluating them all: this catches
+    // bad expressions, undefined keys, wrong result types and cycles
+    if errors.is_empty() {
+        errors.extend(resolve_values(defs, &HashMap::new()).1);
+    }
+    report_errors(&errors, "defconfig.toml validation")?;
+    // Taken together, the defaults must pass the checks a `.config.toml`
+    // does (conflicts_with, required_if), or the one generated from them
+    // is rejected straight away
+    let (values, _) = resolve_values(defs, &HashMap::new());
+    parse_active_xconfigs(values, defs)
+        .with_context(|| format!("the defaults in {} are not a valid config", path.display()))?;
+
+    Ok(def)
+}
+
+/// Merge the `defconfig.d/*.toml` fragments next to `defconfig.toml` at
+/// `path` into `def`, in file name order. A key or virtual cfg may be
+/// defined only once across all of the files, spelling variants setting
+/// the same cfg (`smp`, `SMP`) included; every duplicate is reported
+/// with the two files defining it rather than one silently winning.
+fn merge_fragments(def: &mut DefConfig, path: &Path) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new(".")).join("defconfig.d");
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .with_context(|| format!("read {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    files.retain(|f| f.extension().is_some_and(|ext| ext == "toml"));
+    files.sort();
+
+    let main = path.display().to_string();
+    let defs = def.xconfig.get_or_insert_with(HashMap::new);
+    // cfg name → (key, file defining it)
+    let mut origin: HashMap<String, (String, String)> = defs
+        .keys()
+        .chain(def.virtuals.keys())
+        .map(|k| (cfg_name(k), (k.clone(), main.clone())))
+        .collect();
+    let mut errors: Vec<String> = Vec::new();
+    for file in &files {
+        let content =
+            std::fs::read_to_string(file).with_context(|| format!("read {}", file.display()))?;
+        let fragment: crate::types::DefConfigFragment =
+            toml::from_str(&content).with_context(|| format!("parse {}", file.display()))?;
+        let name = file.display().to_string();
+        let mut keys: Vec<&String> = fragment.xconfig.keys().collect();
+        keys.extend(fragment.virtuals.keys());
+        keys.sort();
+        for key in keys {
+            let cfg = cfg_name(key);
+            match origin.get(&cfg) {
+                Some((other, first)) if other == key => {
+                    errors.push(format!("`{key}` is defined in both {first} and {name}"));
+                }
+                Some((other, first)) => errors.push(format!(
+                    "`{other}` ({first}) and `{key}` ({name}) both set cfg `{cfg}`"
+                )),
+                None => {
+                    origin.insert(cfg, (key.clone(), name.clone()));
+                }
+            }
+        }
+        defs.extend(fragment.xconfig);
+        def.virtuals.extend(fragment.virtuals);
+        debug!("merged {name}");
+    }
+    report_errors(&errors, "defconfig.d merge")
+}
+
+/// The cfg an xconfig key sets: the key in upper case, so `smp` is
+/// tested with `#[cfg(SMP)]`. This is the only spelling emitted, both to
+/// rustc and to the IDE files.
+pub fn cfg_name(key: &str) -> String {
+    key.to_uppercase()
+}
+
+/// Problems with the condition `src` (a `[virtual]` cfg or `required_if`,
+/// described by `what`): it must parse, and test only bool or list keys.
+fn cond_errors(
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    src: &str,
+    what: &str,
+) -> Vec<String> {
+    let cond = match crate::expr::parse_cond(src) {
+        Ok(cond) => cond,
+        Err(e) => return vec![format!("{what}: `{src}`: {e}")],
+    };
+    let mut errors = Vec::new();
+    for k in cond.keys() {
+        match key_by_name(defs, k) {
+            Some(key) if matches!(defs[key].typ.as_str(), "bool" | "list") => {}
+            Some(key) => errors.push(format!(
+                "{what}: `{k}` is of type `{}`, not bool or list",
+                defs[key].typ
+            )),
+            None => errors.push(format!("{what}: unknown xconfig key `{k}`")),
+        }
+    }
+    errors
+}
+
+/// Whether the condition `src` holds over `active`. Conditions were
+/// checked when `defconfig.toml` was read.
+fn cond_holds(
+    src: &str,
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    active: &[String],
+) -> bool {
+    let mut lookup = |name: &str| -> Result<toml::Value, String> {
+        let on = key_by_name(defs, name).is_some_and(|k| active.contains(k));
+        Ok(toml::Value::Boolean(on))
+    };
+    crate::expr::parse_cond(src).and_then(|c| c.eval(&mut lookup)) == Ok(true)
+}
+
+/// The key `name` refers to, by its own name or its cfg spelling.
+fn key_by_name<'a>(
+    defs: &'a HashMap<String, crate::types::XConfigDef>,
+    name: &str,
+) -> Option<&'a String> {
+    defs.get_key_value(name)
+        .map(|(k, _)| k)
+        .or_else(|| defs.keys().find(|k| cfg_name(k) == name))
+}
+
+/// The cfg a tristate key sets when it is `"m"`: `SMP_MODULE` for `smp`.
+/// It is listed among the keys under that name, so metadata can map it.
+pub fn module_name(key: &str) -> String {
+    format!("{}_MODULE", cfg_name(key))
+}
+
+/// Every name set by the keys `emits` is false for (`XConfigDef::emits_cfg`
+/// or `emits_features`): the key itself, its aliases and its module cfg.
+/// Sorted.
+pub fn not_emitting(
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    emits: fn(&crate::types::XConfigDef) -> bool,
+) -> Vec<String> {
+    let mut names = Vec::new();
+    for (key, def) in defs {
+        if emits(def) {
+            continue;
+        }
+        names.push(key.clone());
+        if def.tristate {
+            names.push(module_name(key));
+        }
+        names.extend(def.aliases.iter().cloned());
+    }
+    names.sort();
+    names
+}
+
+/// The `[virtual]` cfgs whose expression holds over `active`.
+fn active_virtuals(
+    virtuals: &HashMap<String, String>,
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    active: &[String],
+) -> Vec<String> {
+    virtuals
+        .iter()
+        .filter(|(_, src)| cond_holds(src, defs, active))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Section for keys without a `group`.
+pub const DEFAULT_GROUP: &str = "Misc";
+
+/// `defs`' keys by section: groups in `order` first, then any other
+/// groups alphabetically, then `DEFAULT_GROUP`. Keys are sorted within
+/// each section.
+pub fn grouped_keys<'a>(
+    defs: &'a HashMap<String, crate::types::XConfigDef>,
+    order: &[String],
+) -> Vec<(&'a str, Vec<&'a String>)> {
+    let mut groups: BTreeMap<(usize, &str), Vec<&String>> = BTreeMap::new();
+    for (key, def) in defs {
+        let rank = match def.group.as_deref() {
+            Some(g) => (order.iter().position(|o| o == g).unwrap_or(order.len()), g),
+            None => (order.len() + 1, DEFAULT_GROUP),
+        };
+        groups.entry(rank).or_default().push(key);
+    }
+    groups
+        .into_iter()
+        .map(|((_, group), mut keys)| {
+            keys.sort();
+            (group, keys)
+        })
+        .collect()
+}
+
+/// Whether `val` has the TOML type named by `def`'s `type` field (for a
+/// list: an array of `element`s). Returns `None` for an unsupported type
+/// or element type name.
+fn value_matches_type(def: &crate::types::XConfigDef, val: &toml::Value) -> Option<bool> {
+    let tristate =
+        |val: &toml::Value| def.tristate && matches!(val.as_str(), Some("y" | "m" | "n"));
+    let matches = |typ: &str, val: &toml::Value| match typ {
+        "bool" => Some(val.is_bool() || tristate(val)),
+        "int" => Some(val.is_integer()),
+        "string" => Some(val.is_str()),
+        _ => None,
+    };
+    match (def.typ.as_str(), def.element_type()) {
+        ("list", element @ ("int" | "string")) => Some(
+            val.as_array()
+                .is_some_and(|items| items.iter().all(|v| matches(element, v) == Some(true))),
+        ),
+        ("list", _) => None,
+        (typ, _) => matches(typ, val),
+    }
+}
+
+/// Every key's value: `set`'s where it has one, otherwise its default.
+///
+/// Literal defaults are taken as-is. Computed defaults are evaluated on
+/// demand: each key an expression refers to is resolved first, whatever
+/// the declaration order, so a default always sees the final value of the
+/// keys it depends on. Reaching a key again while its own default is
+/// still being evaluated is a cycle, reported once as e.g. `a -> b -> a`.
+///
+/// Returns the values that could be resolved and one message per problem.
+pub fn resolve_values(
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    set: &HashMap<String, toml::Value>,
+) -> (HashMap<String, toml::Value>, Vec<String>) {
+    let mut values = set.clone();
+    let mut errors: Vec<String> = Vec::new();
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for key in keys {
+        if let Err(e) = resolve_key(key, defs, &mut values, &mut Vec::new()) {
+            // A broken key fails everything that depends on it too
+            if !errors.contains(&e) {
+                errors.push(e);
+            }
+        }
+    }
+    (values, errors)
+}
+
+/// Resolve one key for `resolve_values`. `stack` holds the keys whose
+/// computed defaults are being evaluated, innermost last.
+fn resolve_key(
+    key: &str,
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    values: &mut HashMap<String, toml::Value>,
+    stack: &mut Vec<String>,
+) -> Result<toml::Value, String> {
+    if let Some(val) = values.get(key) {
+        return Ok(val.clone());
+    }
+    let Some(def) = defs.get(key) else {
+        let referrer = stack.last().map_or("", String::as_str);
+        return Err(format!(
+            "xconfig key `{referrer}`: default refers to undefined key `{key}`"
+        ));
+    };
+    if let Some(start) = stack.iter().position(|k| k == key) {
+        let mut cycle = stack[start..].to_vec();
+        // Start from the smallest key so each cycle reads the same
+        let first = (0..cycle.len()).min_by_key(|&i| &cycle[i]).unwrap_or(0);
+        cycle.rotate_left(first);
+        cycle.push(cycle[0].clone());
+        return Err(format!(
+            "xconfig defaults form a cycle: {}",
+            cycle.join(" -> ")
+        ));
+    }
+
+    let val = match def.default_expr() {
+        None => def.default_value(),
+        Some(src) => {
+            let expr = crate::expr::parse_value(src)
+                .map_err(|e| format!("xconfig key `{key}`: default `{src}`: {e}"))?;
+            for k in expr.cond_keys() {
+                if defs.get(k).is_some_and(|d| d.typ != "bool") {
+                    return Err(format!(
+                        "xconfig key `{key}`: default `{src}` tests `{k}`, which is not a bool"
+                    ));
+                }
+            }
+            stack.push(key.to_string());
+            let val = expr.eval(&mut |k| {
+                let val = resolve_key(k, defs, values, stack)?;
+                Ok(defs.get(k).map_or(val.clone(), |d| d.built_in(&val)))
+            });
+            stack.pop();
+            let val = val?;
+            if value_matches_type(def, &val) == Some(false) {
+                return Err(format!(
+                    "xconfig key `{key}`: default `{src}` gives `{val}`, expected type `{}`",
+                    def.typ
+                ));
+            }
+            if let Some(why) = def.range_error(&val) {
+                return Err(format!("xconfig key `{key}`: default `{src}` gives {why}"));
+            }
+            val
+        }
+    };
+    values.insert(key.to_string(), val.clone());
+    Ok(val)
 }
 
 /// Ensure `.config.toml` exists. If missing, generate from `defconfig.toml` defaults.
-pub fn ensure_config_toml(root: &Path) -> Result<()> {
-    let config_path = root.join(".config.toml");
+pub fn ensure_config_toml(paths: &ConfigPaths) -> Result<()> {
+    let config_path = &paths.config;
     if config_path.exists() {
         return Ok(());
     }
 
-    let defs = load_defconfig(root)?;
+    let defconfig = read_defconfig(&paths.defconfig)?;
+    let defs = defconfig.xconfig.unwrap_or_default();
+    let (values, errors) = resolve_values(&defs, &HashMap::new());
+    report_errors(&errors, "defconfig.toml validation")?;
     let mut lines = vec!["# Auto-generated from defconfig.toml — edit as needed.".to_string()];
     lines.push("[xconfig]".to_string());
 
-    // Sort keys for deterministic output
-    let mut keys: Vec<&String> = defs.keys().collect();
-    keys.sort();
-    for key in keys {
-        let def = &defs[key];
-        if let Some(desc) = &def.description {
-            lines.push(format!("# {desc}"));
+    // Section banners only once some key declares a group
+    let banners = defs.values().any(|d| d.group.is_some());
+    for (group, keys) in grouped_keys(&defs, &defconfig.groups) {
+        if banners {
+            lines.push(String::new());
+            lines.push(format!("# ── {group} ──"));
+        }
+        for key in keys {
+            let def = &defs[key];
+            if let Some(desc) = &def.description {
+                lines.push(format!("# {desc}"));
+            }
+            // toml::Value's Display emits a TOML literal (strings quoted)
+            match def.default_expr() {
+                // Left commented out so it keeps following its inputs
+                Some(src) => {
+                    lines.push(format!("# computed: {src}"));
+                    lines.push(format!("# {} = {}", key, values[key]));
+                }
+                None => lines.push(format!("{} = {}", key, values[key])),
+            }
         }
-        lines.push(format!("{} = {}", key, def.default));
     }
     lines.push(String::new()); // trailing newline
 
     let content = lines.join("\n");
-    std::fs::write(&config_path, &content)?;
-    eprintln!("[xbuild] generated .config.toml from defconfig.toml");
+    std::fs::write(config_path, &content)
+        .with_context(|| format!("write {}", config_path.display()))?;
+    info!("generated {} from defconfig.toml", paths.config_label());
+    Ok(())
+}
+
+/// `--update`: add the keys `defconfig.toml` has gained since
+/// `.config.toml` was generated, each with its default and description,
+/// without touching existing values or comments. Keys already set by an
+/// include and computed keys are not added. Keys `defconfig.toml` no
+/// longer defines are only flagged (validation then rejects them), never
+/// deleted.
+pub fn update_config_toml(paths: &ConfigPaths) -> Result<()> {
+    use toml_edit::{DocumentMut, Item};
+
+    let defconfig = read_defconfig(&paths.defconfig)?;
+    let defs = defconfig.xconfig.unwrap_or_default();
+    let label = paths.config_label();
+    let content = std::fs::read_to_string(&paths.config)
+        .with_context(|| format!("read {}", paths.config.display()))?;
+    let given = merged_config(&content, &paths.config, &label)?;
+    let mut doc = content
+        .parse::<DocumentMut>()
+        .with_context(|| format!("parse {label}"))?;
+    if doc.get("xconfig").is_none() {
+        doc.insert("xconfig", toml_edit::table());
+    }
+    let Some(table) = doc.get_mut("xconfig").and_then(Item::as_table_like_mut) else {
+        anyhow::bail!("{label}: `xconfig` is not a table");
+    };
+
+    // By their flattened names: `[xconfig.net] mtu` is `net_mtu`
+    let mut removed: Vec<&str> = given
+        .keys()
+        .map(String::as_str)
+        .filter(|key| !defs.contains_key(*key))
+        .collect();
+    removed.sort();
+    if !removed.is_empty() {
+        warn!(
+            "{label}: {removed:?} no longer defined in defconfig.toml; \
+             left in place for you to remove"
+        );
+    }
+
+    let mut added: Vec<&str> = Vec::new();
+    for (_, keys) in grouped_keys(&defs, &defconfig.groups) {
+        for key in keys {
+            let def = &defs[key];
+            if given.contains_key(key) || def.default_expr().is_some() {
+                continue;
+            }
+            let val = def
+                .default_value()
+                .to_string()
+                .parse::<toml_edit::Value>()
+                .context("encode value")?;
+            table.insert(key, Item::Value(val));
+            let prefix = match &def.description {
+                Some(desc) => format!("\n# {desc}\n"),
+                None => "\n".to_string(),
+            };
+            if let Some(mut key) = table.key_mut(key) {
+                key.leaf_decor_mut().set_prefix(prefix);
+            }
+            added.push(key);
+        }
+    }
+    if added.is_empty() {
+        debug!("{label} already has every defconfig.toml key");
+        return Ok(());
+    }
+    std::fs::write(&paths.config, doc.to_string())
+        .with_context(|| format!("write {}", paths.config.display()))?;
+    info!("{label}: added {added:?} from defconfig.toml");
     Ok(())
 }
 
 /// Validate `.config.toml` values against `defconfig.toml` type definitions.
-/// Reports unknown keys, missing keys, and type mismatches.
+/// Reports unknown keys, missing keys, and type mismatches, each kind
+/// under its own header with the offending values highlighted. Keys with
+/// a computed default may be left out.
 fn validate_config(
     config_map: &HashMap<String, toml::Value>,
     defs: &HashMap<String, crate::types::XConfigDef>,
 ) -> Result<()> {
-    let mut errors: Vec<String> = Vec::new();
+    use crate::logging::{paint, Style};
+
+    let mut unknown: Vec<String> = Vec::new();
+    let mut missing: Vec<String> = Vec::new();
+    let mut mismatched: Vec<String> = Vec::new();
+    let mut other: Vec<String> = Vec::new();
 
     // Check for unknown keys (in .config.toml but not in defconfig.toml)
     for key in config_map.keys() {
         if !defs.contains_key(key) {
-            errors.push(format!("unknown xconfig key `{key}` (not defined in defconfig.toml)"));
+            unknown.push(paint(key, Style::Bold));
         }
     }
 
     // Check for missing keys and type mismatches
     for (key, def) in defs {
+        let name = paint(key, Style::Bold);
         match config_map.get(key) {
-            None => {
-                errors.push(format!(
-                    "missing xconfig key `{key}` (defined in defconfig.toml as type=\"{}\")",
-                    def.typ
-                ));
-            }
+            None if def.default_expr().is_some() => {}
+            None => missing.push(format!("{name} (type = \"{}\")", def.typ)),
             Some(val) => {
-                let type_ok = match def.typ.as_str() {
-                    "bool" => val.is_bool(),
-                    "int" => val.is_integer(),
-                    "string" => val.is_str(),
-                    other => {
-                        errors.push(format!(
-                            "xconfig key `{key}`: unsupported type `{other}` in defconfig.toml"
-                        ));
-                        continue;
-                    }
-                };
-                if !type_ok {
-                    errors.push(format!(
-                        "xconfig key `{key}`: expected type `{}`, got `{val}`",
+                let Some(type_ok) = value_matches_type(def, val) else {
+                    other.push(format!(
+                        "{name}: unsupported type `{}` in defconfig.toml",
                         def.typ
                     ));
+                    continue;
+                };
+                if !type_ok {
+                    let val = paint(&val.to_string(), Style::Red);
+                    mismatched.push(format!("{name}: expected `{}`, got {val}", def.typ));
+                } else if let Some(why) = def.range_error(val) {
+                    other.push(format!("{name} = {}", paint(&why, Style::Red)));
                 }
             }
         }
     }
 
-    if errors.is_empty() {
-        Ok(())
-    } else {
-        for e in &errors {
-            eprintln!("[xbuild] error: {e}");
-        }
-        anyhow::bail!(
-            ".config.toml validation failed ({} error{})",
-            errors.len(),
-            if errors.len() == 1 { "" } else { "s" }
-        );
-    }
+    let groups = [
+        ("unknown keys (not defined in defconfig.toml)", unknown),
+        ("missing keys", missing),
+        ("type mismatches", mismatched),
+        ("other problems", other),
+    ];
+    let count: usize = groups.iter().map(|(_, lines)| lines.len()).sum();
+    if count == 0 {
+        return Ok(());
+    }
+    let mut report = String::from("invalid xconfig values:");
+    for (header, mut lines) in groups {
+        if lines.is_empty() {
+            continue;
+        }
+        lines.sort();
+        report.push_str(&format!("\n  {}", paint(header, Style::Yellow)));
+        for line in lines {
+            report.push_str(&format!("\n    {line}"));
+        }
+    }
+    error!("{report}");
+    anyhow::bail!(
+        ".config.toml validation failed ({count} error{})",
+        if count == 1 { "" } else { "s" }
+    );
+}
+
+/// Print every collected error, then bail with a one-line summary.
+/// No-op when `errors` is empty.
+pub(crate) fn report_errors(errors: &[String], what: &str) -> Result<()> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        for e in errors {
+            error!("{e}");
+        }
+        anyhow::bail!(
+            "{what} failed ({} error{})",
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" }
+        );
+    }
+}
+
+/// Elements of each `type = "list"` key, as strings; empty for a list
+/// that is empty or inactive. Only list keys have an entry.
+pub type ListValues = HashMap<String, Vec<String>>;
+
+/// Read `.config.toml` and return (active_keys, all_keys, lists).
+/// `all_keys` is derived from `defconfig.toml` (authoritative list).
+/// Validates value types against `defconfig.toml` definitions.
+///
+/// Both include the `[virtual]` cfgs, the active ones when their
+/// expression holds, and the `aliases` of keys, active along with their
+/// key; from here on they are ordinary keys. Both are sorted.
+pub fn load_active_xconfigs(paths: &ConfigPaths) -> Result<(Vec<String>, Vec<String>, ListValues)> {
+    // all_keys comes from defconfig.toml — the authoritative source
+    let def = read_defconfig(&paths.defconfig)?;
+    let defs = def.xconfig.unwrap_or_default();
+    let mut all_keys: Vec<String> = defs.keys().cloned().collect();
+    all_keys.extend(def.virtuals.keys().cloned());
+    all_keys.extend(defs.values().flat_map(|d| d.aliases.iter().cloned()));
+    let tristates = defs.iter().filter(|(_, d)| d.tristate);
+    all_keys.extend(tristates.map(|(k, _)| module_name(k)));
+
+    let config_path = &paths.config;
+    let config_str = std::fs::read_to_string(config_path)
+        .with_context(|| format!("read {}", config_path.display()))?;
+    let mut map = merged_config(&config_str, config_path, &paths.config_label())?;
+    fill_if_empty(&mut map, &defs, &paths.config_label());
+    if paths.config_env {
+        map.extend(kconfig_env(&defs));
+    }
+    let (mut active, mut lists) = parse_active_xconfigs(map, &defs)?;
+    for (key, _) in defs.iter().filter(|(_, d)| d.typ == "list") {
+        lists.entry(key.clone()).or_default();
+    }
+    active.extend(active_virtuals(&def.virtuals, &defs, &active));
+    let aliases: Vec<String> = active
+        .iter()
+        .filter_map(|key| defs.get(key))
+        .flat_map(|d| d.aliases.iter().cloned())
+        .collect();
+    active.extend(aliases);
+    // Sorted so the emitted cfg flags, and so RUSTFLAGS, are identical
+    // from run to run
+    active.sort();
+    all_keys.sort();
+
+    Ok((active, all_keys, lists))
+}
+
+/// Values from a Kconfig-style `CONFIG_*` env block, e.g. `CONFIG_SMP=y`,
+/// for tools migrating from Kconfig. `CONFIG_<KEY>` sets the key whose
+/// cfg name is `<KEY>`; variables naming no key are ignored.
+///
+/// Bool keys take `y` → true and `n` → false; `m` is kept for tristate
+/// keys and counts as `y` elsewhere. Int keys take decimal or `0x` hex, string keys the
+/// value with any surrounding quotes removed (as in a kernel `.config`),
+/// and list keys a comma-separated list. Anything else is passed on as a
+/// string, for `.config.toml` validation to reject with the key named.
+fn kconfig_env(defs: &HashMap<String, crate::types::XConfigDef>) -> HashMap<String, toml::Value> {
+    kconfig_values(defs, std::env::vars())
+}
+
+/// `kconfig_env` over the `(name, value)` pairs of `vars`.
+fn kconfig_values(
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> HashMap<String, toml::Value> {
+    let int = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    };
+    let mut values = HashMap::new();
+    for (var, raw) in vars {
+        let Some(name) = var.strip_prefix("CONFIG_") else {
+            continue;
+        };
+        let Some(key) = key_by_name(defs, name) else {
+            debug!("{var} names no xconfig key; ignored");
+            continue;
+        };
+        let def = &defs[key];
+        let string = || toml::Value::String(raw.clone());
+        let val = match def.typ.as_str() {
+            "bool" => match raw.as_str() {
+                "m" if def.tristate => toml::Value::String(raw.clone()),
+                "y" | "m" => toml::Value::Boolean(true),
+                "n" => toml::Value::Boolean(false),
+                _ => string(),
+            },
+            "int" => int(&raw).map_or_else(string, toml::Value::Integer),
+            "list" => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| match int(s) {
+                        Some(n) if def.element_type() == "int" => toml::Value::Integer(n),
+                        _ => toml::Value::String(s.to_string()),
+                    })
+                    .collect(),
+            ),
+            _ => {
+                let unquoted = raw.strip_prefix('"').and_then(|s| s.strip_suffix('"'));
+                toml::Value::String(unquoted.unwrap_or(&raw).to_string())
+            }
+        };
+        debug!("{var}={raw} → {key} = {val}");
+        values.insert(key.clone(), val);
+    }
+    if !values.is_empty() {
+        let n = values.len();
+        info!("{n} xconfig value(s) taken from CONFIG_* env vars");
+    }
+    values
+}
+
+/// Check that `content` (in `.config.toml` format, e.g. a saved preset)
+/// passes the same validation as `.config.toml` itself. Its includes are
+/// resolved from `.config.toml`'s directory, where presets are loaded.
+pub fn validate_config_content(paths: &ConfigPaths, content: &str, label: &str) -> Result<()> {
+    let defs = load_defconfig(&paths.defconfig)?;
+    let mut map = merged_config(content, &paths.config, label)?;
+    fill_if_empty(&mut map, &defs, label);
+    parse_active_xconfigs(map, &defs).map(|_| ())
+}
+
+/// A config file setting no values at all (empty, only comments, or an
+/// empty `[xconfig]`) means every key at its `defconfig.toml` default,
+/// not every key missing. Computed defaults are left to be computed.
+fn fill_if_empty(
+    map: &mut HashMap<String, toml::Value>,
+    defs: &HashMap<String, crate::types::XConfigDef>,
+    label: &str,
+) {
+    if !map.is_empty() {
+        return;
+    }
+    info!("{label} sets no xconfig values; using the defconfig.toml defaults");
+    for (key, def) in defs.iter().filter(|(_, d)| d.default_expr().is_none()) {
+        map.insert(key.clone(), def.default_value());
+    }
+}
+
+/// The `[xconfig]` values of `.config.toml`-formatted `content`, which
+/// belongs at `file`, with its includes merged in.
+///
+/// `include = ["base.toml", …]` paths are relative to the including file.
+/// Merging is last-wins: each include in order (itself merged the same
+/// way first), then the file's own keys, so local values override shared
+/// ones. A file that includes itself, directly or not, is an error.
+/// Each file's nested tables are flattened first (see `flatten_xconfig`).
+pub fn merged_config(
+    content: &str,
+    file: &Path,
+    label: &str,
+) -> Result<HashMap<String, toml::Value>> {
+    let mut stack: Vec<PathBuf> = file.canonicalize().into_iter().collect();
+    merge_includes(content, file, label, &mut stack)
+}
+
+/// Every file `.config.toml`-formatted `file` includes, directly or not,
+/// as `merged_config` finds them, for `watch`. Best effort: a file that
+/// cannot be read or parsed includes nothing (the sync reports it), and
+/// a missing include is listed so that creating it is noticed.
+pub fn include_chain(file: &Path) -> Vec<PathBuf> {
+    let mut seen: Vec<PathBuf> = file.canonicalize().into_iter().collect();
+    let mut chain = Vec::new();
+    let mut pending = vec![file.to_path_buf()];
+    while let Some(file) = pending.pop() {
+        let Ok(content) = std::fs::read_to_string(&file) else {
+            continue;
+        };
+        let Ok(config) = toml::from_str::<ProjectConfig>(&content) else {
+            continue;
+        };
+        let dir = file.parent().unwrap_or(Path::new("."));
+        for include in &config.include {
+            let path = dir.join(include);
+            match path.canonicalize() {
+                Ok(canonical) if seen.contains(&canonical) => {}
+                Ok(canonical) => {
+                    seen.push(canonical);
+                    chain.push(path.clone());
+                    pending.push(path);
+                }
+                Err(_) => chain.push(path),
+            }
+        }
+    }
+    chain
+}
+
+/// `merged_config`, with `stack` holding the canonical paths of the files
+/// being merged, outermost first.
+fn merge_includes(
+    content: &str,
+    file: &Path,
+    label: &str,
+    stack: &mut Vec<PathBuf>,
+) -> Result<HashMap<String, toml::Value>> {
+    let config: ProjectConfig =
+        toml::from_str(content).with_context(|| format!("parse {label}"))?;
+    let dir = file.parent().unwrap_or(Path::new("."));
+
+    let mut merged = HashMap::new();
+    for include in &config.include {
+        let path = dir.join(include);
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("{label}: include `{include}` ({})", path.display()))?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            anyhow::bail!("{label}: include cycle: {}", chain.join(" -> "));
+        }
+        let included =
+            std::fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        stack.push(canonical);
+        let values = merge_includes(&included, &path, &path.display().to_string(), stack)?;
+        stack.pop();
+        merged.extend(values);
+    }
+    merged.extend(flatten_xconfig(config.xconfig.unwrap_or_default(), label)?);
+    Ok(merged)
+}
+
+/// Expand the nested tables of one file's `[xconfig]` into plain keys:
+/// a table's name and each of its keys are joined with `_`, at any depth,
+/// and the case is kept as written. So
+///
+/// ```toml
+/// [xconfig.net]
+/// enabled = true
+/// mtu = 1500
+/// ```
+///
+/// sets the keys `net_enabled` and `net_mtu` (cfgs `NET_ENABLED` and
+/// `NET_MTU`), exactly as if written flat; `[xconfig.NET] MTU` would set
+/// `NET_MTU`, a different key. Setting one key twice in the same file,
+/// flat and nested or through two tables, is an error.
+fn flatten_xconfig(
+    xconfig: HashMap<String, toml::Value>,
+    label: &str,
+) -> Result<HashMap<String, toml::Value>> {
+    use std::collections::hash_map::Entry;
+
+    fn flatten(prefix: &str, table: toml::Table, out: &mut Vec<(String, toml::Value)>) {
+        for (name, val) in table {
+            let key = format!("{prefix}_{name}");
+            match val {
+                toml::Value::Table(nested) => flatten(&key, nested, out),
+                val => out.push((key, val)),
+            }
+        }
+    }
+
+    let mut flat = HashMap::new();
+    let mut nested = Vec::new();
+    for (key, val) in xconfig {
+        match val {
+            toml::Value::Table(table) => flatten(&key, table, &mut nested),
+            val => {
+                flat.insert(key, val);
+            }
+        }
+    }
+    let mut twice: Vec<String> = Vec::new();
+    for (key, val) in nested {
+        match flat.entry(key) {
+            Entry::Occupied(taken) => twice.push(taken.key().clone()),
+            Entry::Vacant(free) => {
+                free.insert(val);
+            }
+        }
+    }
+    twice.sort();
+    if !twice.is_empty() {
+        anyhow::bail!("{label}: {twice:?} set more than once, directly or in nested tables");
+    }
+    Ok(flat)
+}
+
+/// Validate merged `.config.toml` values, returning the active keys and
+/// the elements of the active lists. A bool key is active when true, a
+/// list key when non-empty.
+fn parse_active_xconfigs(
+    mut map: HashMap<String, toml::Value>,
+    defs: &HashMap<String, crate::types::XConfigDef>,
+) -> Result<(Vec<String>, ListValues)> {
+    // Validate against defconfig.toml
+    validate_config(&map, defs)?;
+
+    // Computed defaults fill the keys left out, from the values given
+    let (values, errors) = resolve_values(defs, &map);
+    report_errors(&errors, ".config.toml validation")?;
+    for (key, def) in defs {
+        if def.default_expr().is_some() && !map.contains_key(key) {
+            map.insert(key.clone(), values[key].clone());
+        }
+    }
+    // Tristate keys: `"m"` sets the module cfg instead of the key's own
+    let mut modules: Vec<String> = Vec::new();
+    for (key, val) in map.iter_mut() {
+        let Some(def) = defs.get(key).filter(|d| d.tristate) else {
+            continue;
+        };
+        if def.is_module(val) {
+            modules.push(module_name(key));
+        }
+        *val = def.built_in(val);
+    }
+
+    let mut lists = ListValues::new();
+    for (key, val) in &map {
+        let Some(items) = val.as_array().filter(|items| !items.is_empty()) else {
+            continue;
+        };
+        let items = items
+            .iter()
+            .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+            .collect();
+        lists.insert(key.clone(), items);
+    }
+    let mut active: Vec<String> = map
+        .iter()
+        .filter(|(k, v)| v.as_bool().unwrap_or(false) || lists.contains_key(*k))
+        .map(|(k, _)| k.clone())
+        .collect();
+
+    check_conflicts(&active, defs)?;
+    check_required(&map, &active, defs)?;
+    active.extend(modules);
+
+    Ok((active, lists))
+}
+
+/// Reject a key left empty (`""`, `[]`, or out of the file) while its
+/// `required_if` condition holds over `active`.
+fn check_required(
+    map: &HashMap<String, toml::Value>,
+    active: &[String],
+    defs: &HashMap<String, crate::types::XConfigDef>,
+) -> Result<()> {
+    let mut errors: Vec<String> = Vec::new();
+
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for key in keys {
+        let Some(cond) = &defs[key].required_if else {
+            continue;
+        };
+        let empty = match map.get(key) {
+            None => true,
+            Some(toml::Value::String(s)) => s.is_empty(),
+            Some(toml::Value::Array(items)) => items.is_empty(),
+            Some(_) => false,
+        };
+        if empty && cond_holds(cond, defs, active) {
+            errors.push(format!(
+                "xconfig key `{key}` is required when `{cond}` holds, but has no value"
+            ));
+        }
+    }
+
+    report_errors(&errors, "xconfig required_if check")
+}
+
+/// Reject an active set containing two keys joined by `conflicts_with`.
+/// Each conflicting pair is reported once, even if declared on both sides.
+fn check_conflicts(
+    active: &[String],
+    defs: &HashMap<String, crate::types::XConfigDef>,
+) -> Result<()> {
+    let mut errors: Vec<String> = Vec::new();
+
+    let mut keys: Vec<&String> = defs.keys().collect();
+    keys.sort();
+    for key in keys {
+        for other in &defs[key].conflicts_with {
+            let Some(other_def) = defs.get(other) else {
+                errors.push(format!(
+                    "xconfig key `{key}`: conflicts_with references unknown key `{other}`"
+                ));
+                continue;
+            };
+            let reported_by_other = other_def.conflicts_with.contains(key) && other < key;
+            if active.contains(key) && active.contains(other) && !reported_by_other {
+                errors.push(format!(
+                    "xconfig keys `{key}` and `{other}` conflict and cannot both be enabled"
+                ));
+            }
+        }
+    }
+
+    report_errors(&errors, "xconfig conflict check")
 }
 
-/// Read `.config.toml` and return (active_keys, all_keys).
-/// `all_keys` is derived from `defconfig.toml` (authoritative list).
-/// Validates value types against `defconfig.toml` definitions.
-pub fn load_active_xconfigs(root: &Path) -> Result<(Vec<String>, Vec<String>)> {
-    // all_keys comes from defconfig.toml — the authoritative source
-    let defs = load_defconfig(root)?;
-    let all_keys: Vec<String> = defs.keys().cloned().collect();
+/// One crate's `[package.metadata.xconfig]` as parsed, before any key is
+/// filtered by being active (`--dump-metadata-map`).
+#[derive(Serialize)]
+pub struct CrateMapping {
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub manifest: String,
+    /// key → its specs, as written (list placeholders not expanded)
+    pub keys: BTreeMap<String, KeyMapping>,
+    /// Own features excluded via `[package.metadata.xconfig.override]`
+    pub excluded: Vec<String>,
+}
 
-    let config_path = root.join(".config.toml");
-    let config_str = std::fs::read_to_string(&config_path)
-        .with_context(|| format!("read {}", config_path.display()))?;
-    let config: ProjectConfig = toml::from_str(&config_str).context("parse .config.toml")?;
+#[derive(Serialize)]
+pub struct KeyMapping {
+    pub specs: Vec<String>,
+    /// spec → why it is mapped, for specs written `{ target, why }`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub why: BTreeMap<String, String>,
+    /// Whether the key is active, so its specs were applied
+    pub active: bool,
+    /// Whether `defconfig.toml` defines the key at all
+    pub known: bool,
+}
 
-    let map = config.xconfig.unwrap_or_default();
+/// Accumulated result of scanning crate manifests for xconfig metadata.
+#[derive(Default)]
+pub struct MetadataScan {
+    /// target_crate → Vec<feature_name>
+    pub feature_map: HashMap<String, Vec<String>>,
+    /// Active keys that mapped to at least one feature
+    pub used_keys: HashSet<String>,
+    /// (target_crate, feature) → active keys that enabled it
+    pub feature_keys: HashMap<(String, String), BTreeSet<String>>,
+    /// (target_crate, feature) → active keys that keep it off
+    /// (`-crate/feature`)
+    pub disabled: HashMap<(String, String), BTreeSet<String>>,
+    /// crate → its own features it excludes from injection
+    excluded: HashMap<String, Vec<String>>,
+    /// Every crate with an xconfig table, in manifest path order
+    pub mappings: Vec<CrateMapping>,
+    /// Metadata keys missing from `defconfig.toml`
+    unknown: Vec<String>,
+    /// Everything else that is wrong with a mapping
+    errors: Vec<String>,
+}
 
-    // Validate against defconfig.toml
-    validate_config(&map, &defs)?;
+impl MetadataScan {
+    /// Fold another (per-manifest) scan into this one.
+    fn merge(&mut self, other: MetadataScan) {
+        for (krate, feats) in other.feature_map {
+            self.feature_map.entry(krate).or_default().extend(feats);
+        }
+        self.used_keys.extend(other.used_keys);
+        for (origin, keys) in other.feature_keys {
+            self.feature_keys.entry(origin).or_default().extend(keys);
+        }
+        for (origin, keys) in other.disabled {
+            self.disabled.entry(origin).or_default().extend(keys);
+        }
+        self.excluded.extend(other.excluded);
+        self.mappings.extend(other.mappings);
+        self.unknown.extend(other.unknown);
+        self.errors.extend(other.errors);
+    }
 
-    let active: Vec<String> = map
-        .into_iter()
-        .filter(|(_, v)| v.as_bool().unwrap_or(false))
-        .map(|(k, _)| k)
-        .collect();
+    /// Drop the features crates exclude via
+    /// `[package.metadata.xconfig.override]`, logging each one dropped.
+    /// A key left mapping to nothing is no longer in `used_keys`.
+    fn apply_exclusions(&mut self) {
+        let same = |a: &str, b: &str| a.replace('-', "_") == b.replace('-', "_");
+        for (owner, excluded) in std::mem::take(&mut self.excluded) {
+            for (krate, feats) in self.feature_map.iter_mut() {
+                if !same(krate, &owner) {
+                    continue;
+                }
+                feats.retain(|feat| {
+                    if !excluded.contains(feat) {
+                        return true;
+                    }
+                    let origin = (krate.clone(), feat.clone());
+                    let keys = self.feature_keys.remove(&origin).unwrap_or_default();
+                    info!("not injecting `{krate}/{feat}` (from {keys:?}): excluded by `{owner}`");
+                    false
+                });
+            }
+        }
+        self.feature_map.retain(|_, feats| !feats.is_empty());
+        let mapping = self.feature_keys.values().chain(self.disabled.values());
+        self.used_keys = mapping.flatten().cloned().collect();
+    }
+}
 
-    Ok((active, all_keys))
+/// Below this many manifests the scan stays on the current thread;
+/// spawning is not worth it.
+const PARALLEL_SCAN_MIN: usize = 8;
+
+/// Map `f` over `items`, once there are at least `PARALLEL_SCAN_MIN` of
+/// them on one scoped thread per available core, each taking a contiguous
+/// chunk. Results keep the order of `items`.
+fn par_map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    if items.len() < PARALLEL_SCAN_MIN {
+        return items.iter().map(f).collect();
+    }
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = items.len().div_ceil(threads);
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk)
+            .map(|part| s.spawn(move || part.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("manifest scan thread panicked"))
+            .collect()
+    })
 }
 
 /// Scan a `Cargo.toml` for `[package.metadata.xconfig]`.
-/// Populates `feature_map`: target_crate → Vec<feature_name>
+/// Populates `scan.feature_map`: target_crate → Vec<feature_name>
 ///
-/// Spec format:
+/// Spec format (anything else is an error, see `spec_problem`):
 ///   - `"crate_name/feature"` → enable feature on another crate
-///   - `"feature"` (no slash) → enable feature on self (this crate)
+///   - `"crate_name@0.2/feature"` → the same, on the version of that
+///     crate matching `0.2` (for a crate in the graph more than once);
+///     see `resolve::split_version`
+///   - `"self/feature"` or `"feature"` (no slash) → enable feature on self (this crate)
+///   - `"*/feature"` → enable feature on every scanned package in
+///     `packages` that declares it in `[features]`; the rest are skipped
+///   - any of these after a `-` (`"-crate_name/std"`) → keep the feature
+///     off instead, even as a default feature (`scan.disabled`)
+///
+/// For a `type = "list"` key, `{}` in a spec is replaced by each element
+/// of its value in `lists`: `drivers = "drivers/{}"` with
+/// `drivers = ["uart", "gpio"]` enables `drivers/uart` and `drivers/gpio`.
+/// Specs without `{}` apply once while the list is non-empty.
+///
+/// Metadata keys not declared in `defconfig.toml` (`all_keys`) and specs
+/// targeting a crate that is neither this one nor a dependency are
+/// recorded in `scan` rather than silently ignored.
 pub fn collect_xconfig_metadata(
     cargo_toml: &Path,
     active: &[String],
-    feature_map: &mut HashMap<String, Vec<String>>,
+    all_keys: &[String],
+    lists: &ListValues,
+    packages: &BTreeMap<String, HashSet<String>>,
+    scan: &mut MetadataScan,
 ) -> Result<()> {
     let content = std::fs::read_to_string(cargo_toml)?;
     let parsed: CargoToml =
         toml::from_str(&content).with_context(|| format!("parse {}", cargo_toml.display()))?;
+    let dep_names = parsed.dependency_names();
 
     let pkg = match parsed.package {
         Some(p) => p,
@@ -167,22 +1369,113 @@ pub fn collect_xconfig_metadata(
         Some(x) => x,
         None => return Ok(()),
     };
+    let excluded = xconfig.overrides.exclude;
+    let xconfig = xconfig.keys;
+    scan.mappings.push(CrateMapping {
+        krate: self_name.clone().unwrap_or_else(|| "?".to_string()),
+        manifest: cargo_toml.display().to_string(),
+        keys: xconfig
+            .iter()
+            .map(|(key, mapping)| {
+                let mapping = KeyMapping {
+                    specs: mapping.specs(),
+                    why: mapping.reasons(),
+                    active: active.contains(key),
+                    known: all_keys.contains(key),
+                };
+                (key.clone(), mapping)
+            })
+            .collect(),
+        excluded: excluded.clone(),
+    });
+    if let Some(name) = self_name.as_ref().filter(|_| !excluded.is_empty()) {
+        scan.excluded.insert(name.clone(), excluded);
+    }
+
+    // Cross-check metadata keys against defconfig.toml, and every targeted
+    // crate against this crate and its declared dependencies
+    let mut keys: Vec<&String> = xconfig.keys().collect();
+    keys.sort();
+    for key in keys {
+        if !all_keys.contains(key) {
+            scan.unknown.push(format!(
+                "crate `{}` ({}): unknown xconfig key `{key}` (not defined in defconfig.toml)",
+                self_name.as_deref().unwrap_or("?"),
+                cargo_toml.display()
+            ));
+        }
+        // An unknown key is reported above, whatever its specs
+        let is_list = lists.contains_key(key) || !all_keys.contains(key);
+        for spec in xconfig[key].specs() {
+            let bare = spec.strip_prefix('-').unwrap_or(&spec);
+            if let Some(problem) = spec_problem(bare, is_list) {
+                scan.errors.push(format!(
+                    "crate `{}` ({}): xconfig key `{key}`: spec `{spec}` {problem}",
+                    self_name.as_deref().unwrap_or("?"),
+                    cargo_toml.display()
+                ));
+                continue;
+            }
+            let Some((krate, _)) = bare.split_once('/') else {
+                continue;
+            };
+            let krate = crate::resolve::split_version(krate).0;
+            let is_self = krate == "self" || self_name.as_deref() == Some(krate);
+            // `*` and list placeholders are resolved later
+            let named = krate != "*" && !krate.contains("{}");
+            if named && !is_self && !dep_names.contains(&krate.replace('-', "_")) {
+                scan.errors.push(format!(
+                    "crate `{}` ({}): xconfig key `{key}` targets `{krate}`, which is \
+                     neither this crate nor one of its dependencies",
+                    self_name.as_deref().unwrap_or("?"),
+                    cargo_toml.display()
+                ));
+            }
+        }
+    }
 
+    let own: Vec<&str> = self_name.as_deref().into_iter().collect();
     for key in active {
-        if let Some(specs) = xconfig.get(key) {
-            for spec in specs {
-                if let Some((crate_name, feature)) = spec.split_once('/') {
-                    // "crate_name/feature" → enable feature on another crate
-                    feature_map
-                        .entry(crate_name.to_string())
+        if let Some(mapping) = xconfig.get(key) {
+            for spec in &interpolate_specs(mapping.specs(), lists.get(key)) {
+                // A leading `-` keeps the feature off rather than on
+                let (negated, spec) = match spec.strip_prefix('-') {
+                    Some(spec) => (true, spec),
+                    None => (false, spec.as_str()),
+                };
+                // "crate_name/feature" → enable feature on another crate;
+                // "self/feature" or bare "feature" → enable feature on self;
+                // "*/feature" → every package that declares the feature
+                let (targets, feature): (Vec<&str>, &str) = match spec.split_once('/') {
+                    Some(("*", feature)) => {
+                        let targets: Vec<&str> = packages
+                            .iter()
+                            .filter(|(_, feats)| feats.contains(feature))
+                            .map(|(name, _)| name.as_str())
+                            .collect();
+                        debug!("`{key}`: `{spec}` expands to {targets:?}");
+                        (targets, feature)
+                    }
+                    Some(("self", feature)) => (own.clone(), feature),
+                    Some((crate_name, feature)) => (vec![crate_name], feature),
+                    None => (own.clone(), spec),
+                };
+                for target in targets {
+                    if negated {
+                        let origin = (target.to_string(), feature.to_string());
+                        scan.disabled.entry(origin).or_default().insert(key.clone());
+                        scan.used_keys.insert(key.clone());
+                        continue;
+                    }
+                    scan.feature_map
+                        .entry(target.to_string())
                         .or_default()
                         .push(feature.to_string());
-                } else if let Some(ref name) = self_name {
-                    // "feature" → enable feature on self
-                    feature_map
-                        .entry(name.clone())
+                    scan.used_keys.insert(key.clone());
+                    scan.feature_keys
+                        .entry((target.to_string(), feature.to_string()))
                         .or_default()
-                        .push(spec.clone());
+                        .insert(key.clone());
                 }
             }
         }
@@ -190,43 +1483,209 @@ pub fn collect_xconfig_metadata(
     Ok(())
 }
 
-/// Walk `crates/` and top-level packages to collect the full feature_map.
-pub fn collect_all_metadata(
-    root: &Path,
-    active: &[String],
-) -> Result<HashMap<String, Vec<String>>> {
-    let mut feature_map: HashMap<String, Vec<String>> = HashMap::new();
+/// What is wrong with a metadata spec, if anything: it must be a bare
+/// `feature`, `crate/feature` or `crate@version/feature`, no part empty,
+/// and may only hold a `{}` placeholder for a list key (`is_list`).
+fn spec_problem(spec: &str, is_list: bool) -> Option<&'static str> {
+    if spec.contains("{}") && !is_list {
+        return Some("uses `{}`, which only a list key fills in");
+    }
+    match spec.split_once('/') {
+        None if spec.is_empty() => Some("is empty"),
+        None => None,
+        Some(("", _)) => Some("has no crate before the `/`"),
+        Some((_, "")) => Some("has no feature after the `/`"),
+        Some((_, feature)) if feature.contains('/') => Some("has more than one `/`"),
+        Some((krate, _)) if krate.ends_with('@') => Some("has no version after the `@`"),
+        Some((krate, _)) if krate.starts_with('@') => Some("has no crate before the `@`"),
+        Some(_) => None,
+    }
+}
+
+/// Substitute each list element for `{}` in `specs`; specs without it
+/// are kept. Without elements (an empty list, or a non-list key, whose
+/// `{}` is rejected by `spec_problem`) a `{}` spec expands to nothing.
+fn interpolate_specs(specs: Vec<String>, elements: Option<&Vec<String>>) -> Vec<String> {
+    let elements = elements.map_or(&[][..], Vec::as_slice);
+    let mut out = Vec::new();
+    for spec in specs {
+        if spec.contains("{}") {
+            out.extend(elements.iter().map(|e| spec.replace("{}", e)));
+        } else {
+            out.push(spec);
+        }
+    }
+    out
+}
 
+/// Manifests of the packages xconfig metadata is read from: every
+/// `crates/*` plus the top-level packages, sorted by path.
+///
+/// Entries of `crates/` that cannot be read (permission denied, dangling
+/// symlinks, symlink loops) are skipped rather than failing the build.
+fn workspace_manifests(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut manifests = Vec::new();
     let crates_dir = root.join("crates");
     if crates_dir.is_dir() {
-        for entry in std::fs::read_dir(&crates_dir)? {
-            let path = entry?.path();
+        let entries = match std::fs::read_dir(&crates_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("skipping {}: {e}", crates_dir.display());
+                return Ok(top_level_manifests(root, manifests));
+            }
+        };
+        manifests.extend(entries.filter_map(|entry| {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("skipping entry of {}: {e}", crates_dir.display());
+                    return None;
+                }
+            };
             let toml_path = path.join("Cargo.toml");
-            if toml_path.exists() {
-                collect_xconfig_metadata(&toml_path, active, &mut feature_map)?;
+            match std::fs::metadata(&toml_path) {
+                Ok(meta) if meta.is_file() => Some(toml_path),
+                Ok(_) => None,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    debug!("skipping {}: not a crate", path.display());
+                    None
+                }
+                Err(e) => {
+                    warn!("skipping {}: {e}", path.display());
+                    None
+                }
             }
-        }
+        }));
     }
-    for name in ["entry"] {
+    Ok(top_level_manifests(root, manifests))
+}
+
+/// Add the top-level packages to `manifests` and sort them by path.
+fn top_level_manifests(root: &Path, mut manifests: Vec<PathBuf>) -> Vec<PathBuf> {
+    for name in [crate::DEFAULT_PACKAGE] {
         let toml_path = root.join(name).join("Cargo.toml");
         if toml_path.exists() {
-            collect_xconfig_metadata(&toml_path, active, &mut feature_map)?;
+            manifests.push(toml_path);
+        }
+    }
+    manifests.sort();
+    manifests
+}
+
+/// Walk `crates/` and top-level packages of the cargo workspace at `root`
+/// to collect the full feature_map.
+/// Fails listing every metadata key that `defconfig.toml` doesn't define,
+/// unless `allow_unknown` downgrades those to warnings.
+///
+/// Manifests that cannot be read or parsed are all reported together;
+/// with `keep_going` they are only warned about and skipped, and the scan
+/// carries on with the rest.
+///
+/// Manifests are parsed in parallel on large workspaces; per-manifest
+/// results are merged in path order and each crate's features sorted, so
+/// the outcome never depends on thread scheduling.
+pub fn collect_all_metadata(
+    root: &Path,
+    active: &[String],
+    all_keys: &[String],
+    lists: &ListValues,
+    allow_unknown: bool,
+    keep_going: bool,
+) -> Result<MetadataScan> {
+    let manifests = workspace_manifests(root)?;
+
+    // package → declared features, for expanding "*/feature" specs
+    let declared = par_map(&manifests, |toml_path| -> Result<_> {
+        let content = std::fs::read_to_string(toml_path)
+            .with_context(|| format!("read {}", toml_path.display()))?;
+        let parsed: CargoToml =
+            toml::from_str(&content).with_context(|| format!("parse {}", toml_path.display()))?;
+        Ok(parsed
+            .package
+            .and_then(|p| p.name)
+            .map(|name| (name, parsed.features.into_keys().collect::<HashSet<_>>())))
+    });
+    let mut packages = BTreeMap::new();
+    let mut parsed = Vec::with_capacity(manifests.len());
+    let mut broken = Vec::new();
+    for (toml_path, entry) in manifests.iter().zip(declared) {
+        match entry {
+            Ok(entry) => {
+                packages.extend(entry);
+                parsed.push(toml_path.clone());
+            }
+            Err(e) => broken.push(format!("{e:#}")),
+        }
+    }
+    if keep_going {
+        for e in &broken {
+            warn!("{e} (skipped: --keep-going)");
+        }
+    } else {
+        report_errors(&broken, "manifest parsing")?;
+    }
+
+    let scans = par_map(&parsed, |toml_path| {
+        let mut scan = MetadataScan::default();
+        collect_xconfig_metadata(toml_path, active, all_keys, lists, &packages, &mut scan)?;
+        Ok::<_, anyhow::Error>(scan)
+    });
+    let mut scan = MetadataScan::default();
+    for one in scans {
+        scan.merge(one?);
+    }
+    scan.apply_exclusions();
+    for feats in scan.feature_map.values_mut() {
+        feats.sort();
+        feats.dedup();
+    }
+
+    // One active key enabling a feature that another keeps off is a
+    // contradiction either way round
+    let mut conflicts: Vec<String> = Vec::new();
+    for ((krate, feat), off) in &scan.disabled {
+        let Some(on) = scan.feature_keys.get(&(krate.clone(), feat.clone())) else {
+            continue;
+        };
+        let feats = scan.feature_map.get(krate);
+        if feats.is_some_and(|f| f.contains(feat)) {
+            conflicts.push(format!(
+                "feature `{feat}` of `{krate}` is enabled by xconfig {on:?} but disabled by {off:?}"
+            ));
         }
     }
+    conflicts.sort();
+    scan.errors.extend(conflicts);
 
-    Ok(feature_map)
+    let unknown = std::mem::take(&mut scan.unknown);
+    if allow_unknown {
+        for e in &unknown {
+            warn!("{e} (ignored: --allow-unknown)");
+        }
+    } else {
+        scan.errors.extend(unknown);
+    }
+    report_errors(&scan.errors, "[package.metadata.xconfig] validation")?;
+    Ok(scan)
 }
 
 /// Regenerate `.cargo/config.toml` so that rust-analyzer picks up
 /// the active xconfig cfgs via `[build] rustflags`.
-/// Also includes `--extern` and `-Ldependency` for xdeps rlibs so
-/// rust-analyzer can resolve optional deps injected via RUSTC_WRAPPER.
+/// Also includes `--extern` for xdeps rlibs and `-Ldependency` for every
+/// directory in `dep_dirs` so rust-analyzer can resolve optional deps
+/// (and their transitive deps) injected via RUSTC_WRAPPER.
+/// Warns when the file was edited by hand since it was last written,
+/// unless `force`. Returns whether the file was (re)written; with
+/// `check`, whether it is out of date, leaving it as is (`report_drift`).
 pub fn sync_cargo_config(
     root: &Path,
     active: &[String],
     all_keys: &[String],
     rlib_paths: &HashMap<String, String>,
-) -> Result<()> {
+    dep_dirs: &BTreeSet<String>,
+    force: bool,
+    check: bool,
+) -> Result<bool> {
     let mut content = String::from("\
 # Auto-generated by cargo-xbuild — do not edit manually.\n\
 # Run `cargo xbuild` to regenerate after changing .config.toml.\n\
@@ -235,23 +1694,23 @@ pub fn sync_cargo_config(
     let mut flags: Vec<String> = Vec::new();
     // --cfg for active keys only
     for c in active {
-        flags.push(format!("\"--cfg={}\"" , c.to_uppercase()));
+        flags.push(format!("\"--cfg={}\"", cfg_name(c)));
     }
     // --check-cfg for ALL known keys (so rust-analyzer never warns)
     for c in all_keys {
-        flags.push(format!("\"--check-cfg=cfg({})\"", c.to_uppercase()));
+        flags.push(format!("\"--check-cfg=cfg({})\"", cfg_name(c)));
     }
     flags.push("\"--check-cfg=cfg(__xfp,values(any()))\"".to_string());
 
     // --extern for xdeps rlibs (so RA can resolve injected optional deps)
-    for (name, path) in rlib_paths {
+    let mut rlibs: Vec<_> = rlib_paths.iter().collect();
+    rlibs.sort();
+    for (name, path) in rlibs {
         flags.push(format!("\"--extern={}={}\"", name, path));
     }
     // -Ldependency so RA can find transitive xdeps rlibs
-    if let Some(first_rlib) = rlib_paths.values().next() {
-        if let Some(deps_dir) = Path::new(first_rlib).parent() {
-            flags.push(format!("\"-Ldependency={}\"", deps_dir.display()));
-        }
+    for dir in dep_dirs {
+        flags.push(format!("\"-Ldependency={dir}\""));
     }
 
     content.push_str(&format!(
@@ -261,49 +1720,957 @@ pub fn sync_cargo_config(
 
     let config_path = root.join(".cargo").join("config.toml");
     let existing = std::fs::read_to_string(&config_path).unwrap_or_default();
-    if existing != content {
-        std::fs::create_dir_all(root.join(".cargo"))?;
-        std::fs::write(&config_path, &content)?;
-        eprintln!("[xbuild] synced .cargo/config.toml");
+    if existing == content {
+        return Ok(false);
     }
-    Ok(())
+    if check {
+        report_drift(&config_path, &existing, &content);
+        return Ok(true);
+    }
+
+    if cargo_config_hand_edited(root, &existing) && !force {
+        warn!(
+            ".cargo/config.toml was edited since cargo-xbuild last wrote it; \
+             the edits are being overwritten (--force silences this)"
+        );
+    }
+
+    std::fs::create_dir_all(root.join(".cargo"))?;
+    std::fs::write(&config_path, &content)?;
+    let stamp = cargo_config_stamp(root);
+    if let Some(dir) = stamp.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&stamp, content_hash(&content) + "\n")
+        .with_context(|| format!("write {}", stamp.display()))?;
+    info!("synced .cargo/config.toml");
+    Ok(true)
+}
+
+/// `--check-sync`: show how the IDE file at `path`, currently `existing`,
+/// differs from what a sync would write, as a diff on stderr.
+fn report_drift(path: &Path, existing: &str, expected: &str) {
+    warn!("{} is out of date", path.display());
+    eprintln!("--- {} (on disk)", path.display());
+    eprintln!("+++ {} (after sync)", path.display());
+    for line in line_diff(existing, expected) {
+        eprintln!("{line}");
+    }
+}
+
+/// The lines removed from `old` (`-`) and added in `new` (`+`), in order,
+/// by longest common subsequence. Unchanged lines are left out.
+fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    // lcs[i][j]: length of the longest common subsequence of a[i..], b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::new();
+    loop {
+        match (a.get(i), b.get(j)) {
+            (Some(x), Some(y)) if x == y => (i, j) = (i + 1, j + 1),
+            (Some(x), None) => {
+                out.push(format!("-{x}"));
+                i += 1;
+            }
+            (Some(x), Some(_)) if lcs[i + 1][j] >= lcs[i][j + 1] => {
+                out.push(format!("-{x}"));
+                i += 1;
+            }
+            (_, Some(y)) => {
+                out.push(format!("+{y}"));
+                j += 1;
+            }
+            (None, None) => return out,
+        }
+    }
+}
+
+/// Where `sync_cargo_config` records the hash of what it last wrote.
+fn cargo_config_stamp(root: &Path) -> PathBuf {
+    root.join("target").join("xbuild").join("cargo-config.hash")
+}
+
+fn content_hash(s: &str) -> String {
+    format!("{:016x}", crate::fnv1a(&[s]))
+}
+
+/// Whether `.cargo/config.toml`, currently `existing`, was edited since
+/// cargo-xbuild last wrote it. The hash of what was last written tells a
+/// hand edit apart from a file that is merely out of date.
+pub fn cargo_config_hand_edited(root: &Path, existing: &str) -> bool {
+    !existing.is_empty()
+        && std::fs::read_to_string(cargo_config_stamp(root))
+            .is_ok_and(|last| last.trim() != content_hash(existing))
 }
 
-/// Regenerate `.vscode/settings.json` so rust-analyzer picks up xconfig cfgs
+/// Update `.vscode/settings.json` so rust-analyzer picks up xconfig cfgs
 /// and feature activation inferred from `[package.metadata.xconfig]`.
+///
+/// Only `rust-analyzer.cargo.features` and `rust-analyzer.cargo.cfgs` are
+/// owned by cargo-xbuild; every other setting is kept, in its order. A
+/// file that is not a plain JSON object (VS Code also accepts comments,
+/// which are not supported here) is warned about and left as is, and the
+/// sync of it is skipped.
+///
+/// `extra_env` vars are set in `rust-analyzer.cargo.extraEnv`, next to
+/// any the user put there; with none, that setting is left alone.
+/// Returns whether the file was (re)written; with `check`, whether it is
+/// out of date, leaving it as is (`report_drift`).
 pub fn sync_vscode_settings(
     root: &Path,
     active: &[String],
     feature_map: &HashMap<String, Vec<String>>,
-) -> Result<()> {
-    use serde_json::json;
+    extra_env: &[(&str, String)],
+    check: bool,
+) -> Result<bool> {
     use std::collections::BTreeSet;
 
-    let cfgs = active
-        .iter()
-        .map(|c| c.to_uppercase())
-        .collect::<Vec<_>>();
+    let cfgs = active.iter().map(|c| cfg_name(c)).collect::<Vec<_>>();
 
     let mut features = BTreeSet::new();
     for (crate_name, feats) in feature_map {
+        // rust-analyzer, like cargo, names the crate without a version
+        let crate_name = crate::resolve::split_version(crate_name).0;
         for feat in feats {
             features.insert(format!("{crate_name}/{feat}"));
         }
     }
 
-    let settings = json!({
-        "rust-analyzer.cargo.features": features.into_iter().collect::<Vec<_>>(),
-        "rust-analyzer.cargo.cfgs": cfgs,
-    });
-
-    let content = serde_json::to_string_pretty(&settings)? + "\n";
     let settings_path = root.join(".vscode").join("settings.json");
     let existing = std::fs::read_to_string(&settings_path).unwrap_or_default();
-    if existing != content {
-        std::fs::create_dir_all(root.join(".vscode"))?;
-        std::fs::write(&settings_path, content)?;
-        eprintln!("[xbuild] synced .vscode/settings.json");
+    let mut settings = if existing.trim().is_empty() {
+        serde_json::Map::new()
+    } else {
+        match serde_json::from_str::<serde_json::Value>(&existing) {
+            Ok(serde_json::Value::Object(settings)) => settings,
+            Ok(_) => {
+                warn!("not syncing {}: not a JSON object", settings_path.display());
+                return Ok(false);
+            }
+            Err(e) => {
+                warn!(
+                    "not syncing {}: {e}; fix it, or remove it to have it regenerated",
+                    settings_path.display()
+                );
+                return Ok(false);
+            }
+        }
+    };
+    let features: Vec<String> = features.into_iter().collect();
+    for (key, value) in [("features", features), ("cfgs", cfgs)] {
+        let value = serde_json::to_value(value)?;
+        settings.insert(format!("rust-analyzer.cargo.{key}"), value);
+    }
+    if !extra_env.is_empty() {
+        let env = settings
+            .entry("rust-analyzer.cargo.extraEnv".to_string())
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        let Some(env) = env.as_object_mut() else {
+            warn!(
+                "not syncing {}: rust-analyzer.cargo.extraEnv is not an object",
+                settings_path.display()
+            );
+            return Ok(false);
+        };
+        for (var, value) in extra_env {
+            env.insert(var.to_string(), serde_json::Value::String(value.clone()));
+        }
     }
 
-    Ok(())
+    let content = serde_json::to_string_pretty(&settings)? + "\n";
+    if existing == content {
+        return Ok(false);
+    }
+    if check {
+        report_drift(&settings_path, &existing, &content);
+        return Ok(true);
+    }
+    std::fs::create_dir_all(root.join(".vscode"))?;
+    std::fs::write(&settings_path, content)?;
+    info!("synced .vscode/settings.json");
+    Ok(true)
+}
+
+/// Update the `CargoProjects` component of `.idea/workspace.xml` so the
+/// IntelliJ Rust plugin (and RustRover) enables the features inferred from
+/// `[package.metadata.xconfig]` on workspace packages. cfgs need no extra
+/// file: the plugin reads them from `[build] rustflags` in the
+/// `.cargo/config.toml` that `sync_cargo_config` writes.
+///
+/// The component is owned by cargo-xbuild: features toggled by hand in
+/// the IDE are replaced on the next sync. The rest of `workspace.xml` is
+/// left alone. Returns whether the file was (re)written.
+///
+/// `.idea/` is the project's, at `root`; the packages are those of the
+/// cargo `workspace`, which may sit below it, and paths run through it
+/// (`$PROJECT_DIR$/code/crates/crate_a`). A manifest that cannot be read
+/// or parsed is warned about and its package left out; the build
+/// reports it (or skips it, with `--keep-going`) on its own.
+pub fn sync_idea_settings(
+    root: &Path,
+    workspace: &Path,
+    feature_map: &HashMap<String, Vec<String>>,
+) -> Result<bool> {
+    // `$PROJECT_DIR$/code/` for a workspace below the root; its absolute
+    // path for one elsewhere
+    let base = match workspace.strip_prefix(root) {
+        Ok(rel) if rel.as_os_str().is_empty() => "$PROJECT_DIR$/".to_string(),
+        Ok(rel) => format!("$PROJECT_DIR$/{}/", rel.display()),
+        Err(_) => format!("{}/", workspace.display()),
+    };
+    // crate name → package directory relative to the workspace
+    let mut package_dirs = BTreeMap::new();
+    for toml_path in workspace_manifests(workspace)? {
+        let parsed = std::fs::read_to_string(&toml_path)
+            .with_context(|| format!("read {}", toml_path.display()))
+            .and_then(|content| {
+                toml::from_str::<CargoToml>(&content)
+                    .with_context(|| format!("parse {}", toml_path.display()))
+            });
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("{e:#}; left out of .idea/workspace.xml");
+                continue;
+            }
+        };
+        let name = parsed.package.and_then(|p| p.name);
+        if let (Some(name), Some(dir)) = (name, toml_path.parent()) {
+            let dir = dir.strip_prefix(workspace).unwrap_or(dir);
+            package_dirs.insert(name.replace('-', "_"), dir.display().to_string());
+        }
+    }
+
+    let mut packages = String::new();
+    for (crate_name, dir) in &package_dirs {
+        let Some(feats) = feature_map
+            .iter()
+            .find(|(cn, _)| crate::resolve::split_version(cn).0.replace('-', "_") == *crate_name)
+            .map(|(_, feats)| feats)
+        else {
+            continue;
+        };
+        packages.push_str(&format!("      <package file=\"{base}{dir}\">\n"));
+        for feat in feats {
+            packages.push_str(&format!(
+                "        <feature name=\"{}\" enabled=\"true\" />\n",
+                xml_escape(feat)
+            ));
+        }
+        packages.push_str("      </package>\n");
+    }
+    let component = format!(
+        "  <component name=\"CargoProjects\">\n\
+         \x20   <cargoProject FILE=\"{base}Cargo.toml\">\n\
+         {packages}\
+         \x20   </cargoProject>\n\
+         \x20 </component>\n"
+    );
+
+    let idea_dir = root.join(".idea");
+    let workspace_path = idea_dir.join("workspace.xml");
+    let existing = std::fs::read_to_string(&workspace_path).unwrap_or_default();
+    let content = replace_xml_component(&existing, "CargoProjects", &component);
+    if existing == content {
+        return Ok(false);
+    }
+    std::fs::create_dir_all(&idea_dir)?;
+    std::fs::write(&workspace_path, content)?;
+    info!("synced .idea/workspace.xml");
+    Ok(true)
+}
+
+/// Swap the `<component name="…">` element of an IntelliJ project file
+/// for `component` (whole lines), or insert it before `</project>` if
+/// absent. An empty `xml` becomes a fresh project file.
+fn replace_xml_component(xml: &str, name: &str, component: &str) -> String {
+    let xml = if xml.trim().is_empty() {
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<project version=\"4\">\n</project>\n"
+    } else {
+        xml
+    };
+
+    if let Some(pos) = xml.find(&format!("<component name=\"{name}\"")) {
+        let start = xml[..pos].rfind('\n').map_or(0, |i| i + 1);
+        let rest = &xml[pos..];
+        // Either `<component … />` or `<component …>…</component>`
+        let tag_end = rest.find('>').map_or(rest.len(), |i| i + 1);
+        let len = if rest[..tag_end].ends_with("/>") {
+            tag_end
+        } else {
+            rest.find("</component>")
+                .map_or(rest.len(), |i| i + "</component>".len())
+        };
+        let mut end = pos + len;
+        if xml[end..].starts_with('\n') {
+            end += 1;
+        }
+        return format!("{}{component}{}", &xml[..start], &xml[end..]);
+    }
+    match xml.rfind("</project>") {
+        Some(i) => {
+            let i = xml[..i].rfind('\n').map_or(i, |nl| nl + 1);
+            format!("{}{component}{}", &xml[..i], &xml[i..])
+        }
+        None => format!("{xml}{component}"),
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{scratch, strings};
+
+    /// Scan the manifests `files` of a scratch workspace `name`, with the
+    /// `active` keys on and every key in `all` defined.
+    fn scan(name: &str, files: &[(&str, &str)], active: &[&str], all: &[&str]) -> MetadataScan {
+        let root = scratch(name, files);
+        let lists = ListValues::default();
+        collect_all_metadata(&root, &strings(active), &strings(all), &lists, false, false).unwrap()
+    }
+
+    /// The paths of a scratch project `name` holding `files`, with
+    /// `defconfig.toml` and `.config.toml` at its root.
+    fn project(name: &str, files: &[(&str, &str)]) -> ConfigPaths {
+        let root = scratch(name, files);
+        ConfigPaths {
+            defconfig: root.join("defconfig.toml"),
+            config: root.join(".config.toml"),
+            workspace: root.clone(),
+            root,
+            config_env: false,
+        }
+    }
+
+    #[test]
+    fn excluded_feature_is_not_injected() {
+        let files = [
+            (
+                "crates/a/Cargo.toml",
+                r#"
+[package]
+name = "a"
+version = "0.1.0"
+
+[dependencies]
+b = { path = "../b" }
+
+[package.metadata.xconfig]
+smp = ["smp", "b/smp"]
+"#,
+            ),
+            (
+                "crates/b/Cargo.toml",
+                r#"
+[package]
+name = "b"
+version = "0.1.0"
+
+[features]
+smp = []
+
+[package.metadata.xconfig.override]
+exclude = ["smp"]
+"#,
+            ),
+        ];
+        let scan = scan("excluded_feature", &files, &["smp"], &["smp"]);
+        assert_eq!(scan.feature_map.get("a"), Some(&strings(&["smp"])));
+        assert!(!scan.feature_map.contains_key("b"));
+        assert!(scan.used_keys.contains("smp"));
+    }
+
+    #[test]
+    fn key_mapping_only_excluded_features_is_unused() {
+        let files = [
+            (
+                "crates/a/Cargo.toml",
+                r#"
+[package]
+name = "a"
+version = "0.1.0"
+
+[dependencies]
+b = { path = "../b" }
+
+[package.metadata.xconfig]
+smp = "b/smp"
+"#,
+            ),
+            (
+                "crates/b/Cargo.toml",
+                r#"
+[package]
+name = "b"
+version = "0.1.0"
+
+[features]
+smp = []
+
+[package.metadata.xconfig.override]
+exclude = ["smp"]
+"#,
+            ),
+        ];
+        let scan = scan("excluded_only", &files, &["smp"], &["smp"]);
+        assert!(scan.feature_map.is_empty());
+        assert!(!scan.used_keys.contains("smp"));
+    }
+
+    #[test]
+    fn self_and_bare_specs_enable_the_same_feature() {
+        let manifest = |spec: &str| {
+            format!(
+                "[package]\nname = \"a\"\nversion = \"0.1.0\"\n\n\
+                 [features]\nsmp = []\n\n\
+                 [package.metadata.xconfig]\nsmp = \"{spec}\"\n"
+            )
+        };
+        let scan_with = |name: &str, spec: &str| {
+            let files = [("crates/a/Cargo.toml", manifest(spec))];
+            let files = files.each_ref().map(|(path, text)| (*path, text.as_str()));
+            scan(name, &files, &["smp"], &["smp"])
+        };
+        let bare = scan_with("bare_spec", "smp");
+        let qualified = scan_with("self_spec", "self/smp");
+        assert_eq!(bare.feature_map, qualified.feature_map);
+        assert_eq!(bare.feature_map.get("a"), Some(&strings(&["smp"])));
+    }
+
+    #[test]
+    fn wildcard_enables_the_feature_where_declared() {
+        let manifest = |name: &str, features: &str, metadata: &str| {
+            format!(
+                "[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n\n\
+                 [features]\n{features}\n{metadata}"
+            )
+        };
+        let a = manifest(
+            "a",
+            "tracing = []",
+            "[package.metadata.xconfig]\ntracing = \"*/tracing\"\n",
+        );
+        let b = manifest("b", "tracing = []", "");
+        let c = manifest("c", "std = []", "");
+        let files = [
+            ("crates/a/Cargo.toml", a.as_str()),
+            ("crates/b/Cargo.toml", b.as_str()),
+            ("crates/c/Cargo.toml", c.as_str()),
+        ];
+        let scan = scan("wildcard_spec", &files, &["tracing"], &["tracing"]);
+        assert_eq!(scan.feature_map.get("a"), Some(&strings(&["tracing"])));
+        assert_eq!(scan.feature_map.get("b"), Some(&strings(&["tracing"])));
+        assert!(!scan.feature_map.contains_key("c"));
+        assert!(scan.errors.is_empty(), "{:?}", scan.errors);
+    }
+
+    #[test]
+    fn overlapping_specs_enable_a_feature_once() {
+        let files = [
+            (
+                "crates/a/Cargo.toml",
+                r#"
+[package]
+name = "a"
+version = "0.1.0"
+
+[dependencies]
+b = { path = "../b" }
+
+[package.metadata.xconfig]
+smp = "b/smp"
+net = ["b/smp", "b/net"]
+"#,
+            ),
+            (
+                "crates/b/Cargo.toml",
+                r#"
+[package]
+name = "b"
+version = "0.1.0"
+
+[features]
+smp = []
+net = []
+"#,
+            ),
+        ];
+        let keys = ["net", "smp"];
+        let scan = scan("overlapping_specs", &files, &keys, &keys);
+        assert_eq!(scan.feature_map.get("b"), Some(&strings(&["net", "smp"])));
+        let encoded = crate::wrapper::encode_features(&scan.feature_map, &HashMap::new());
+        assert_eq!(encoded, "b:net,smp");
+    }
+
+    #[test]
+    fn two_element_list_expands_each_placeholder_spec() {
+        let specs = strings(&["drivers/{}", "drivers/registry", "-hal/{}_polling"]);
+        let elements = strings(&["uart", "gpio"]);
+        let expanded = interpolate_specs(specs.clone(), Some(&elements));
+        let expected = [
+            "drivers/uart",
+            "drivers/gpio",
+            "drivers/registry",
+            "-hal/uart_polling",
+            "-hal/gpio_polling",
+        ];
+        assert_eq!(expanded, expected);
+        let empty = interpolate_specs(specs, Some(&Vec::new()));
+        assert_eq!(empty, ["drivers/registry"]);
+    }
+
+    #[test]
+    fn local_keys_override_an_included_base() {
+        let files = [
+            ("shared/base.toml", "[xconfig]\nsmp = true\nmtu = 1500\n"),
+            (
+                "board/.config.toml",
+                "include = [\"../shared/base.toml\"]\n\n[xconfig]\nmtu = 9000\n",
+            ),
+        ];
+        let root = scratch("include-override", &files);
+        let file = root.join("board/.config.toml");
+        let content = std::fs::read_to_string(&file).unwrap();
+        let merged = merged_config(&content, &file, ".config.toml").unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["smp"], toml::Value::Boolean(true));
+        assert_eq!(merged["mtu"], toml::Value::Integer(9000));
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let files = [
+            ("a.toml", "include = [\"b.toml\"]\n"),
+            ("b.toml", "include = [\"a.toml\"]\n"),
+        ];
+        let root = scratch("include-cycle", &files);
+        let file = root.join("a.toml");
+        let content = std::fs::read_to_string(&file).unwrap();
+        let err = merged_config(&content, &file, "a.toml").unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains(": include cycle: "), "{err}");
+        assert!(err.ends_with("/a.toml"), "{err}");
+    }
+
+    #[test]
+    fn cargo_workspace_below_the_defconfig_is_found_from_cwd() {
+        let files = [
+            ("defconfig.toml", "[xconfig.smp]\n"),
+            (
+                "code/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[workspace]\n",
+            ),
+            ("code/src/lib.rs", ""),
+        ];
+        let root = scratch("workspace-below-root", &files);
+        let code = root.join("code");
+        let found = workspace_root_from(&root, Some(&code.join("src")));
+        assert_eq!(found.canonicalize().unwrap(), code.canonicalize().unwrap());
+        // Outside any workspace, the defconfig root is used as is
+        assert_eq!(workspace_root_from(&root, None), root);
+    }
+
+    /// A bool key conflicting with each of `conflicts_with`.
+    fn bool_key(conflicts_with: &[&str]) -> crate::types::XConfigDef {
+        crate::types::XConfigDef {
+            description: None,
+            typ: "bool".to_string(),
+            element: None,
+            default: Some(toml::Value::Boolean(false)),
+            aliases: Vec::new(),
+            required_if: None,
+            tristate: false,
+            host: true,
+            emit: "both".to_string(),
+            conflicts_with: strings(conflicts_with),
+            group: None,
+            min: None,
+            max: None,
+        }
+    }
+
+    #[test]
+    fn two_conflicting_keys_enabled_fail() {
+        // Declared on both sides, the pair is still reported once
+        let defs = HashMap::from([
+            ("allocator_buddy".to_string(), bool_key(&["allocator_slab"])),
+            ("allocator_slab".to_string(), bool_key(&["allocator_buddy"])),
+            ("smp".to_string(), bool_key(&[])),
+        ]);
+        let check =
+            |active: &[&str]| check_conflicts(&strings(active), &defs).map_err(|e| e.to_string());
+        let both = check(&["allocator_buddy", "allocator_slab", "smp"]);
+        let failed = "xconfig conflict check failed (1 error)".to_string();
+        assert_eq!(both, Err(failed));
+        assert!(check(&["allocator_buddy", "smp"]).is_ok());
+        assert!(check(&["allocator_slab"]).is_ok());
+    }
+
+    #[test]
+    fn defaults_must_match_their_type() {
+        use toml::Value::{Array, Boolean, Integer};
+        let text = |s: &str| toml::Value::String(s.to_string());
+        let typed = |typ: &str, element: Option<&str>| crate::types::XConfigDef {
+            typ: typ.to_string(),
+            element: element.map(str::to_string),
+            ..bool_key(&[])
+        };
+        let ints = Array(vec![Integer(1), Integer(2)]);
+        let names = Array(vec![text("uart"), text("gpio")]);
+        let cases = [
+            (typed("bool", None), Boolean(true), Some(true)),
+            (typed("bool", None), Integer(1), Some(false)),
+            (typed("int", None), Integer(1500), Some(true)),
+            (typed("int", None), text("1500"), Some(false)),
+            (typed("string", None), text("eth0"), Some(true)),
+            (typed("string", None), Boolean(false), Some(false)),
+            (typed("list", None), names.clone(), Some(true)),
+            (typed("list", None), ints.clone(), Some(false)),
+            (typed("list", Some("int")), ints, Some(true)),
+            (typed("list", Some("int")), names, Some(false)),
+            (typed("float", None), Integer(1), None),
+        ];
+        for (def, default, expected) in cases {
+            let matches = value_matches_type(&def, &default);
+            assert_eq!(matches, expected, "{} = {default}", def.typ);
+        }
+    }
+
+    #[test]
+    fn generated_config_writes_typed_defaults() {
+        let defconfig = "\
+[xconfig.smp]
+default = true
+
+[xconfig.mtu]
+type = \"int\"
+default = 1500
+
+[xconfig.iface]
+type = \"string\"
+default = \"eth0\"
+
+[xconfig.drivers]
+type = \"list\"
+default = [\"uart\"]
+";
+        let paths = project("typed-defaults", &[("defconfig.toml", defconfig)]);
+        ensure_config_toml(&paths).unwrap();
+        let generated = std::fs::read_to_string(&paths.config).unwrap();
+        let expected = [
+            "smp = true",
+            "mtu = 1500",
+            "iface = \"eth0\"",
+            "drivers = [\"uart\"]",
+        ];
+        for line in expected {
+            let found = generated.lines().any(|l| l == line);
+            assert!(found, "`{line}` in:\n{generated}");
+        }
+    }
+
+    #[test]
+    fn kconfig_y_and_n_become_booleans() {
+        let tristate = crate::types::XConfigDef {
+            tristate: true,
+            ..bool_key(&[])
+        };
+        let defs = HashMap::from([
+            ("smp".to_string(), bool_key(&[])),
+            ("net".to_string(), bool_key(&[])),
+            ("fs".to_string(), bool_key(&[])),
+            ("usb".to_string(), tristate),
+        ]);
+        let vars = [
+            ("CONFIG_SMP", "y"),
+            ("CONFIG_NET", "n"),
+            ("CONFIG_FS", "m"),
+            ("CONFIG_USB", "m"),
+            ("CONFIG_UNKNOWN", "y"),
+            ("SMP", "n"),
+        ];
+        let vars = vars.map(|(var, value)| (var.to_string(), value.to_string()));
+        let values = kconfig_values(&defs, vars);
+        let expected = HashMap::from([
+            ("smp".to_string(), toml::Value::Boolean(true)),
+            ("net".to_string(), toml::Value::Boolean(false)),
+            // `m` is a module only for a tristate key
+            ("fs".to_string(), toml::Value::Boolean(true)),
+            ("usb".to_string(), toml::Value::String("m".to_string())),
+        ]);
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn update_adds_a_new_key_and_keeps_the_rest() {
+        let defconfig = "\
+[xconfig.smp]
+default = false
+
+[xconfig.mtu]
+description = \"Link MTU\"
+type = \"int\"
+default = 1500
+";
+        let config = "\
+# board tweaks
+[xconfig]
+smp = true # needed for the demo
+legacy = true
+";
+        let files = [("defconfig.toml", defconfig), (".config.toml", config)];
+        let paths = project("update-new-key", &files);
+        update_config_toml(&paths).unwrap();
+        let updated = std::fs::read_to_string(&paths.config).unwrap();
+        // `legacy`, no longer defined, is only warned about
+        assert_eq!(updated, format!("{config}\n# Link MTU\nmtu = 1500\n"));
+    }
+
+    #[test]
+    fn key_with_two_aliases_sets_and_declares_both() {
+        let defconfig = "\
+[xconfig.smp]
+default = false
+aliases = [\"CONFIG_SMP\", \"MULTICORE\"]
+
+[xconfig.net]
+default = false
+aliases = [\"CONFIG_NET\"]
+";
+        let config = "[xconfig]\nsmp = true\nnet = false\n";
+        let files = [("defconfig.toml", defconfig), (".config.toml", config)];
+        let paths = project("two-aliases", &files);
+        let (active, all_keys, _) = load_active_xconfigs(&paths).unwrap();
+        assert_eq!(active, ["CONFIG_SMP", "MULTICORE", "smp"]);
+        // Declared to --check-cfg whether on or not
+        let declared = ["CONFIG_NET", "CONFIG_SMP", "MULTICORE", "net", "smp"];
+        assert_eq!(all_keys, declared);
+    }
+
+    #[test]
+    fn non_crate_entries_of_crates_are_skipped() {
+        let files = [
+            ("crates/a/Cargo.toml", "[package]\nname = \"a\"\n"),
+            ("crates/docs/README.md", "not a crate"),
+            ("crates/notes.txt", ""),
+            ("entry/Cargo.toml", "[package]\nname = \"entry\"\n"),
+        ];
+        let root = scratch("non-crate-dirs", &files);
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("missing"), root.join("crates/dangling")).unwrap();
+        let manifests = workspace_manifests(&root).unwrap();
+        let expected = [
+            root.join("crates/a/Cargo.toml"),
+            root.join("entry/Cargo.toml"),
+        ];
+        assert_eq!(manifests, expected);
+    }
+
+    #[test]
+    fn required_key_needs_a_value_only_under_its_condition() {
+        let mac = crate::types::XConfigDef {
+            typ: "string".to_string(),
+            default: None,
+            required_if: Some("NET".to_string()),
+            ..bool_key(&[])
+        };
+        let defs = HashMap::from([
+            ("net".to_string(), bool_key(&[])),
+            ("net_mac_addr".to_string(), mac),
+        ]);
+        let check = |active: &[&str], mac: Option<&str>| {
+            let value = mac.map(|m| ("net_mac_addr".to_string(), toml::Value::String(m.into())));
+            let map: HashMap<String, toml::Value> = value.into_iter().collect();
+            check_required(&map, &strings(active), &defs).map_err(|e| e.to_string())
+        };
+        let failed = Err("xconfig required_if check failed (1 error)".to_string());
+        assert_eq!(check(&["net"], None), failed);
+        assert_eq!(check(&["net"], Some("")), failed);
+        assert_eq!(check(&["net"], Some("02:00:00:00:00:01")), Ok(()));
+        assert_eq!(check(&[], None), Ok(()));
+    }
+
+    #[test]
+    fn key_defined_in_two_fragments_fails_the_merge() {
+        let net = "[xconfig.net]\ndefault = true\n";
+        let files = [
+            ("defconfig.toml", "[xconfig.smp]\ndefault = false\n"),
+            ("defconfig.d/board.toml", net),
+            ("defconfig.d/net.toml", net),
+        ];
+        let paths = project("duplicate-fragment-key", &files);
+        let merged = load_defconfig(&paths.defconfig).map(|_| ());
+        let failed = "defconfig.d merge failed (1 error)".to_string();
+        assert_eq!(merged.map_err(|e| e.to_string()), Err(failed));
+        // Without the duplicate, both files' keys are loaded
+        std::fs::remove_file(paths.root.join("defconfig.d/board.toml")).unwrap();
+        let defs = load_defconfig(&paths.defconfig).unwrap();
+        assert!(defs.contains_key("smp") && defs.contains_key("net"));
+    }
+
+    #[test]
+    fn vscode_sync_keeps_unrelated_settings() {
+        let existing = r#"{
+  "editor.formatOnSave": true,
+  "rust-analyzer.cargo.features": ["old/feature"],
+  "files.exclude": { "target": true }
+}"#;
+        let root = scratch("vscode-merge", &[(".vscode/settings.json", existing)]);
+        let feature_map = HashMap::from([("app@0.1".to_string(), strings(&["net"]))]);
+        let synced = sync_vscode_settings(&root, &strings(&["smp"]), &feature_map, &[], false);
+        assert!(synced.unwrap());
+        let expected = r#"{
+  "editor.formatOnSave": true,
+  "rust-analyzer.cargo.features": [
+    "app/net"
+  ],
+  "files.exclude": {
+    "target": true
+  },
+  "rust-analyzer.cargo.cfgs": [
+    "SMP"
+  ]
+}
+"#;
+        let settings = root.join(".vscode/settings.json");
+        assert_eq!(std::fs::read_to_string(&settings).unwrap(), expected);
+
+        // Malformed JSON is reported and left alone
+        std::fs::write(&settings, "{ \"editor.formatOnSave\": tru").unwrap();
+        let synced = sync_vscode_settings(&root, &strings(&["smp"]), &feature_map, &[], false);
+        assert!(!synced.unwrap());
+        let kept = std::fs::read_to_string(&settings).unwrap();
+        assert_eq!(kept, "{ \"editor.formatOnSave\": tru");
+    }
+
+    #[test]
+    fn empty_config_means_every_default() {
+        let defconfig = "[xconfig.smp]\ndefault = true\n\n[xconfig.net]\ndefault = false\n";
+        for (name, config) in [
+            ("empty-config", ""),
+            ("blank-config", "  \n\n"),
+            ("comment-only-config", "# nothing set yet\n[xconfig]\n"),
+        ] {
+            let files = [("defconfig.toml", defconfig), (".config.toml", config)];
+            let (active, _, _) = load_active_xconfigs(&project(name, &files)).unwrap();
+            assert_eq!(active, ["smp"], "{name}");
+        }
+    }
+
+    #[test]
+    fn each_emit_mode_leaves_out_the_other_path() {
+        use crate::types::XConfigDef;
+
+        let emitting = |emit: &str| XConfigDef {
+            emit: emit.to_string(),
+            ..bool_key(&[])
+        };
+        let net = XConfigDef {
+            aliases: strings(&["CONFIG_NET"]),
+            ..emitting("feature")
+        };
+        let usb = XConfigDef {
+            tristate: true,
+            ..emitting("feature")
+        };
+        let defs = HashMap::from([
+            ("smp".to_string(), emitting("cfg")),
+            ("net".to_string(), net),
+            ("usb".to_string(), usb),
+            ("log".to_string(), emitting("both")),
+        ]);
+        let feature_only = not_emitting(&defs, XConfigDef::emits_cfg);
+        assert_eq!(feature_only, ["CONFIG_NET", "USB_MODULE", "net", "usb"]);
+        assert_eq!(not_emitting(&defs, XConfigDef::emits_features), ["smp"]);
+    }
+
+    #[test]
+    fn nested_tables_flatten_to_prefixed_keys() {
+        use toml::Value::{Boolean, Integer, Table};
+        let values = |entries: Vec<(&str, toml::Value)>| -> HashMap<String, toml::Value> {
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect()
+        };
+        let table = |entries| Table(values(entries).into_iter().collect());
+        let ip = table(vec![("dhcp", Boolean(false))]);
+        let net = table(vec![
+            ("enabled", Boolean(true)),
+            ("mtu", Integer(1500)),
+            ("ip", ip),
+        ]);
+        let xconfig = values(vec![("net", net), ("smp", Boolean(true))]);
+        let flat = flatten_xconfig(xconfig, ".config.toml").unwrap();
+        let expected = values(vec![
+            ("net_enabled", Boolean(true)),
+            ("net_mtu", Integer(1500)),
+            ("net_ip_dhcp", Boolean(false)),
+            ("smp", Boolean(true)),
+        ]);
+        assert_eq!(flat, expected);
+
+        let net = table(vec![("mtu", Integer(1500))]);
+        let twice = values(vec![("net", net), ("net_mtu", Integer(9000))]);
+        let err = flatten_xconfig(twice, "c.toml").map_err(|e| e.to_string());
+        let expected = "c.toml: [\"net_mtu\"] set more than once, directly or in nested tables";
+        assert_eq!(err, Err(expected.to_string()));
+    }
+
+    #[test]
+    fn spec_problems() {
+        let placeholder = "uses `{}`, which only a list key fills in";
+        let cases = [
+            ("smp", false, None),
+            ("crate_b/smp", false, None),
+            ("crate_b@0.2/smp", false, None),
+            ("crate_b/{}", true, None),
+            ("", false, Some("is empty")),
+            ("crate_b/", false, Some("has no feature after the `/`")),
+            ("/smp", false, Some("has no crate before the `/`")),
+            ("crate_b//smp", false, Some("has more than one `/`")),
+            ("crate_b/smp/", false, Some("has more than one `/`")),
+            ("crate_b@/smp", false, Some("has no version after the `@`")),
+            ("@0.2/smp", false, Some("has no crate before the `@`")),
+            ("crate_b/{}", false, Some(placeholder)),
+        ];
+        for (spec, is_list, expected) in cases {
+            assert_eq!(spec_problem(spec, is_list), expected, "spec `{spec}`");
+        }
+    }
+
+    #[test]
+    fn default_violating_min_fails_at_load() {
+        // `stack_size` with `min = 10` and the `default` line given
+        let load = |name: &str, default: &str| {
+            let defconfig = format!("[xconfig.stack_size]\ntype = \"int\"\nmin = 10\n{default}");
+            let root = scratch(name, &[("defconfig.toml", &defconfig)]);
+            load_defconfig(&root.join("defconfig.toml")).map_err(|e| e.to_string())
+        };
+        let failed = Err("defconfig.toml validation failed (1 error)".to_string());
+        let below = load("default-below-min", "default = 0\n").map(|_| ());
+        assert_eq!(below, failed);
+        // Omitted, it is generated as 0 too
+        let implied = load("implied-below-min", "").map(|_| ());
+        assert_eq!(implied, failed);
+        let defs = load("default-in-range", "default = 10\n").unwrap();
+        assert_eq!(defs["stack_size"].min, Some(10));
+    }
 }
@@ -0,0 +1,172 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+
+use crate::cargo_args::CargoArgs;
+use crate::cli::Options;
+use crate::config::{self, ConfigPaths};
+use crate::lock;
+
+/// Findings so far, printed as they come in.
+#[derive(Default)]
+struct Report {
+    critical: usize,
+}
+
+impl Report {
+    fn ok(&mut self, what: &str) {
+        println!("[ok]    {what}");
+    }
+
+    /// Something that works but is likely not what the user wants.
+    fn warn(&mut self, what: &str, help: &str) {
+        println!("[warn]  {what}");
+        println!("        help: {help}");
+    }
+
+    /// Something that breaks builds; makes `doctor` fail.
+    fn critical(&mut self, what: &str, help: &str) {
+        self.critical += 1;
+        println!("[error] {what}");
+        println!("        help: {help}");
+    }
+}
+
+/// `doctor`: check the environment and the project for the usual setup
+/// problems, printing a remedy for each. Fails if any of them would break
+/// a build. Nothing is written, `.config.toml` included.
+pub fn doctor(opts: &Options) -> Result<()> {
+    let mut report = Report::default();
+    check_wrapper_env(&mut report);
+    match ConfigPaths::discover(
+        opts.defconfig_file.as_deref(),
+        opts.config_file.as_deref(),
+        true,
+    ) {
+        Ok(mut paths) => {
+            paths.config_env = opts.config_env;
+            check_project(&paths, opts, &mut report)
+        }
+        Err(e) => report.critical(
+            &format!("{e:#}"),
+            "run from inside the project, or pass --defconfig-file",
+        ),
+    }
+
+    match report.critical {
+        0 => Ok(()),
+        1 => bail!("doctor found 1 critical problem"),
+        n => bail!("doctor found {n} critical problems"),
+    }
+}
+
+/// xbuild runs Phase 2 with its own `RUSTC_WRAPPER`; one already in the
+/// environment is chained after it rather than replaced.
+fn check_wrapper_env(report: &mut Report) {
+    let own = std::env::current_exe().unwrap_or_default();
+    match crate::inner_wrapper(&own) {
+        Some(inner) => report.ok(&format!(
+            "existing RUSTC_WRAPPER `{inner}` is run by xbuild's wrapper"
+        )),
+        None => report.ok("no other RUSTC_WRAPPER in the environment"),
+    }
+}
+
+fn check_project(paths: &ConfigPaths, opts: &Options, report: &mut Report) {
+    report.ok(&format!("found {}", paths.defconfig.display()));
+    if let Err(e) = config::load_defconfig(&paths.defconfig) {
+        report.critical(&format!("{e:#}"), "fix defconfig.toml as described above");
+        return;
+    }
+    report.ok("defconfig.toml is valid");
+
+    let label = paths.config_label();
+    if !paths.config.exists() {
+        report.warn(
+            &format!("{label} does not exist"),
+            "the next `cargo xbuild` generates it from the defconfig.toml defaults",
+        );
+        return;
+    }
+    if let Err(e) = config::load_active_xconfigs(paths) {
+        report.critical(
+            &format!("{e:#}"),
+            &format!("fix {label}, or `cargo xbuild unset <KEY>` to reset a key"),
+        );
+        return;
+    }
+    report.ok(&format!("{label} is valid"));
+
+    // Metadata keys, mapped features and extern resolution, as a build
+    // would check them
+    let opts = Options {
+        locked: false,
+        no_summary: true,
+        ..opts.clone()
+    };
+    let plan = match crate::plan(paths, &opts, &CargoArgs::default()) {
+        Ok(plan) => plan,
+        Err(e) => {
+            report.critical(
+                &format!("{e:#}"),
+                "make [package.metadata.xconfig] agree with defconfig.toml \
+                 (--allow-unknown turns unknown keys into warnings)",
+            );
+            return;
+        }
+    };
+    report.ok("crate metadata agrees with defconfig.toml");
+
+    if plan.settings.sync_cargo_config {
+        check_cargo_config(&paths.root, &plan.cfg_keys(&plan.active), report);
+    }
+    if paths.root.join(lock::LOCK_FILE).exists() {
+        match lock::verify_lock(&paths.root, &plan.lock(&paths.root)) {
+            Ok(()) => report.ok(&format!("{} is up to date", lock::LOCK_FILE)),
+            Err(e) => report.warn(
+                &format!("{e:#}"),
+                "run `cargo xbuild` to update it, or `--locked` builds will fail",
+            ),
+        }
+    }
+}
+
+/// `.cargo/config.toml` is what rust-analyzer reads the cfgs from, so a
+/// stale one shows code as enabled or disabled wrongly.
+fn check_cargo_config(root: &std::path::Path, active: &[String], report: &mut Report) {
+    let path = root.join(".cargo").join("config.toml");
+    let Ok(existing) = std::fs::read_to_string(&path) else {
+        report.warn(
+            ".cargo/config.toml does not exist yet",
+            "run `cargo xbuild sync` so rust-analyzer sees the xconfig cfgs",
+        );
+        return;
+    };
+    if config::cargo_config_hand_edited(root, &existing) {
+        report.warn(
+            ".cargo/config.toml was edited since cargo-xbuild last wrote it",
+            "the next sync overwrites the edits; move them elsewhere, then \
+             `cargo xbuild --force sync`",
+        );
+    }
+
+    let written: BTreeSet<String> = toml::from_str::<toml::Table>(&existing)
+        .ok()
+        .as_ref()
+        .and_then(|t| t.get("build")?.get("rustflags")?.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|flag| flag.as_str()?.strip_prefix("--cfg="))
+        .map(str::to_string)
+        .collect();
+    let expected: BTreeSet<String> = active.iter().map(|k| config::cfg_name(k)).collect();
+    if written == expected {
+        report.ok(".cargo/config.toml has the active cfgs");
+        return;
+    }
+    let missing: Vec<&String> = expected.difference(&written).collect();
+    let stale: Vec<&String> = written.difference(&expected).collect();
+    report.warn(
+        &format!(".cargo/config.toml is stale (missing {missing:?}, extra {stale:?})"),
+        "run `cargo xbuild sync`",
+    );
+}
@@ -0,0 +1,219 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::collections::HashMap;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::config::{self, ConfigPaths};
+use crate::types::XConfigDef;
+
+/// A one-key change to `.config.toml`.
+pub enum ConfigEdit {
+    /// `toggle <KEY>`: flip a bool key
+    Toggle(String),
+    /// `set <KEY>=<VALUE>`: VALUE is a TOML literal (`8192`, `true`,
+    /// `["uart"]`); string keys also take it unquoted
+    Set(String, String),
+    /// `unset <KEY>`: back to the `defconfig.toml` default. A computed
+    /// default is removed from the file so it follows its inputs again.
+    Unset(String),
+}
+
+/// Apply `edit` to `.config.toml` in place. Comments, key order and the
+/// formatting of everything else are kept, and the result is validated
+/// like `.config.toml` itself before it is written.
+pub fn edit_config(paths: &ConfigPaths, edit: &ConfigEdit) -> Result<()> {
+    let defs = config::load_defconfig(&paths.defconfig)?;
+    let label = paths.config_label();
+    let content = std::fs::read_to_string(&paths.config)
+        .with_context(|| format!("read {}", paths.config.display()))?;
+    let mut doc = content
+        .parse::<DocumentMut>()
+        .with_context(|| format!("parse {label}"))?;
+    let Some(table) = doc.get_mut("xconfig").and_then(Item::as_table_like_mut) else {
+        bail!("{label} has no [xconfig] table");
+    };
+
+    let (key, new) = match edit {
+        ConfigEdit::Toggle(name) => {
+            let (key, def) = find_key(&defs, name)?;
+            if def.typ != "bool" {
+                bail!(
+                    "`{key}` is of type `{}`, not bool; use `cargo xbuild set {key}=<value>`",
+                    def.typ
+                );
+            }
+            // A key left out (computed default, or set by an include) toggles
+            // from its current value. A tristate key is on when built in or a
+            // module, and keeps its spelling: `"y"`/`"m"` turns into `"n"`.
+            let given = config::merged_config(&content, &paths.config, &label)?;
+            let (values, _) = config::resolve_values(&defs, &given);
+            let current = values.get(key).cloned();
+            let current = current.unwrap_or_else(|| def.default_value());
+            let on = def.is_module(&current) || def.built_in(&current).as_bool() == Some(true);
+            let new = match current {
+                toml::Value::String(_) if def.tristate => {
+                    toml::Value::String(if on { "n" } else { "y" }.to_string())
+                }
+                _ => toml::Value::Boolean(!on),
+            };
+            (key, Some(new))
+        }
+        ConfigEdit::Set(name, raw) => {
+            let (key, def) = find_key(&defs, name)?;
+            (key, Some(parse_value(key, def, raw)?))
+        }
+        ConfigEdit::Unset(name) => {
+            let (key, def) = find_key(&defs, name)?;
+            match def.default_expr() {
+                Some(_) => (key, None),
+                None => (key, Some(def.default_value())),
+            }
+        }
+    };
+
+    // A key grouped in a nested table (`[xconfig.net] mtu` for `net_mtu`)
+    // is edited there, as is a new one whose group exists
+    let mut table = table;
+    let mut name = key.clone();
+    while !table.contains_key(&name) {
+        let Some(group) = group_of(&*table, &name) else {
+            break;
+        };
+        name = name[group.len() + 1..].to_string();
+        let Some(nested) = table.get_mut(&group).and_then(Item::as_table_like_mut) else {
+            unreachable!("group_of returns tables only");
+        };
+        table = nested;
+    }
+
+    match &new {
+        Some(val) => {
+            let val = val
+                .to_string()
+                .parse::<toml_edit::Value>()
+                .context("encode value")?;
+            match table.get_mut(&name).and_then(Item::as_value_mut) {
+                // Keep the trailing comment, if any
+                Some(old) => {
+                    let decor = old.decor().clone();
+                    *old = val;
+                    *old.decor_mut() = decor;
+                }
+                None => {
+                    table.insert(&name, Item::Value(val));
+                }
+            }
+        }
+        None => {
+            table.remove(&name);
+        }
+    }
+
+    let updated = doc.to_string();
+    if updated == content {
+        info!("{label} already has that value for `{key}`");
+        return Ok(());
+    }
+    config::validate_config_content(paths, &updated, &label)?;
+    std::fs::write(&paths.config, &updated)
+        .with_context(|| format!("write {}", paths.config.display()))?;
+    match new {
+        Some(val) => info!("{label}: {key} = {val}"),
+        None => info!("{label}: {key} now follows its computed default"),
+    }
+    Ok(())
+}
+
+/// The defconfig key `name` refers to, by its own name or by its cfg
+/// spelling (`SMP` for `smp`).
+fn find_key<'a>(
+    defs: &'a HashMap<String, XConfigDef>,
+    name: &str,
+) -> Result<(&'a String, &'a XConfigDef)> {
+    defs.get_key_value(name)
+        .or_else(|| defs.iter().find(|(k, _)| config::cfg_name(k) == name))
+        .with_context(|| format!("unknown xconfig key `{name}` (not defined in defconfig.toml)"))
+}
+
+/// The nested table of `table` that `key` belongs in: the longest one
+/// whose name and `_` start the key, case-sensitively, as
+/// `config::flatten_xconfig` joins them.
+fn group_of(table: &dyn TableLike, key: &str) -> Option<String> {
+    table
+        .iter()
+        .filter(|(_, item)| item.is_table_like())
+        .map(|(group, _)| group)
+        .filter(|group| key.len() > group.len() + 1)
+        .filter(|group| key.starts_with(group) && key[group.len()..].starts_with('_'))
+        .max_by_key(|group| group.len())
+        .map(str::to_string)
+}
+
+/// `set`'s VALUE as a TOML value. A string key takes anything that isn't
+/// a quoted TOML string verbatim, so `set platform=qemu` works.
+fn parse_value(key: &str, def: &XConfigDef, raw: &str) -> Result<toml::Value> {
+    let parsed = toml::from_str::<toml::Table>(&format!("v = {raw}"))
+        .ok()
+        .and_then(|mut t| t.remove("v"));
+    match parsed {
+        Some(toml::Value::String(s)) => Ok(toml::Value::String(s)),
+        _ if def.typ == "string" => Ok(toml::Value::String(raw.to_string())),
+        None if def.tristate && matches!(raw, "y" | "m" | "n") => {
+            Ok(toml::Value::String(raw.to_string()))
+        }
+        Some(val) => Ok(val),
+        None => bail!("`{raw}` is not a valid `{}` for `{key}`", def.typ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch;
+
+    const DEFCONFIG: &str = r#"
+[xconfig.smp]
+default = false
+
+[xconfig.stack_size]
+type = "int"
+default = 8192
+min = 4096
+"#;
+
+    const CONFIG: &str = "[xconfig]\nsmp = false # keep\nstack_size = 8192\n";
+
+    /// A project `name` with `DEFCONFIG` and `CONFIG`.
+    fn project(name: &str) -> ConfigPaths {
+        let root = scratch(name, &[("defconfig.toml", DEFCONFIG), (".config.toml", CONFIG)]);
+        ConfigPaths {
+            defconfig: root.join("defconfig.toml"),
+            config: root.join(".config.toml"),
+            workspace: root.clone(),
+            root,
+            config_env: false,
+        }
+    }
+
+    #[test]
+    fn toggle_flips_a_bool_in_place() {
+        let paths = project("toggle-bool");
+        edit_config(&paths, &ConfigEdit::Toggle("SMP".to_string())).unwrap();
+        let updated = std::fs::read_to_string(&paths.config).unwrap();
+        assert_eq!(updated, "[xconfig]\nsmp = true # keep\nstack_size = 8192\n");
+    }
+
+    #[test]
+    fn bad_values_are_rejected_and_the_file_kept() {
+        let paths = project("reject-bad-values");
+        let edit = |edit: ConfigEdit| edit_config(&paths, &edit).map_err(|e| e.to_string());
+        let set = |raw: &str| edit(ConfigEdit::Set("stack_size".to_string(), raw.to_string()));
+        let not_an_int = "`big` is not a valid `int` for `stack_size`".to_string();
+        assert_eq!(set("big"), Err(not_an_int));
+        assert!(set("1024").is_err(), "below min");
+        let toggled = edit(ConfigEdit::Toggle("stack_size".to_string())).unwrap_err();
+        let guidance = "use `cargo xbuild set stack_size=<value>`";
+        assert!(toggled.contains(guidance), "{toggled}");
+        assert_eq!(std::fs::read_to_string(&paths.config).unwrap(), CONFIG);
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::cargo_args::CargoArgs;
+use crate::config::{self, ConfigPaths};
+use crate::expr::{parse_cond, parse_value};
+use crate::message::{Message, MessageFormat};
+use crate::resolve;
+use crate::types::XConfigDef;
+
+/// Everything known about one key, as `explain` prints it.
+#[derive(Serialize)]
+pub struct Explanation {
+    pub key: String,
+    /// The cfgs it sets: its own, its aliases, and `<KEY>_MODULE` for a
+    /// tristate key; none with `emit = "feature"`
+    pub cfgs: Vec<String>,
+    pub description: Option<String>,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub group: Option<String>,
+    /// The literal default, or `expr = ".."` for a computed one
+    pub default: String,
+    /// The value after `.config.toml`, its includes and computed defaults
+    pub value: String,
+    pub active: bool,
+    pub conflicts_with: Vec<String>,
+    pub required_if: Option<String>,
+    /// Computed defaults, `required_if`s and `[virtual]` cfgs testing it
+    pub used_by: Vec<String>,
+    /// Why the key is mapped, as given by `{ target, why }` specs:
+    /// declaring crate → spec → reason
+    pub why: BTreeMap<String, BTreeMap<String, String>>,
+    /// crate → features it enables, whether or not it is active now
+    pub features: BTreeMap<String, Vec<String>>,
+    /// crate → optional deps those features pull in via `--extern`
+    pub externs: BTreeMap<String, Vec<String>>,
+}
+
+/// `explain <KEY>`: the key's definition in `defconfig.toml`, its value in
+/// `.config.toml`, the keys and cfgs that depend on it, and what turning
+/// it on injects, as text or (`json`) one JSON object.
+///
+/// The injection is worked out as if the key were the only active one,
+/// so an inactive key shows what enabling it would do.
+pub fn explain(paths: &ConfigPaths, name: &str, json: bool) -> Result<()> {
+    let def = config::read_defconfig(&paths.defconfig)?;
+    let defs = def.xconfig.clone().unwrap_or_default();
+    let Some((key, xdef)) = defs
+        .get_key_value(name)
+        .or_else(|| defs.iter().find(|(k, _)| config::cfg_name(k) == name))
+    else {
+        match suggest(name, defs.keys()) {
+            Some(close) => bail!("unknown xconfig key `{name}`; did you mean `{close}`?"),
+            None => bail!("unknown xconfig key `{name}` (not defined in defconfig.toml)"),
+        }
+    };
+
+    config::ensure_config_toml(paths)?;
+    let content = std::fs::read_to_string(&paths.config)
+        .with_context(|| format!("read {}", paths.config.display()))?;
+    let given = config::merged_config(&content, &paths.config, &paths.config_label())?;
+    let (values, _) = config::resolve_values(&defs, &given);
+    let value = values.get(key).cloned();
+    let (active, all_keys, lists) = config::load_active_xconfigs(paths)?;
+
+    let mut cfgs = vec![config::cfg_name(key)];
+    cfgs.extend(xdef.aliases.iter().cloned());
+    if xdef.tristate {
+        cfgs.push(config::module_name(key));
+    }
+    if !xdef.emits_cfg() {
+        cfgs.clear();
+    }
+
+    let mapped: &[String] = match xdef.emits_features() {
+        true => std::slice::from_ref(key),
+        false => &[],
+    };
+    let scan =
+        config::collect_all_metadata(&paths.workspace, mapped, &all_keys, &lists, true, true)?;
+    let why = scan
+        .mappings
+        .iter()
+        .filter_map(|m| Some((m.krate.clone(), m.keys.get(key)?.why.clone())))
+        .filter(|(_, reasons)| !reasons.is_empty())
+        .collect();
+    let resolved =
+        resolve::resolve_extern_map(&paths.workspace, &scan.feature_map, &CargoArgs::default())?;
+
+    let explanation = Explanation {
+        key: key.clone(),
+        cfgs,
+        description: xdef.description.clone(),
+        typ: xdef.typ.clone(),
+        group: xdef.group.clone(),
+        default: match xdef.default_expr() {
+            Some(src) => format!("expr = \"{src}\""),
+            None => xdef.default_value().to_string(),
+        },
+        value: value.unwrap_or_else(|| xdef.default_value()).to_string(),
+        active: active.contains(key),
+        conflicts_with: xdef.conflicts_with.clone(),
+        required_if: xdef.required_if.clone(),
+        used_by: used_by(key, &defs, &def.virtuals),
+        why,
+        features: scan.feature_map.into_iter().collect(),
+        externs: resolved
+            .extern_map
+            .into_iter()
+            .map(|(krate, deps)| (krate, deps.into_iter().map(|d| d.crate_name).collect()))
+            .collect(),
+    };
+    if json {
+        return MessageFormat::Json.emit(&Message::Explain(&explanation));
+    }
+    print_explanation(&explanation);
+    Ok(())
+}
+
+fn print_explanation(e: &Explanation) {
+    let state = if e.active { "active" } else { "inactive" };
+    println!("{} ({}, {state})", e.key, e.typ);
+    if let Some(description) = &e.description {
+        println!("  {description}");
+    }
+    match e.cfgs.is_empty() {
+        true => println!("cfgs:           (none; emit = \"feature\")"),
+        false => println!("cfgs:           {}", e.cfgs.join(", ")),
+    }
+    if let Some(group) = &e.group {
+        println!("group:          {group}");
+    }
+    println!("default:        {}", e.default);
+    println!("value:          {}", e.value);
+    if !e.conflicts_with.is_empty() {
+        println!("conflicts with: {}", e.conflicts_with.join(", "));
+    }
+    if let Some(cond) = &e.required_if {
+        println!("required if:    {cond}");
+    }
+    if !e.used_by.is_empty() {
+        println!("used by:        {}", e.used_by.join(", "));
+    }
+    println!("enables:");
+    if e.features.is_empty() {
+        println!("  (no crate features)");
+    }
+    for (krate, feats) in &e.features {
+        println!("  {krate}: {}", feats.join(", "));
+    }
+    if !e.why.is_empty() {
+        println!("because:");
+        for (krate, reasons) in &e.why {
+            for (spec, why) in reasons {
+                println!("  {krate}: {spec}: {why}");
+            }
+        }
+    }
+    if !e.externs.is_empty() {
+        println!("pulls in:");
+        for (krate, deps) in &e.externs {
+            println!("  {krate}: {}", deps.join(", "));
+        }
+    }
+}
+
+/// What tests `key`: other keys' computed defaults and `required_if`s,
+/// and `[virtual]` cfgs, e.g. `virtual LOG_DRIVERS`. Sorted.
+fn used_by(
+    key: &str,
+    defs: &HashMap<String, XConfigDef>,
+    virtuals: &HashMap<String, String>,
+) -> Vec<String> {
+    let cfg = config::cfg_name(key);
+    let names = |keys: Vec<&str>| keys.into_iter().any(|k| k == key || k == cfg);
+    let mut out = Vec::new();
+    for (other, def) in defs {
+        match def.default_expr().map(parse_value) {
+            Some(Ok(expr)) if names(expr.cond_keys()) => out.push(format!("default of {other}")),
+            _ => {}
+        }
+        match def.required_if.as_deref().map(parse_cond) {
+            Some(Ok(cond)) if names(cond.keys()) => out.push(format!("required_if of {other}")),
+            _ => {}
+        }
+    }
+    for (name, src) in virtuals {
+        if parse_cond(src).is_ok_and(|cond| names(cond.keys())) {
+            out.push(format!("virtual {name}"));
+        }
+    }
+    out.sort();
+    out
+}
+
+/// The key closest to the unknown `name`, ignoring case, if it is within
+/// a couple of edits.
+fn suggest<'a>(name: &str, keys: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    let name = name.to_lowercase();
+    keys.map(|k| (edit_distance(&name, &k.to_lowercase()), k))
+        .filter(|&(d, _)| d <= 2)
+        .min()
+        .map(|(_, k)| k)
+}
+
+/// Levenshtein distance between `a` and `b`, by chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != cb);
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
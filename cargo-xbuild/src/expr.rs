@@ -0,0 +1,395 @@
+//! Small expression language for computed xconfig values.
+//!
+//! ```text
+//! value := "if" cond "then" value "else" value
+//!        | INT | "true" | "false" | "\"string\"" | KEY
+//! cond  := cond "||" cond | cond "&&" cond | "!" cond | "(" cond ")" | KEY
+//! ```
+//!
+//! `&&` binds tighter than `||`. A `KEY` in a condition must name a bool
+//! xconfig; as a value it stands for that key's value. A `cond` on its
+//! own defines a `[virtual]` cfg.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    If(Cond, Box<Expr>, Box<Expr>),
+    Lit(toml::Value),
+    Key(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cond {
+    Key(String),
+    Not(Box<Cond>),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Str(String),
+    Not,
+    And,
+    Or,
+    LParen,
+    RParen,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(s) => write!(f, "`{s}`"),
+            Token::Int(n) => write!(f, "`{n}`"),
+            Token::Str(s) => write!(f, "`\"{s}\"`"),
+            Token::Not => f.write_str("`!`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::LParen => f.write_str("`(`"),
+            Token::RParen => f.write_str("`)`"),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '!' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("expected `{c}{c}`"));
+                }
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                s.push(c);
+                chars.next();
+                while let Some(&d) = chars.peek() {
+                    if !(d.is_ascii_digit() || d == '_') {
+                        break;
+                    }
+                    s.push(d);
+                    chars.next();
+                }
+                let n = s
+                    .replace('_', "")
+                    .parse()
+                    .map_err(|_| format!("invalid integer `{s}`"))?;
+                tokens.push(Token::Int(n));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&d) = chars.peek() {
+                    if !(d.is_alphanumeric() || d == '_') {
+                        break;
+                    }
+                    s.push(d);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(s));
+            }
+            c => return Err(format!("unexpected character `{c}`")),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn keyword(&mut self, kw: &str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Ident(s)) if s == kw => Ok(()),
+            Some(t) => Err(format!("expected `{kw}`, found {t}")),
+            None => Err(format!("expected `{kw}`, found end of expression")),
+        }
+    }
+
+    fn value(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Ident(s)) if s == "if" => {
+                let cond = self.cond()?;
+                self.keyword("then")?;
+                let then = self.value()?;
+                self.keyword("else")?;
+                let otherwise = self.value()?;
+                Ok(Expr::If(cond, Box::new(then), Box::new(otherwise)))
+            }
+            Some(Token::Ident(s)) if s == "true" => Ok(Expr::Lit(toml::Value::Boolean(true))),
+            Some(Token::Ident(s)) if s == "false" => Ok(Expr::Lit(toml::Value::Boolean(false))),
+            Some(Token::Ident(s)) => Ok(Expr::Key(s)),
+            Some(Token::Int(n)) => Ok(Expr::Lit(toml::Value::Integer(n))),
+            Some(Token::Str(s)) => Ok(Expr::Lit(toml::Value::String(s))),
+            Some(t) => Err(format!("expected a value, found {t}")),
+            None => Err("expected a value, found end of expression".to_string()),
+        }
+    }
+
+    fn cond(&mut self) -> Result<Cond, String> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            lhs = Cond::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Cond, String> {
+        let mut lhs = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            lhs = Cond::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Cond, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Cond::Not(Box::new(self.unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.cond()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(inner),
+                    Some(t) => Err(format!("expected `)`, found {t}")),
+                    None => Err("expected `)`, found end of expression".to_string()),
+                }
+            }
+            Some(Token::Ident(s)) if !matches!(s.as_str(), "if" | "then" | "else") => {
+                Ok(Cond::Key(s))
+            }
+            Some(t) => Err(format!("expected a key, `!` or `(`, found {t}")),
+            None => Err("expected a key, found end of expression".to_string()),
+        }
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(t) => Err(format!("unexpected {t} after the end of the expression")),
+        }
+    }
+}
+
+/// Parse a value expression (`if smp then 4 else 1`).
+pub fn parse_value(src: &str) -> Result<Expr, String> {
+    let mut p = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = p.value()?;
+    p.finish()?;
+    Ok(expr)
+}
+
+/// Parse a condition on its own (`SMP && !NET`).
+pub fn parse_cond(src: &str) -> Result<Cond, String> {
+    let mut p = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let cond = p.cond()?;
+    p.finish()?;
+    Ok(cond)
+}
+
+impl Expr {
+    /// Keys tested by `if` conditions; each must name a bool xconfig.
+    pub fn cond_keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        self.collect_cond_keys(&mut keys);
+        keys
+    }
+
+    fn collect_cond_keys<'a>(&'a self, out: &mut Vec<&'a str>) {
+        if let Expr::If(cond, then, otherwise) = self {
+            cond.collect_keys(out);
+            then.collect_cond_keys(out);
+            otherwise.collect_cond_keys(out);
+        }
+    }
+
+    /// Evaluate, looking keys up through `lookup`.
+    pub fn eval(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> Result<toml::Value, String>,
+    ) -> Result<toml::Value, String> {
+        match self {
+            Expr::If(cond, then, otherwise) => {
+                if cond.eval(lookup)? {
+                    then.eval(lookup)
+                } else {
+                    otherwise.eval(lookup)
+                }
+            }
+            Expr::Lit(v) => Ok(v.clone()),
+            Expr::Key(k) => lookup(k),
+        }
+    }
+}
+
+impl Cond {
+    /// Every key the condition tests.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        self.collect_keys(&mut keys);
+        keys
+    }
+
+    fn collect_keys<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Cond::Key(k) => out.push(k),
+            Cond::Not(c) => c.collect_keys(out),
+            Cond::And(a, b) | Cond::Or(a, b) => {
+                a.collect_keys(out);
+                b.collect_keys(out);
+            }
+        }
+    }
+
+    /// Evaluate, looking keys up through `lookup`; every key must be bool.
+    pub fn eval(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> Result<toml::Value, String>,
+    ) -> Result<bool, String> {
+        match self {
+            Cond::Key(k) => match lookup(k)? {
+                toml::Value::Boolean(b) => Ok(b),
+                other => Err(format!(
+                    "`{k}` is used as a condition but is `{other}`, not a bool"
+                )),
+            },
+            Cond::Not(c) => Ok(!c.eval(lookup)?),
+            Cond::And(a, b) => Ok(a.eval(lookup)? && b.eval(lookup)?),
+            Cond::Or(a, b) => Ok(a.eval(lookup)? || b.eval(lookup)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(k: &str) -> Box<Cond> {
+        Box::new(Cond::Key(k.to_string()))
+    }
+
+    /// `src` evaluated with the keys in `on` true and every other false.
+    fn holds(src: &str, on: &[&str]) -> bool {
+        let mut lookup = |k: &str| Ok(toml::Value::Boolean(on.contains(&k)));
+        parse_cond(src).unwrap().eval(&mut lookup).unwrap()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expected = Cond::Or(key("A"), Box::new(Cond::And(key("B"), key("C"))));
+        assert_eq!(parse_cond("A || B && C"), Ok(expected));
+        let expected = Cond::Or(Box::new(Cond::And(key("A"), key("B"))), key("C"));
+        assert_eq!(parse_cond("A && B || C"), Ok(expected));
+    }
+
+    #[test]
+    fn not_binds_tightest_and_parentheses_group() {
+        let expected = Cond::And(Box::new(Cond::Not(key("A"))), key("B"));
+        assert_eq!(parse_cond("!A && B"), Ok(expected));
+        let expected = Cond::Not(Box::new(Cond::And(key("A"), key("B"))));
+        assert_eq!(parse_cond("!(A && B)"), Ok(expected));
+    }
+
+    #[test]
+    fn conditions_evaluate_over_the_active_keys() {
+        assert!(holds("SMP && NET", &["SMP", "NET"]));
+        assert!(!holds("SMP && NET", &["SMP"]));
+        assert!(holds("SMP || NET && DHCP", &["SMP"]));
+        assert!(!holds("(SMP || NET) && DHCP", &["SMP"]));
+        assert!(holds("NET && !DHCP", &["NET"]));
+        assert!(!holds("!!NET", &[]));
+    }
+
+    #[test]
+    fn values() {
+        let mut lookup = |k: &str| match k {
+            "smp" => Ok(toml::Value::Boolean(true)),
+            "cpus" => Ok(toml::Value::Integer(8)),
+            _ => Err(format!("unknown key `{k}`")),
+        };
+        let mut eval = |src: &str| parse_value(src).unwrap().eval(&mut lookup);
+        assert_eq!(eval("if smp then 4 else 1"), Ok(toml::Value::Integer(4)));
+        assert_eq!(eval("if !smp then \"up\" else \"smp\""), Ok("smp".into()));
+        assert_eq!(eval("if smp then cpus else 1"), Ok(toml::Value::Integer(8)));
+        assert_eq!(eval("-1_000"), Ok(toml::Value::Integer(-1000)));
+        assert_eq!(eval("false"), Ok(toml::Value::Boolean(false)));
+        let not_bool = "`cpus` is used as a condition but is `8`, not a bool";
+        assert_eq!(eval("if cpus then 1 else 0"), Err(not_bool.to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("A & B", "expected `&&`"),
+            ("A ||", "expected a key, found end of expression"),
+            ("(A || B", "expected `)`, found end of expression"),
+            ("A B", "unexpected `B` after the end of the expression"),
+            ("A && then", "expected a key, `!` or `(`, found `then`"),
+            ("A + B", "unexpected character `+`"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(parse_cond(src).unwrap_err(), expected, "`{src}`");
+        }
+        let cases = [
+            ("if A then 1", "expected `else`, found end of expression"),
+            ("if A 1 else 2", "expected `then`, found `1`"),
+            ("\"open", "unterminated string"),
+            ("-", "invalid integer `-`"),
+            ("", "expected a value, found end of expression"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(parse_value(src).unwrap_err(), expected, "`{src}`");
+        }
+    }
+}
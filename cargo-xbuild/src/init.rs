@@ -0,0 +1,87 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::{self, ConfigPaths};
+
+/// Starter `defconfig.toml`: one key per supported type.
+const STARTER_DEFCONFIG: &str = r#"# xconfig definitions — describes all available configuration switches.
+# cargo-xbuild uses this to auto-generate .config.toml when it doesn't exist.
+#
+# Map a key to crate features in each crate's Cargo.toml, e.g.
+#
+#   [package.metadata.xconfig]
+#   log = ["log", "other_crate/log"]
+#
+# Every key is also available as a cfg named after it in upper case:
+# bool keys set to true emit `--cfg LOG`.
+#
+# Combinations can get a cfg of their own, set while the expression
+# holds (`&&`, `||`, `!` and parentheses):
+#
+#   [virtual]
+#   LOG_DRIVERS = "LOG && DRIVERS"
+
+[xconfig.log]
+description = "Enable logging"
+type = "bool"
+default = false
+
+[xconfig.stack_size]
+description = "Stack size in bytes"
+type = "int"
+default = 65536
+min = 4096
+
+[xconfig.platform]
+description = "Name of the target platform"
+type = "string"
+default = "qemu"
+
+# A list enables one feature per element: map it with a `{}` spec,
+# e.g. `drivers = "drivers/{}"`.
+[xconfig.drivers]
+description = "Extra drivers to build in"
+type = "list"
+default = []
+"#;
+
+/// `init`: scaffold `defconfig.toml`, generate `.config.toml` from it and
+/// write the IDE files. Refuses to overwrite an existing `defconfig.toml`.
+pub fn init(paths: &ConfigPaths) -> Result<()> {
+    if paths.defconfig.exists() {
+        bail!(
+            "{} already exists; not overwriting it",
+            paths.defconfig.display()
+        );
+    }
+    std::fs::write(&paths.defconfig, STARTER_DEFCONFIG)
+        .with_context(|| format!("write {}", paths.defconfig.display()))?;
+    info!("created {}", paths.defconfig.display());
+
+    config::ensure_config_toml(paths)?;
+
+    // No metadata has been written yet, so there is nothing to resolve or
+    // build: the IDE files only carry the cfgs.
+    let (active, all_keys, _) = config::load_active_xconfigs(paths)?;
+    config::sync_cargo_config(
+        &paths.root,
+        &active,
+        &all_keys,
+        &HashMap::new(),
+        &BTreeSet::new(),
+        false,
+        false,
+    )?;
+    config::sync_vscode_settings(&paths.root, &active, &HashMap::new(), &[], false)?;
+
+    println!("Initialized xconfig in {}", paths.root.display());
+    println!();
+    println!("Next steps:");
+    println!("  1. Edit defconfig.toml to declare your configuration switches.");
+    println!("  2. Map them to crate features under [package.metadata.xconfig]");
+    println!("     in each crate's Cargo.toml.");
+    println!("  3. Turn switches on in {}.", paths.config_label());
+    println!("  4. Run `cargo xbuild` (or `cargo xbuild check` to preview).");
+    Ok(())
+}
@@ -0,0 +1,1372 @@
+//! cargo-xbuild: Kconfig-style configuration for cargo workspaces.
+//!
+//! The `cargo-xbuild` binary is a thin wrapper over [`run`]. Tools that
+//! only need the resolution (build scripts, editor plugins) can call
+//! [`plan`] instead, which reads the config and metadata and returns the
+//! [`BuildPlan`] without compiling anything.
+
+pub mod cargo_args;
+pub mod cli;
+pub mod config;
+mod doctor;
+mod edit;
+mod explain;
+mod expr;
+mod init;
+mod lock;
+mod logging;
+mod message;
+mod preset;
+pub mod resolve;
+mod schema;
+#[cfg(test)]
+mod test_util;
+mod timings;
+pub mod types;
+mod watch;
+mod wrapper;
+mod xdeps;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::process::Command;
+
+use cargo_args::CargoArgs;
+use cli::{Options, Subcommand};
+use config::ConfigPaths;
+use message::{Message, MessageFormat};
+use types::{ExternDep, XBuildSettings};
+use xdeps::XDeps;
+
+// ── Orchestrator ─────────────────────────────────────────────────────
+
+/// The top-level binary package: built by a bare `cargo xbuild` and
+/// selected for `run`/`test` when no package is given.
+const DEFAULT_PACKAGE: &str = "entry";
+
+/// Everything decided before anything is compiled: the active xconfigs
+/// and what they inject into which crates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildPlan {
+    pub active: Vec<String>,
+    pub all_keys: Vec<String>,
+    /// crate → features to enable
+    pub feature_map: HashMap<String, Vec<String>>,
+    /// crate → features to keep off (`-crate/feature` specs), checked
+    /// against cargo's own feature resolution (`resolve::disabled_problems`)
+    pub disabled_map: HashMap<String, Vec<String>>,
+    /// crate → optional deps to inject via `--extern`
+    pub extern_map: HashMap<String, Vec<ExternDep>>,
+    /// crate → version to pin injection to (only for ambiguous names)
+    pub versions: HashMap<String, String>,
+    /// crate → every feature the build enables in it: the injected ones
+    /// plus those passed with `--features`. Crate names use `_`; both
+    /// levels are sorted and deduplicated.
+    pub package_features: BTreeMap<String, Vec<String>>,
+    /// `--all-features` was passed, so the selected packages get every
+    /// feature on top of `package_features`
+    pub all_features: bool,
+    /// Without `-p`, the packages a bare `--features` applies to
+    /// (`resolve::default_packages`)
+    pub default_packages: Vec<String>,
+    /// With `-p`, the crates the build injects into: the selected packages
+    /// and their dependencies, names with `_`. The maps above still cover
+    /// the whole workspace, so the lock and the IDE files never depend on
+    /// which packages a build selected; `wrapper_env` applies this.
+    pub scope: Option<BTreeSet<String>>,
+    /// Every crate injected into is a workspace member (see `wrapper_env`)
+    pub members_only: bool,
+    /// Active cfgs of `host = false` keys, kept out of proc-macros and
+    /// build scripts by the wrapper. Sorted.
+    pub target_only: Vec<String>,
+    /// Keys, aliases and module cfgs of `emit = "feature"` keys, which
+    /// set no cfg and are not declared to `--check-cfg`. Sorted.
+    pub feature_only: Vec<String>,
+    /// `[xbuild]` settings from `defconfig.toml`
+    pub settings: XBuildSettings,
+}
+
+/// Steps 1–3: read and validate the config, collect metadata and resolve
+/// externs. Nothing is compiled here. `cargo` is the cargo command being
+/// run, if any (it affects how externs are resolved); library callers
+/// without one pass `CargoArgs::default()`, and `Options::default()`.
+pub fn plan(paths: &ConfigPaths, opts: &Options, cargo: &CargoArgs) -> Result<BuildPlan> {
+    let root = &paths.root;
+    let format = opts.message_format;
+
+    // 1. Ensure .config.toml exists (generate from defconfig.toml if missing)
+    config::ensure_config_toml(paths).context(Failure::Config)?;
+    if opts.update {
+        config::update_config_toml(paths).context(Failure::Config)?;
+    }
+
+    // 2. Read .config.toml
+    let loaded = timings::time("config", || config::load_active_xconfigs(paths));
+    let (active, all_keys, lists) = loaded.context(Failure::Config)?;
+    info!("active xconfigs: {active:?}");
+    format.emit(&Message::XconfigActive { active: &active })?;
+    let defs = config::load_defconfig(&paths.defconfig).context(Failure::Config)?;
+    // emit = "cfg" keys are left out of the scan, so they map to nothing
+    let cfg_only = config::not_emitting(&defs, types::XConfigDef::emits_features);
+    let mapped: Vec<String> = active
+        .iter()
+        .filter(|k| !cfg_only.contains(k))
+        .cloned()
+        .collect();
+
+    // 2. Collect [package.metadata.xconfig] → feature_map
+    let scan = timings::time("manifest scan", || {
+        config::collect_all_metadata(
+            &paths.workspace,
+            &mapped,
+            &all_keys,
+            &lists,
+            opts.allow_unknown,
+            opts.keep_going,
+        )
+    })
+    .context(Failure::Metadata)?;
+    if opts.dump_metadata_map {
+        dump_metadata_map(&scan.mappings);
+        format.emit(&Message::MetadataMap {
+            crates: &scan.mappings,
+        })?;
+    }
+    for mapping in &scan.mappings {
+        for key in mapping.keys.keys().filter(|k| cfg_only.contains(k)) {
+            warn!(
+                "{}: xconfig `{key}` has emit = \"cfg\"; its features are not enabled",
+                mapping.krate
+            );
+        }
+    }
+    let mut noop: Vec<&str> = mapped
+        .iter()
+        .filter(|k| !scan.used_keys.contains(*k))
+        .map(|k| k.as_str())
+        .collect();
+    if !noop.is_empty() {
+        noop.sort();
+        let msg = format!(
+            "active xconfig(s) {noop:?} map to no crate features in any \
+             [package.metadata.xconfig] (only the --cfg is emitted)"
+        );
+        if opts.strict {
+            bail!("{msg}");
+        }
+        warn!("{msg}");
+    }
+    let config::MetadataScan {
+        feature_map,
+        feature_keys,
+        disabled,
+        ..
+    } = scan;
+    let mut disabled_map: HashMap<String, Vec<String>> = HashMap::new();
+    for (krate, feat) in disabled.into_keys() {
+        disabled_map.entry(krate).or_default().push(feat);
+    }
+    // -p: leave crates outside the selection's dependency graph alone
+    let scope = timings::time("package closure", || {
+        resolve::package_closure(&paths.workspace, cargo)
+    })
+    .context(Failure::Metadata)?
+    .map(|closure| closure.into_iter().collect::<BTreeSet<String>>());
+    if let Some(scope) = &scope {
+        debug!("feature injection limited to the -p closure {scope:?}");
+    }
+    debug!("feature injection: {feature_map:?}");
+    let default_packages = timings::time("default packages", || {
+        resolve::default_packages(&paths.workspace, cargo)
+    })
+    .context(Failure::Metadata)?;
+    let explicit = cargo.features_by_crate(&default_packages);
+    // Cargo decides which features are on; a kept-off one it enables
+    // anyway is refused rather than half removed
+    let disabled_map = unless_explicit(&disabled_map, &explicit);
+    let problems = timings::time("disabled feature check", || {
+        resolve::disabled_problems(&paths.workspace, &disabled_map, cargo)
+    })
+    .context(Failure::Metadata)?;
+    config::report_errors(&problems, "disabled feature check").context(Failure::Config)?;
+    if !disabled_map.is_empty() {
+        info!("features kept off: {disabled_map:?}");
+    }
+    format.emit(&Message::feature_map(&feature_map))?;
+    if !opts.no_summary {
+        let injected = within_scope(&feature_map, scope.as_ref());
+        for line in feature_summary(&injected, &feature_keys) {
+            info!("{line}");
+        }
+    }
+
+    // 3. Auto-resolve extern injection for all features
+    let resolve::Resolved {
+        extern_map,
+        versions,
+        members_only,
+    } = timings::time("extern resolution", || {
+        resolve::resolve_extern_map(&paths.workspace, &feature_map, cargo)
+    })
+    .context(Failure::Metadata)?;
+
+    debug!("extern injection (auto-resolved): {extern_map:?}");
+    format.emit(&Message::extern_map(&extern_map))?;
+    if opts.explain_externs {
+        explain_externs(&extern_map, &feature_keys);
+    }
+
+    let plan = BuildPlan {
+        target_only: target_only_cfgs(&defs, &active),
+        feature_only: config::not_emitting(&defs, types::XConfigDef::emits_cfg),
+        active,
+        all_keys,
+        package_features: package_features(&within_scope(&feature_map, scope.as_ref()), &explicit),
+        all_features: cargo.all_features,
+        default_packages,
+        scope,
+        feature_map,
+        disabled_map,
+        extern_map,
+        versions,
+        members_only,
+        settings: config::load_xbuild_settings(&paths.defconfig).context(Failure::Config)?,
+    };
+    if opts.locked {
+        lock::verify_lock(root, &plan.lock(root)).context(Failure::Config)?;
+    }
+    if opts.emit_cfgs {
+        let path = write_cfgs_file(root, &plan)?;
+        info!("cfgs written to {}", path.display());
+    }
+    Ok(plan)
+}
+
+/// The entries of `map` for crates within `scope` (`BuildPlan::scope`);
+/// all of them without one.
+fn within_scope<V: Clone>(
+    map: &HashMap<String, V>,
+    scope: Option<&BTreeSet<String>>,
+) -> HashMap<String, V> {
+    let in_scope = |krate: &str| {
+        let name = resolve::split_version(krate).0.replace('-', "_");
+        scope.is_none_or(|scope| scope.contains(&name))
+    };
+    map.iter()
+        .filter(|(krate, _)| in_scope(krate))
+        .map(|(krate, v)| (krate.clone(), v.clone()))
+        .collect()
+}
+
+/// `BuildPlan::package_features`: `feature_map` merged with the explicit
+/// `--features` of the cargo command.
+fn package_features(
+    feature_map: &HashMap<String, Vec<String>>,
+    explicit: &[(String, String)],
+) -> BTreeMap<String, Vec<String>> {
+    let mut merged: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let injected = feature_map
+        .iter()
+        .flat_map(|(krate, feats)| feats.iter().map(|feat| (krate.clone(), feat.clone())));
+    for (krate, feat) in injected.chain(explicit.iter().cloned()) {
+        let krate = resolve::split_version(&krate).0.replace('-', "_");
+        merged.entry(krate).or_default().insert(feat);
+    }
+    merged
+        .into_iter()
+        .map(|(krate, feats)| (krate, feats.into_iter().collect()))
+        .collect()
+}
+
+/// The features about to be injected as an aligned table, one row per
+/// crate and feature (both sorted), with the xconfig keys behind each:
+///
+/// ```text
+/// crate    feature  xconfig
+/// crate_a  net      net
+///          smp      smp
+/// ```
+///
+/// Empty when nothing is injected.
+fn feature_summary(
+    feature_map: &HashMap<String, Vec<String>>,
+    feature_keys: &HashMap<(String, String), BTreeSet<String>>,
+) -> Vec<String> {
+    let mut rows: Vec<[String; 3]> = Vec::new();
+    let mut crates: Vec<&String> = feature_map.keys().collect();
+    crates.sort();
+    for krate in crates {
+        let mut feats: Vec<&String> = feature_map[krate].iter().collect();
+        feats.sort();
+        feats.dedup();
+        for (i, feat) in feats.into_iter().enumerate() {
+            let keys = feature_keys
+                .get(&(krate.clone(), feat.clone()))
+                .map(|keys| keys.iter().cloned().collect::<Vec<_>>().join(", "))
+                .unwrap_or_default();
+            let name = if i == 0 { krate.clone() } else { String::new() };
+            rows.push([name, feat.clone(), keys]);
+        }
+    }
+    if rows.is_empty() {
+        return Vec::new();
+    }
+    rows.insert(0, ["crate", "feature", "xconfig"].map(str::to_string));
+
+    let w0 = rows.iter().map(|r| r[0].chars().count()).max().unwrap_or(0);
+    let w1 = rows.iter().map(|r| r[1].chars().count()).max().unwrap_or(0);
+    let mut lines = Vec::with_capacity(rows.len());
+    for [krate, feat, keys] in &rows {
+        let line = format!("{krate:w0$}  {feat:w1$}  {keys}");
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+/// `--explain-externs`: one line per injected extern with the chain that
+/// caused it, e.g. `net → crate_a/net → dep:smoltcp`.
+fn explain_externs(
+    extern_map: &HashMap<String, Vec<ExternDep>>,
+    feature_keys: &HashMap<(String, String), BTreeSet<String>>,
+) {
+    let mut crates: Vec<&String> = extern_map.keys().collect();
+    crates.sort();
+    for krate in crates {
+        for dep in &extern_map[krate] {
+            let keys = feature_keys
+                .get(&(krate.clone(), dep.feature.clone()))
+                .map(|keys| keys.iter().cloned().collect::<Vec<_>>().join(", "))
+                .unwrap_or_else(|| "?".to_string());
+            info!(
+                "extern `{}` into `{krate}`: {keys} → {krate}/{} → dep:{}",
+                dep.crate_name, dep.feature, dep.pkg_name
+            );
+        }
+    }
+}
+
+/// The cfgs of `host = false` keys among `active`: each key's own, its
+/// aliases and its module cfg.
+fn target_only_cfgs(defs: &HashMap<String, types::XConfigDef>, active: &[String]) -> Vec<String> {
+    let mut cfgs: Vec<String> = Vec::new();
+    for (key, def) in defs.iter().filter(|(_, d)| !d.host) {
+        let mut names = vec![key.clone(), config::module_name(key)];
+        names.extend(def.aliases.iter().cloned());
+        names.retain(|n| active.contains(n));
+        cfgs.extend(names.iter().map(|n| config::cfg_name(n)));
+    }
+    cfgs.sort();
+    cfgs
+}
+
+/// `--dump-metadata-map`: each crate's xconfig keys and the specs they
+/// map to, marking those applied (active) and those `defconfig.toml`
+/// does not define, e.g. `crate_a: smp = ["smp", "crate_b/smp"] (active)`.
+fn dump_metadata_map(mappings: &[config::CrateMapping]) {
+    for mapping in mappings {
+        info!("{} ({}):", mapping.krate, mapping.manifest);
+        for (key, m) in &mapping.keys {
+            let state = match (m.known, m.active) {
+                (false, _) => " (unknown key)",
+                (true, true) => " (active)",
+                (true, false) => "",
+            };
+            info!("  {key} = {:?}{state}", m.specs);
+            for (spec, why) in &m.why {
+                info!("    {spec}: {why}");
+            }
+        }
+        if !mapping.excluded.is_empty() {
+            info!("  excluded: {:?}", mapping.excluded);
+        }
+    }
+}
+
+impl BuildPlan {
+    /// `keys` without those that set no cfg (`feature_only`).
+    pub fn cfg_keys(&self, keys: &[String]) -> Vec<String> {
+        let sets_cfg = |k: &&String| !self.feature_only.contains(*k);
+        keys.iter().filter(sets_cfg).cloned().collect()
+    }
+
+    fn lock(&self, root: &Path) -> lock::ConfigLock {
+        lock::ConfigLock::new(root, &self.active, &self.feature_map, &self.extern_map)
+    }
+}
+
+/// Sync `.cargo/config.toml` and `.vscode/settings.json` for rust-analyzer
+/// (after Phase 1 so we have rlib_paths), plus `.idea/workspace.xml` for
+/// IntelliJ Rust when `.idea/` exists or `--idea` is given. Each can be
+/// switched off in `[xbuild]` of `defconfig.toml`.
+///
+/// `--check-sync` writes none of them: it fails if `.cargo/config.toml`
+/// or `.vscode/settings.json`, the files a project commits, are out of
+/// date, after printing how. `.idea/` is left out.
+fn sync_ide(paths: &ConfigPaths, plan: &BuildPlan, xdeps: &XDeps, opts: &Options) -> Result<()> {
+    let root = &paths.root;
+    let format = opts.message_format;
+    let settings = &plan.settings;
+    let check = opts.check_sync;
+    let mut stale = Vec::new();
+    if settings.sync_cargo_config {
+        let changed = config::sync_cargo_config(
+            root,
+            &plan.cfg_keys(&plan.active),
+            &plan.cfg_keys(&plan.all_keys),
+            &xdeps.rlib_paths,
+            &xdeps.dep_dirs,
+            opts.force,
+            check,
+        )?;
+        format.emit(&Message::IdeSync {
+            file: ".cargo/config.toml",
+            changed,
+        })?;
+        if changed {
+            stale.push(".cargo/config.toml");
+        }
+    }
+    if settings.sync_vscode {
+        let mut extra_env = Vec::new();
+        if settings.vscode_extra_env {
+            let active_file = write_active_file(root, plan)?;
+            extra_env.push(("XBUILD_CONFIG", active_file.display().to_string()));
+        }
+        let active = plan.cfg_keys(&plan.active);
+        let feature_map = &plan.feature_map;
+        let changed = config::sync_vscode_settings(root, &active, feature_map, &extra_env, check)?;
+        format.emit(&Message::IdeSync {
+            file: ".vscode/settings.json",
+            changed,
+        })?;
+        if changed {
+            stale.push(".vscode/settings.json");
+        }
+    }
+    if check {
+        if !stale.is_empty() {
+            bail!(
+                "{} out of date; run `cargo xbuild sync` and commit the result",
+                stale.join(" and ")
+            );
+        }
+        return Ok(());
+    }
+    if settings.sync_idea && (opts.idea || root.join(".idea").is_dir()) {
+        let changed = config::sync_idea_settings(root, &paths.workspace, &plan.feature_map)?;
+        format.emit(&Message::IdeSync {
+            file: ".idea/workspace.xml",
+            changed,
+        })?;
+    }
+    Ok(())
+}
+
+/// Hash of every input the wrapper injection depends on, for `__xfp`: the
+/// canonical (sorted) plan — active keys, features, extern specs — plus
+/// the exact env handed to the wrapper, which adds explicit-feature merging
+/// and the rlib paths, and the cfgs it keeps out of host crates.
+fn fingerprint(
+    root: &Path,
+    plan: &BuildPlan,
+    features_env: &str,
+    externs_env: &str,
+) -> Result<String> {
+    let canonical = toml::to_string(&plan.lock(root)).context("serialize plan for __xfp")?;
+    let target_only = plan.target_only.join(",");
+    let parts = [&canonical, features_env, externs_env, &target_only];
+    Ok(format!("{:016x}", fnv1a(&parts)))
+}
+
+/// 64-bit FNV-1a over `parts`, each terminated by 0xff (never valid in
+/// UTF-8, so parts can't run into each other). Unlike `DefaultHasher`,
+/// the result is fixed across runs and Rust releases, which `__xfp`
+/// needs since cargo compares it against previous builds.
+fn fnv1a(parts: &[&str]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for b in part.bytes().chain([0xff]) {
+            h ^= u64::from(b);
+            h = h.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    h
+}
+
+/// `sync` (and `load-config`): bring the IDE files in line with
+/// `.config.toml`. Phase 1 still runs, since the rlib paths rust-analyzer
+/// needs only exist once xdeps is built; the workspace itself is not.
+fn sync(paths: &ConfigPaths, opts: &Options) -> Result<()> {
+    let plan = plan(paths, opts, &CargoArgs::default())?;
+    // 4. Phase 1: auto-generate xdeps/Cargo.toml and build it
+    let xdeps = timings::time("xdeps build", || {
+        xdeps::build_xdeps(&paths.root, &plan.extern_map, &[])
+    })
+    .context(Failure::Metadata)?;
+    timings::time("IDE sync", || sync_ide(paths, &plan, &xdeps, opts))
+}
+
+/// Steps 5–6: everything Phase 2 sets in cargo's environment for the
+/// wrapper, given the cargo command line it will run. `RUSTFLAGS` is
+/// additionally removed (see `base_rustflags`). `XBUILD_CONFIG` points
+/// build scripts at the active config (see `write_active_file`).
+///
+/// The wrapper goes in `RUSTC_WRAPPER`, which cargo runs for every crate
+/// in the graph, registry deps included, where it has nothing to do but
+/// still costs a process spawn each. When every crate injected into is a
+/// workspace member it goes in `RUSTC_WORKSPACE_WRAPPER` instead, which
+/// cargo only runs for members. That cannot reach a dependency such as a
+/// git crate mapped with `crate_b/smp`, and an existing `RUSTC_WRAPPER`
+/// (which cargo would run around ours) also keeps the plain variant, so
+/// it is chained as before, as does a `RUSTC_WORKSPACE_WRAPPER` already
+/// taken by someone else (`cargo clippy`). So do `host = false` keys: the
+/// wrapper must see every proc-macro and build script to strip their
+/// cfgs (`XCONFIG_TARGET_ONLY`).
+///
+/// With `trace` (`--dump-rustc-commands`) the wrapper also appends each
+/// rustc command to that file (`XCONFIG_TRACE_FILE`). It lists only what
+/// cargo recompiles.
+fn wrapper_env(
+    root: &Path,
+    plan: &BuildPlan,
+    xdeps: &XDeps,
+    args: &[String],
+    trace: Option<&Path>,
+) -> Result<Vec<(String, String)>> {
+    let cargo_args = CargoArgs::parse(args);
+    if cargo_args.no_default_features {
+        debug!("--no-default-features given; xconfig features are still injected");
+    }
+    let explicit = cargo_args.features_by_crate(&plan.default_packages);
+    let scope = plan.scope.as_ref();
+    let feature_map = merge_explicit_features(&within_scope(&plan.feature_map, scope), &explicit);
+    let extern_map = unless_explicit_externs(&within_scope(&plan.extern_map, scope), &explicit);
+
+    // 5. Encode env vars for the wrapper
+    let features_env = wrapper::encode_features(&feature_map, &plan.versions);
+    let externs_env = wrapper::encode_externs(&extern_map, &xdeps.rlib_paths, &plan.versions);
+
+    // 6. Build rustflags. Passed as CARGO_ENCODED_RUSTFLAGS (one flag per
+    // \x1f-separated field) so no flag is ever re-split on whitespace or
+    // has its quotes reinterpreted.
+    let mut rustflags = base_rustflags();
+    warn_cfg_collisions(&rustflags, plan);
+    for c in &plan.cfg_keys(&plan.active) {
+        rustflags.push(format!("--cfg={}", config::cfg_name(c)));
+    }
+    // --check-cfg for ALL known keys, not just active ones
+    for c in &plan.cfg_keys(&plan.all_keys) {
+        rustflags.push(format!("--check-cfg=cfg({})", config::cfg_name(c)));
+    }
+    // Add xdeps deps dirs to search path so transitive deps can find extern rlibs
+    for dir in &xdeps.dep_dirs {
+        rustflags.push(format!("-Ldependency={dir}"));
+    }
+    // __xfp: cargo never sees what the wrapper injects, so fold all of it
+    // into a cfg value; any change then changes RUSTFLAGS and rebuilds.
+    let fingerprint = fingerprint(root, plan, &features_env, &externs_env)?;
+    rustflags.push(format!("--cfg=__xfp=\"{fingerprint}\""));
+    rustflags.push("--check-cfg=cfg(__xfp,values(any()))".to_string());
+    debug!("Phase 2: rustflags={rustflags:?}");
+
+    let active_file = write_active_file(root, plan)?;
+    let wrapper = std::env::current_exe().context("locate cargo-xbuild binary")?;
+    let inner = inner_wrapper(&wrapper);
+    let wrapper_path = wrapper.display().to_string();
+    // `cargo clippy` sets RUSTC_WORKSPACE_WRAPPER itself, to clippy-driver
+    let workspace_wrapper_free = cargo_args.command.as_deref() != Some("clippy")
+        && std::env::var_os("RUSTC_WORKSPACE_WRAPPER").is_none();
+    // Keeping target-only cfgs out of host crates takes seeing all of them,
+    // and so does tracing them all
+    let host_free = plan.target_only.is_empty() && trace.is_none();
+    let mut env = if plan.members_only && inner.is_none() && workspace_wrapper_free && host_free {
+        debug!("Phase 2: injecting into workspace members only; using RUSTC_WORKSPACE_WRAPPER");
+        // An empty RUSTC_WRAPPER also switches off one set in cargo config
+        vec![
+            ("RUSTC_WORKSPACE_WRAPPER".into(), wrapper_path),
+            ("RUSTC_WRAPPER".into(), String::new()),
+        ]
+    } else {
+        vec![("RUSTC_WRAPPER".into(), wrapper_path)]
+    };
+    env.extend([
+        ("__XCONFIG_WRAPPER".into(), "1".to_string()),
+        (logging::LOG_ENV.into(), log::max_level().to_string()),
+        ("CARGO_ENCODED_RUSTFLAGS".into(), rustflags.join("\x1f")),
+        ("XBUILD_CONFIG".into(), active_file.display().to_string()),
+    ]);
+    let pass = |var, value| inline_or_file(root, &fingerprint, var, value);
+    env.extend(pass("XCONFIG_FEATURES", features_env)?);
+    env.extend(pass("XCONFIG_EXTERNS", externs_env)?);
+    if !plan.target_only.is_empty() {
+        env.push(("XCONFIG_TARGET_ONLY".into(), plan.target_only.join(",")));
+    }
+    if let Some(inner) = inner {
+        debug!("Phase 2: chaining the existing RUSTC_WRAPPER `{inner}`");
+        env.push(("XCONFIG_INNER_WRAPPER".into(), inner));
+    }
+    if let Some(path) = trace {
+        env.push(("XCONFIG_TRACE_FILE".into(), path.display().to_string()));
+    }
+    Ok(env)
+}
+
+/// The `target/xbuild/` file `--dump-rustc-commands` traces into.
+const TRACE_FILE: &str = "rustc-commands.txt";
+
+/// Longest wrapper env value passed inline. Windows caps a variable at
+/// 32767 chars and the whole block not much higher, and big workspaces
+/// can exceed that with features and extern paths alone.
+const INLINE_ENV_MAX: usize = 8 * 1024;
+
+/// `var` set to `value`, or, when `value` is longer than
+/// `INLINE_ENV_MAX`, `<var>_FILE` set to `target/xbuild/<var>-<fingerprint>`
+/// holding it, so builds of different plans never overwrite each other's
+/// file. The other of the two is set empty, so one inherited from an
+/// enclosing xbuild build never shadows it (the wrapper reads `_FILE`
+/// first).
+fn inline_or_file(
+    root: &Path,
+    fingerprint: &str,
+    var: &str,
+    value: String,
+) -> Result<[(String, String); 2]> {
+    let file_var = format!("{var}_FILE");
+    if value.len() <= INLINE_ENV_MAX {
+        return Ok([(var.to_string(), value), (file_var, String::new())]);
+    }
+    let dir = root.join("target").join("xbuild");
+    let path = dir.join(format!("{var}-{fingerprint}"));
+    if !std::fs::read_to_string(&path).is_ok_and(|old| old == value) {
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&path, &value).with_context(|| format!("write {}", path.display()))?;
+    }
+    let (path, len) = (path.display().to_string(), value.len());
+    debug!("Phase 2: {var} is {len} bytes; passing it in {path}");
+    Ok([(var.to_string(), String::new()), (file_var, path)])
+}
+
+/// Write `target/xbuild/active.json` for build scripts that generate code
+/// from the config: the active keys and the cfgs they set, both sorted.
+/// The file is only rewritten when that changes. Phase 2 exports its path
+/// as `XBUILD_CONFIG`, so a build script can do
+///
+/// ```ignore
+/// println!("cargo:rerun-if-env-changed=XBUILD_CONFIG");
+/// if let Ok(path) = std::env::var("XBUILD_CONFIG") {
+///     println!("cargo:rerun-if-changed={path}");
+///     let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+///     let smp = config["cfgs"].as_array().unwrap().iter().any(|c| c == "SMP");
+/// }
+/// ```
+fn write_active_file(root: &Path, plan: &BuildPlan) -> Result<std::path::PathBuf> {
+    let mut active = plan.active.clone();
+    active.sort();
+    let cfgs: Vec<String> = plan
+        .cfg_keys(&active)
+        .iter()
+        .map(|k| config::cfg_name(k))
+        .collect();
+    let content = serde_json::to_string_pretty(&serde_json::json!({
+        "active": active,
+        "cfgs": cfgs,
+    }))? + "\n";
+    write_xbuild_file(root, "active.json", &content)
+}
+
+/// `--emit-cfgs`: write `target/xbuild/cfgs.json`, every cfg the build
+/// passes to rustc, for tools that must reproduce the set out of band
+/// (coverage, external analyzers):
+///
+/// ```json
+/// {
+///   "cfgs": ["NET", "SMP"],
+///   "target_only": ["SMP"],
+///   "features": { "crate_a": ["net", "smp"] },
+///   "disabled": { "crate_b": ["std"] }
+/// }
+/// ```
+///
+/// `cfgs` go to every crate, except `target_only` ones to proc-macros and
+/// build scripts; `features` are each crate's `feature="…"` cfgs on top
+/// of its defaults (`--features` included), `disabled` those checked to
+/// stay off.
+/// Everything is sorted, so equal plans write identical files.
+fn write_cfgs_file(root: &Path, plan: &BuildPlan) -> Result<std::path::PathBuf> {
+    let mut cfgs: Vec<String> = plan
+        .cfg_keys(&plan.active)
+        .iter()
+        .map(|k| config::cfg_name(k))
+        .collect();
+    cfgs.sort();
+    let disabled: BTreeMap<&String, BTreeSet<&String>> = plan
+        .disabled_map
+        .iter()
+        .map(|(krate, feats)| (krate, feats.iter().collect()))
+        .collect();
+    let content = serde_json::to_string_pretty(&serde_json::json!({
+        "cfgs": cfgs,
+        "target_only": plan.target_only,
+        "features": plan.package_features,
+        "disabled": disabled,
+    }))? + "\n";
+    write_xbuild_file(root, "cfgs.json", &content)
+}
+
+/// Write `target/xbuild/<name>`, unless it already holds `content` (so
+/// its mtime only moves when it changes), returning its path.
+fn write_xbuild_file(root: &Path, name: &str, content: &str) -> Result<std::path::PathBuf> {
+    let dir = root.join("target").join("xbuild");
+    let path = dir.join(name);
+    if std::fs::read_to_string(&path).is_ok_and(|old| old == content) {
+        return Ok(path);
+    }
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(&path, content).with_context(|| format!("write {}", path.display()))?;
+    debug!("wrote {}", path.display());
+    Ok(path)
+}
+
+/// The build script instructions that give a crate the xconfig cfgs
+/// without the wrapper: `cargo:rustc-cfg` for each active key (virtual
+/// cfgs and aliases included), `cargo:rustc-check-cfg` for every known
+/// one, both leaving out `emit = "feature"` keys, and
+/// `cargo:rerun-if-changed` for `defconfig.toml`, its `defconfig.d/` and
+/// `.config.toml`.
+///
+/// This is the fallback for crates built without `cargo xbuild` (or that
+/// cannot use `RUSTC_WRAPPER`), e.g. from a build script:
+///
+/// ```ignore
+/// fn main() {
+///     cargo_xbuild::build_script().unwrap();
+/// }
+/// ```
+///
+/// or as `cargo xbuild buildscript`, whose output the build script copies
+/// to its own. Only the cfgs are set: no features or optional
+/// dependencies are injected into any crate, and files pulled in with
+/// `include = [...]` do not trigger a rerun.
+pub fn build_script_lines(paths: &ConfigPaths) -> Result<Vec<String>> {
+    let (active, all_keys, _) = config::load_active_xconfigs(paths)?;
+    let defs = config::load_defconfig(&paths.defconfig)?;
+    let feature_only = config::not_emitting(&defs, types::XConfigDef::emits_cfg);
+    let mut lines = vec![
+        format!("cargo:rerun-if-changed={}", paths.defconfig.display()),
+        format!("cargo:rerun-if-changed={}", paths.config.display()),
+    ];
+    let fragments = paths.defconfig.with_file_name("defconfig.d");
+    if fragments.is_dir() {
+        lines.push(format!("cargo:rerun-if-changed={}", fragments.display()));
+    }
+    let cfgs = |keys: &[String]| {
+        let keys = keys.iter().filter(|k| !feature_only.contains(k));
+        keys.map(|k| config::cfg_name(k)).collect::<Vec<_>>()
+    };
+    for cfg in cfgs(&all_keys) {
+        lines.push(format!("cargo:rustc-check-cfg=cfg({cfg})"));
+    }
+    for cfg in cfgs(&active) {
+        lines.push(format!("cargo:rustc-cfg={cfg}"));
+    }
+    Ok(lines)
+}
+
+/// [`build_script_lines`] for the project containing the current
+/// directory (a build script runs in its package's), printed to stdout.
+pub fn build_script() -> Result<()> {
+    let paths = ConfigPaths::discover(None, None, true)?;
+    for line in build_script_lines(&paths)? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// The `RUSTC_WRAPPER` already in the environment (e.g. sccache), which
+/// ours replaces and then runs in rustc's place so the two coexist.
+/// `own` (this binary, when xbuild runs inside another xbuild's build)
+/// never counts.
+pub(crate) fn inner_wrapper(own: &Path) -> Option<String> {
+    inner_wrapper_in(own, |var| std::env::var(var).ok())
+}
+
+/// `inner_wrapper`, reading the environment through `get`.
+fn inner_wrapper_in(own: &Path, get: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let own = own.canonicalize().ok();
+    ["RUSTC_WRAPPER", "CARGO_BUILD_RUSTC_WRAPPER"]
+        .into_iter()
+        .filter_map(get)
+        .find(|w| !w.is_empty())
+        .filter(|w| own.is_none() || Path::new(w).canonicalize().ok() != own)
+}
+
+/// Step 7: Phase 2, run cargo in the cargo workspace with the wrapper env.
+fn run_cargo(
+    paths: &ConfigPaths,
+    plan: &BuildPlan,
+    xdeps: &XDeps,
+    cargo_args: &[String],
+    opts: &Options,
+) -> Result<()> {
+    let format = opts.message_format;
+    let args = cargo_command(cargo_args);
+    // Emptied first, so it lists this build's commands only
+    let trace = if opts.dump_rustc_commands {
+        let path = write_xbuild_file(&paths.root, TRACE_FILE, "")?;
+        info!("tracing rustc commands to {}", path.display());
+        Some(path)
+    } else {
+        None
+    };
+    let env = wrapper_env(&paths.root, plan, xdeps, &args, trace.as_deref())?;
+
+    info!("Phase 2: running cargo {}", args.join(" "));
+    let status = Command::new("cargo")
+        .args(&args)
+        .envs(env)
+        .env_remove("RUSTFLAGS")
+        .current_dir(&paths.workspace)
+        .status()
+        .context("cargo failed")?;
+
+    format.emit(&Message::BuildFinished {
+        success: status.success(),
+        exit_code: status.code(),
+    })?;
+    if !status.success() {
+        return Err(Failure::Cargo(status.code().unwrap_or(1)).into());
+    }
+    Ok(())
+}
+
+/// `env [--json] [CARGO ARGS...]`: plan and build xdeps like a real build
+/// (the extern rlib paths only exist after Phase 1), then print the env
+/// Phase 2 would set for `cargo CARGO ARGS...` instead of running it.
+fn print_env(paths: &ConfigPaths, opts: &Options, json: bool, cargo_args: &[String]) -> Result<()> {
+    let root = &paths.root;
+    let args = cargo_command(cargo_args);
+    let cargo = CargoArgs::parse(&args);
+    let plan = plan(paths, opts, &cargo)?;
+    let xdeps_flags = cargo.xdeps_flags(&paths.workspace);
+    let xdeps =
+        xdeps::build_xdeps(root, &plan.extern_map, &xdeps_flags).context(Failure::Metadata)?;
+    // Named but left alone: nothing is built here to trace
+    let trace_dir = root.join("target").join("xbuild");
+    let trace = opts.dump_rustc_commands.then(|| trace_dir.join(TRACE_FILE));
+    let env = wrapper_env(root, &plan, &xdeps, &args, trace.as_deref())?;
+
+    if json {
+        let env = env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        return MessageFormat::Json.emit(&Message::Env {
+            env,
+            unset: &["RUSTFLAGS"],
+        });
+    }
+    println!("unset RUSTFLAGS");
+    for (key, value) in &env {
+        println!("export {key}={}", shell_quote(value));
+    }
+    Ok(())
+}
+
+/// Quote `s` for a POSIX shell; values with control characters (the \x1f
+/// in CARGO_ENCODED_RUSTFLAGS) use bash's `$'…'` so they survive copy-paste.
+fn shell_quote(s: &str) -> String {
+    if s.chars().any(|c| c.is_control()) {
+        let mut out = String::from("$'");
+        for c in s.chars() {
+            match c {
+                '\\' | '\'' => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('\'');
+        out
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// The cargo command line to run: `build -p entry` when none is given, and
+/// `run`/`test` get `-p entry` unless a package (or `--workspace`) is
+/// already selected. It is inserted right after the command, so anything
+/// after a `--` reaches the binary or test harness untouched. A leading
+/// `+toolchain` stays in front of the command.
+fn cargo_command(cargo_args: &[String]) -> Vec<String> {
+    let mut args = cargo_args.to_vec();
+    let at = usize::from(args.first().is_some_and(|a| a.starts_with('+')));
+    match args.get(at).map(|s| s.as_str()) {
+        None => {
+            args.extend(["build".into(), "-p".into(), DEFAULT_PACKAGE.into()]);
+        }
+        Some("run" | "test") => {
+            let parsed = CargoArgs::parse(&args[at + 1..]);
+            if parsed.packages.is_empty() && !parsed.workspace {
+                debug!(
+                    "no package selected for `{}`; defaulting to -p {DEFAULT_PACKAGE}",
+                    args[at]
+                );
+                let default = ["-p".to_string(), DEFAULT_PACKAGE.to_string()];
+                args.splice(at + 1..at + 1, default);
+            }
+        }
+        Some(_) => {}
+    }
+    args
+}
+
+/// `check`: run every validation and the extern resolution, then print
+/// what a build would inject — without compiling anything.
+fn check(paths: &ConfigPaths, opts: &Options, json: bool) -> Result<()> {
+    let plan = plan(paths, opts, &CargoArgs::default())?;
+    if json {
+        let summary = Message::plan_summary(&plan);
+        return MessageFormat::Json.emit(&summary);
+    }
+
+    let mut active = plan.active.clone();
+    active.sort();
+    println!("xconfig check passed");
+    println!("active xconfigs: {}", active.join(", "));
+    println!("features to inject:");
+    for (krate, feats) in message::sorted_features(&plan.feature_map) {
+        println!("  {krate}: {}", feats.join(", "));
+    }
+    println!("externs to inject:");
+    for (krate, deps) in message::sorted_externs(&plan.extern_map) {
+        println!("  {krate}: {}", deps.join(", "));
+    }
+    Ok(())
+}
+
+/// Drop injected features that are also requested explicitly with
+/// `--features`/`-F` on the cargo command line.
+///
+/// Precedence: an explicit cargo feature wins. cargo enables it natively
+/// (including any `dep:` activation), so also injecting it as
+/// `--cfg feature` would only duplicate it; its optional deps are left to
+/// cargo too (`unless_explicit_externs`). `--no-default-features` only
+/// affects cargo's default features; xconfig features are injected on top
+/// regardless.
+fn merge_explicit_features(
+    feature_map: &HashMap<String, Vec<String>>,
+    explicit: &[(String, String)],
+) -> HashMap<String, Vec<String>> {
+    let mut merged = feature_map.clone();
+    for (krate, feat) in explicit {
+        let krate = krate.replace('-', "_");
+        for (cn, feats) in merged.iter_mut() {
+            let name = resolve::split_version(cn).0;
+            if name.replace('-', "_") == krate && feats.contains(feat) {
+                warn!(
+                    "feature `{feat}` on `{cn}` is both passed via --features and \
+                     injected by xconfig; leaving it to cargo"
+                );
+                feats.retain(|f| f != feat);
+            }
+        }
+    }
+    merged.retain(|_, feats| !feats.is_empty());
+    merged
+}
+
+/// `extern_map` without the optional deps a feature passed with
+/// `--features` pulls in: cargo activates that `dep:` itself and passes
+/// its own `--extern`, and a second one from xdeps would give rustc two
+/// different rlibs for the same crate.
+fn unless_explicit_externs(
+    extern_map: &HashMap<String, Vec<ExternDep>>,
+    explicit: &[(String, String)],
+) -> HashMap<String, Vec<ExternDep>> {
+    let mut kept = extern_map.clone();
+    for (cn, deps) in kept.iter_mut() {
+        let name = resolve::split_version(cn).0.replace('-', "_");
+        let explicit_for = |feat: &str| {
+            let same = |k: &String| k.replace('-', "_") == name;
+            explicit.iter().any(|(k, f)| same(k) && f == feat)
+        };
+        let by_cargo: Vec<String> = deps
+            .iter()
+            .filter(|d| explicit_for(&d.feature))
+            .map(|d| d.crate_name.clone())
+            .collect();
+        if !by_cargo.is_empty() {
+            debug!("`{cn}`: cargo passes --extern for {by_cargo:?} itself (--features)");
+        }
+        deps.retain(|d| !by_cargo.contains(&d.crate_name));
+    }
+    kept.retain(|_, deps| !deps.is_empty());
+    kept
+}
+
+/// `disabled` without the features passed with `--features`: cargo
+/// enables those, as asked.
+fn unless_explicit(
+    disabled: &HashMap<String, Vec<String>>,
+    explicit: &[(String, String)],
+) -> HashMap<String, Vec<String>> {
+    let mut kept = disabled.clone();
+    for (krate, feat) in explicit {
+        let krate = krate.replace('-', "_");
+        for (cn, feats) in kept.iter_mut() {
+            let name = resolve::split_version(cn).0;
+            if name.replace('-', "_") == krate && feats.contains(feat) {
+                warn!(
+                    "feature `{feat}` on `{cn}` is disabled by xconfig but passed via \
+                     --features; leaving it on"
+                );
+                feats.retain(|f| f != feat);
+            }
+        }
+    }
+    kept.retain(|_, feats| !feats.is_empty());
+    kept
+}
+
+/// The user's own rustflags to build on, following cargo's precedence:
+/// an existing `CARGO_ENCODED_RUSTFLAGS` wins and `RUSTFLAGS` is ignored;
+/// otherwise `RUSTFLAGS` is split on whitespace. Either way the result is
+/// passed on as `CARGO_ENCODED_RUSTFLAGS` only, so the two are never both
+/// set for the child cargo.
+fn base_rustflags() -> Vec<String> {
+    let encoded = std::env::var("CARGO_ENCODED_RUSTFLAGS").ok();
+    user_rustflags(encoded, std::env::var("RUSTFLAGS").ok())
+}
+
+/// `base_rustflags` from the values of `CARGO_ENCODED_RUSTFLAGS` and
+/// `RUSTFLAGS`.
+fn user_rustflags(encoded: Option<String>, plain: Option<String>) -> Vec<String> {
+    if let Some(encoded) = encoded {
+        if plain.is_some() {
+            warn!(
+                "both CARGO_ENCODED_RUSTFLAGS and RUSTFLAGS are set; \
+                 RUSTFLAGS is ignored (same as cargo)"
+            );
+        }
+        return encoded
+            .split('\x1f')
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    plain
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Warn about `--cfg`s in the user's own rustflags that name an xconfig
+/// cfg. Both are passed to rustc and a cfg is set if either sets it, so
+/// the user's flag wins over a key that is off in `.config.toml`; for an
+/// active key it is merely redundant.
+fn warn_cfg_collisions(user_flags: &[String], plan: &BuildPlan) {
+    for collision in cfg_collisions(user_flags, &plan.all_keys, &plan.active) {
+        warn!("{collision}");
+    }
+}
+
+/// The warnings `warn_cfg_collisions` gives, for the keys `all_keys` of
+/// which `active` are on.
+fn cfg_collisions(user_flags: &[String], all_keys: &[String], active: &[String]) -> Vec<String> {
+    let mut cfgs = Vec::new();
+    let mut flags = user_flags.iter();
+    while let Some(flag) = flags.next() {
+        let spec = match flag.strip_prefix("--cfg") {
+            Some("") => flags.next().map(String::as_str),
+            Some(rest) => rest.strip_prefix('='),
+            None => None,
+        };
+        if let Some(spec) = spec {
+            cfgs.push(spec.split('=').next().unwrap_or(spec));
+        }
+    }
+
+    let mut collisions = Vec::new();
+    for key in all_keys {
+        let name = config::cfg_name(key);
+        if !cfgs.contains(&name.as_str()) {
+            continue;
+        }
+        if active.contains(key) {
+            collisions.push(format!(
+                "RUSTFLAGS already sets --cfg {name}, which xconfig `{key}` sets too"
+            ));
+        } else {
+            collisions.push(format!(
+                "RUSTFLAGS sets --cfg {name} although xconfig `{key}` is off; \
+                 the RUSTFLAGS cfg wins, so {name} is on"
+            ));
+        }
+    }
+    collisions
+}
+
+fn xbuild_main() -> Result<()> {
+    // cargo passes "xbuild" as the first arg when invoked as `cargo xbuild`;
+    // skip it so the remaining args are the real cargo command.
+    let all_args: Vec<String> = std::env::args().skip(1).collect();
+    let all_args: Vec<String> = if all_args.first().map(|s| s.as_str()) == Some("xbuild") {
+        all_args.into_iter().skip(1).collect()
+    } else {
+        all_args
+    };
+    let cli = cli::parse(all_args)?;
+    let opts = &cli.options;
+    logging::init(opts.verbosity);
+    if opts.timings {
+        timings::show();
+    }
+    // `doctor` reports a missing defconfig.toml rather than failing on it
+    if let Subcommand::Doctor = cli.subcommand {
+        return doctor::doctor(opts);
+    }
+    // Nor does `schema`, which needs no project at all
+    if let Subcommand::Schema = cli.subcommand {
+        return schema::print_schema();
+    }
+    // `init` creates defconfig.toml, so it must not search for one
+    let search = !matches!(cli.subcommand, Subcommand::Init);
+    let mut paths = ConfigPaths::discover(
+        opts.defconfig_file.as_deref(),
+        opts.config_file.as_deref(),
+        search,
+    )?;
+    paths.config_env = opts.config_env;
+
+    // The summary covers the phases that ran, also when one failed
+    let result = run_subcommand(cli.subcommand, &paths, opts);
+    timings::summary();
+    result
+}
+
+/// Run `subcommand` against the discovered project.
+fn run_subcommand(subcommand: Subcommand, paths: &ConfigPaths, opts: &Options) -> Result<()> {
+    let root = &paths.root;
+    match subcommand {
+        Subcommand::Init => init::init(paths),
+        Subcommand::SaveConfig(name) => preset::save_config(paths, &name),
+        Subcommand::LoadConfig(name) => {
+            preset::load_config(paths, &name)?;
+            sync(paths, opts)
+        }
+        Subcommand::Check { json } => check(paths, opts, json),
+        Subcommand::Sync => sync(paths, opts),
+        Subcommand::Watch => watch::watch(paths, opts),
+        Subcommand::BuildScript => {
+            for line in build_script_lines(paths)? {
+                println!("{line}");
+            }
+            Ok(())
+        }
+        Subcommand::Edit(edit) => {
+            edit::edit_config(paths, &edit)?;
+            sync(paths, opts)
+        }
+        Subcommand::Explain { key, json } => explain::explain(paths, &key, json),
+        Subcommand::Doctor | Subcommand::Schema => unreachable!("handled before discovery"),
+        Subcommand::Env { json, cargo_args } => print_env(paths, opts, json, &cargo_args),
+        Subcommand::Cargo(cargo_args) => {
+            let cargo = CargoArgs::parse(&cargo_command(&cargo_args));
+            let plan = plan(paths, opts, &cargo)?;
+            // 4. Phase 1: auto-generate xdeps/Cargo.toml and build it
+            let xdeps_flags = cargo.xdeps_flags(&paths.workspace);
+            let xdeps = timings::time("xdeps build", || {
+                xdeps::build_xdeps(root, &plan.extern_map, &xdeps_flags)
+            })
+            .context(Failure::Metadata)?;
+            timings::time("IDE sync", || sync_ide(paths, &plan, &xdeps, opts))?;
+            timings::time("cargo", || {
+                run_cargo(paths, &plan, &xdeps, &cargo_args, opts)
+            })?;
+            // 8. Record the plan that was just built
+            if !opts.locked {
+                lock::write_lock(root, &plan.lock(root))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// ── Entry point ──────────────────────────────────────────────────────
+
+/// What failed, for the exit code of `cargo xbuild` (see [`exit_code`]).
+/// Attached to errors as context, so it also heads the error message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// `defconfig.toml` or `.config.toml` is invalid, or `--locked` found
+    /// `.config.lock` out of date
+    Config,
+    /// Reading crate manifests or `cargo metadata`, resolving externs, or
+    /// building the injected dependencies (xdeps); often worth a retry
+    Metadata,
+    /// The cargo command itself failed with this exit code
+    Cargo(i32),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Config => f.write_str("invalid xconfig configuration"),
+            Failure::Metadata => f.write_str("crate metadata resolution failed"),
+            Failure::Cargo(code) => write!(f, "cargo exited with code {code}"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The exit code for `err`, for scripts and CI:
+///
+/// | code | meaning                                                   |
+/// |------|-----------------------------------------------------------|
+/// | 0    | success                                                   |
+/// | 1    | anything else: bad arguments, I/O, internal errors        |
+/// | 2    | configuration invalid ([`Failure::Config`])               |
+/// | 3    | metadata or dependency resolution ([`Failure::Metadata`]) |
+/// | *n*  | the cargo command failed with code *n* (usually 101)      |
+///
+/// Cargo's own codes 2 and 3 exit as 1, so that those two always mean
+/// what the table says.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<Failure>() {
+        Some(Failure::Config) => 2,
+        Some(Failure::Metadata) => 3,
+        Some(Failure::Cargo(2 | 3)) => 1,
+        Some(Failure::Cargo(code)) => *code,
+        None => 1,
+    }
+}
+
+/// What the `cargo-xbuild` binary does: act as the rustc wrapper when
+/// cargo invokes it as one, otherwise run the command line.
+pub fn run() -> Result<()> {
+    if std::env::var("__XCONFIG_WRAPPER").is_ok() {
+        wrapper::wrapper_main()
+    } else {
+        xbuild_main()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{scratch, strings, words};
+
+    fn map(entries: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(krate, feats)| (krate.to_string(), strings(feats)))
+            .collect()
+    }
+
+    #[test]
+    fn injection_is_scoped_to_the_selected_packages() {
+        let feature_map = map(&[
+            ("app", &["net"]),
+            ("net-stack", &["smp"]),
+            ("log@0.4", &["std"]),
+            ("other", &["smp"]),
+        ]);
+        let scope: BTreeSet<String> = strings(&["app", "net_stack", "log"]).into_iter().collect();
+
+        let scoped = within_scope(&feature_map, Some(&scope));
+        let encoded = wrapper::encode_features(&scoped, &HashMap::new());
+        assert_eq!(encoded, "app:net;log:std;net_stack:smp");
+        // The plan itself keeps the whole workspace for the lock and IDE sync
+        assert_eq!(feature_map.len(), 4);
+    }
+
+    #[test]
+    fn explicit_feature_overlapping_an_injected_one_is_left_to_cargo() {
+        let feature_map = map(&[("app", &["net", "smp"]), ("net-stack", &["smp"])]);
+        // `cargo build --features smp` with `app` the default package
+        let cargo = CargoArgs::parse(&words("build --features smp"));
+        let explicit = cargo.features_by_crate(&strings(&["app"]));
+        let merged = merge_explicit_features(&feature_map, &explicit);
+        assert_eq!(merged, map(&[("app", &["net"]), ("net-stack", &["smp"])]));
+    }
+
+    #[test]
+    fn package_features_merge_and_deduplicate() {
+        let feature_map = map(&[("net-stack@0.2", &["smp", "log"]), ("app", &["net"])]);
+        let cargo = CargoArgs::parse(&words("build -p app@1.2 -F net,smp -F net_stack/smp"));
+        let explicit = cargo.features_by_crate(&[]);
+        let merged = package_features(&feature_map, &explicit);
+        let expected: BTreeMap<String, Vec<String>> = [
+            ("app".to_string(), strings(&["net", "smp"])),
+            ("net_stack".to_string(), strings(&["log", "smp"])),
+        ]
+        .into();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn encoded_rustflags_keep_a_value_with_a_space() {
+        let encoded = "--cfg\x1fvendor=\"acme corp\"\x1f-Copt-level=2";
+        let flags = user_rustflags(Some(encoded.to_string()), None);
+        assert_eq!(flags, ["--cfg", "vendor=\"acme corp\"", "-Copt-level=2"]);
+        // and go back out in the same form, with the xconfig cfgs after them
+        let mut out = flags;
+        out.push("--cfg=SMP".to_string());
+        assert_eq!(out.join("\x1f"), format!("{encoded}\x1f--cfg=SMP"));
+    }
+
+    #[test]
+    fn encoded_rustflags_win_when_both_are_set() {
+        let encoded = Some("-Ctarget-cpu=native".to_string());
+        let plain = Some("-Copt-level=3 --cfg=LEGACY".to_string());
+        let both = user_rustflags(encoded, plain.clone());
+        assert_eq!(both, ["-Ctarget-cpu=native"]);
+        let plain_only = user_rustflags(None, plain);
+        assert_eq!(plain_only, ["-Copt-level=3", "--cfg=LEGACY"]);
+        assert!(user_rustflags(Some(String::new()), None).is_empty());
+    }
+
+    #[test]
+    fn no_scope_injects_everywhere() {
+        let feature_map = map(&[("app", &["net"]), ("other", &["smp"])]);
+        assert_eq!(within_scope(&feature_map, None), feature_map);
+    }
+
+    #[test]
+    fn existing_wrapper_is_chained_but_never_ours() {
+        let root = scratch("inner-wrapper", &[("sccache", ""), ("cargo-xbuild", "")]);
+        let (dummy, own) = (root.join("sccache"), root.join("cargo-xbuild"));
+        let dummy = dummy.display().to_string();
+        let env = |vars: &[(&str, &str)]| {
+            let get = |var: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == var)
+                    .map(|(_, v)| v.to_string())
+            };
+            inner_wrapper_in(&own, get)
+        };
+        assert_eq!(env(&[("RUSTC_WRAPPER", &dummy)]), Some(dummy.clone()));
+        let config = [("RUSTC_WRAPPER", ""), ("CARGO_BUILD_RUSTC_WRAPPER", &dummy)];
+        assert_eq!(env(&config), Some(dummy.clone()));
+        let nested = own.display().to_string();
+        assert_eq!(env(&[("RUSTC_WRAPPER", &nested)]), None);
+        assert_eq!(env(&[]), None);
+    }
+
+    #[test]
+    fn user_cfg_naming_a_key_is_warned_about() {
+        let flags = words("-C opt-level=2 --cfg SMP --cfg=NET --cfg feature=\"log\"");
+        let all_keys = strings(&["smp", "net", "log", "fs"]);
+        let collisions = cfg_collisions(&flags, &all_keys, &strings(&["smp", "fs"]));
+        let expected = [
+            "RUSTFLAGS already sets --cfg SMP, which xconfig `smp` sets too",
+            "RUSTFLAGS sets --cfg NET although xconfig `net` is off; \
+             the RUSTFLAGS cfg wins, so NET is on",
+        ];
+        assert_eq!(collisions, expected);
+    }
+}
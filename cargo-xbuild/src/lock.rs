@@ -0,0 +1,162 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::types::{DepSource, ExternDep};
+
+pub const LOCK_FILE: &str = ".config.lock";
+
+const HEADER: &str = "# This file is generated by cargo-xbuild. Do not edit it by hand.\n";
+
+/// `.config.lock`: the exact plan a build injected — active xconfigs,
+/// features per crate and resolved externs. Like `Cargo.lock` it is meant
+/// to be committed; `--locked` fails if the computed plan differs.
+///
+/// Everything is sorted so the file only changes when the plan does.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ConfigLock {
+    pub active: Vec<String>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub externs: BTreeMap<String, Vec<LockedExtern>>,
+}
+
+/// One resolved `ExternDep`, with its source spelled like a Cargo.toml
+/// dependency entry.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LockedExtern {
+    pub name: String,
+    pub package: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    /// Relative to the project root when the dep lives inside it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(
+        rename = "default-features",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_features: Option<bool>,
+}
+
+impl ConfigLock {
+    pub fn new(
+        root: &Path,
+        active: &[String],
+        feature_map: &HashMap<String, Vec<String>>,
+        extern_map: &HashMap<String, Vec<ExternDep>>,
+    ) -> Self {
+        let mut active = active.to_vec();
+        active.sort();
+
+        let features = feature_map
+            .iter()
+            .map(|(krate, feats)| {
+                let mut feats = feats.clone();
+                feats.sort();
+                feats.dedup();
+                (krate.clone(), feats)
+            })
+            .collect();
+
+        let externs = extern_map
+            .iter()
+            .map(|(krate, deps)| {
+                let mut locked: Vec<LockedExtern> =
+                    deps.iter().map(|d| LockedExtern::new(root, d)).collect();
+                locked.sort_by(|a, b| a.name.cmp(&b.name));
+                (krate.clone(), locked)
+            })
+            .collect();
+
+        ConfigLock {
+            active,
+            features,
+            externs,
+        }
+    }
+}
+
+impl LockedExtern {
+    fn new(root: &Path, dep: &ExternDep) -> Self {
+        let mut locked = LockedExtern {
+            name: dep.crate_name.clone(),
+            package: dep.pkg_name.clone(),
+            git: None,
+            path: None,
+            version: None,
+            features: Vec::new(),
+            default_features: None,
+        };
+        match &dep.source {
+            DepSource::Git(url) => locked.git = Some(url.clone()),
+            DepSource::Path(p) => {
+                let p = Path::new(p);
+                let p = p.strip_prefix(root).unwrap_or(p);
+                locked.path = Some(p.display().to_string());
+            }
+            DepSource::Registry {
+                version,
+                features,
+                default_features,
+            } => {
+                let mut features = features.clone();
+                features.sort();
+                locked.version = Some(version.clone());
+                locked.features = features;
+                locked.default_features = Some(*default_features);
+            }
+        }
+        locked
+    }
+}
+
+/// Write `.config.lock` if its content changed. Returns whether it wrote.
+pub fn write_lock(root: &Path, lock: &ConfigLock) -> Result<bool> {
+    let path = root.join(LOCK_FILE);
+    let content = format!(
+        "{HEADER}{}",
+        toml::to_string(lock).context("serialize .config.lock")?
+    );
+    if std::fs::read_to_string(&path).is_ok_and(|old| old == content) {
+        return Ok(false);
+    }
+    std::fs::write(&path, content).with_context(|| format!("write {}", path.display()))?;
+    info!("updated {LOCK_FILE}");
+    Ok(true)
+}
+
+/// `--locked`: fail unless the committed `.config.lock` matches `lock`.
+pub fn verify_lock(root: &Path, lock: &ConfigLock) -> Result<()> {
+    let path = root.join(LOCK_FILE);
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("--locked given but {} cannot be read", path.display()))?;
+    let committed: ConfigLock =
+        toml::from_str(&content).with_context(|| format!("parse {}", path.display()))?;
+    if committed == *lock {
+        return Ok(());
+    }
+
+    let mut changed = Vec::new();
+    if committed.active != lock.active {
+        changed.push("active xconfigs");
+    }
+    if committed.features != lock.features {
+        changed.push("features");
+    }
+    if committed.externs != lock.externs {
+        changed.push("externs");
+    }
+    bail!(
+        "{LOCK_FILE} is out of date ({} differ); rerun without --locked to update it",
+        changed.join(", ")
+    );
+}
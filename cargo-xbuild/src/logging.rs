@@ -0,0 +1,59 @@
+use log::{Level, LevelFilter};
+use std::io::{IsTerminal, Write};
+use std::sync::OnceLock;
+
+/// Env var carrying the orchestrator's effective log level to wrapper
+/// processes, so `-v`/`-q` apply to the rustc wrapper as well.
+pub const LOG_ENV: &str = "__XCONFIG_LOG";
+
+/// Initialize `env_logger` with xbuild's `[xbuild] …` line format.
+///
+/// The default level is info, which matches the historical always-on
+/// output. `RUST_LOG` refines it, and an explicit `level` (`-v`/`-q`)
+/// overrides both.
+pub fn init(level: Option<LevelFilter>) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(LevelFilter::Info)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Info => writeln!(buf, "[xbuild] {}", record.args()),
+            Level::Warn => writeln!(buf, "[xbuild] warning: {}", record.args()),
+            level => writeln!(
+                buf,
+                "[xbuild] {}: {}",
+                level.as_str().to_lowercase(),
+                record.args()
+            ),
+        });
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    builder.init();
+}
+
+/// Text styles for `paint`.
+#[derive(Clone, Copy)]
+pub enum Style {
+    Bold,
+    Red,
+    Yellow,
+}
+
+/// `s` in `style` when stderr (where all logging goes) is a terminal and
+/// `NO_COLOR` is unset or empty; `s` unchanged otherwise.
+pub fn paint(s: &str, style: Style) -> String {
+    static COLOR: OnceLock<bool> = OnceLock::new();
+    let color = *COLOR.get_or_init(|| {
+        std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && std::io::stderr().is_terminal()
+    });
+    if !color {
+        return s.to_string();
+    }
+    let code = match style {
+        Style::Bold => "1",
+        Style::Red => "1;31",
+        Style::Yellow => "1;33",
+    };
+    format!("\x1b[{code}m{s}\x1b[0m")
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::CrateMapping;
+use crate::explain::Explanation;
+use crate::types::ExternDep;
+use crate::BuildPlan;
+
+/// How cargo-xbuild reports its own lifecycle events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageFormat {
+    /// `[xbuild] …` lines on stderr only (default)
+    #[default]
+    Human,
+    /// Additionally emit one JSON object per event on stdout
+    Json,
+}
+
+/// A lifecycle event emitted under `--message-format=json`.
+///
+/// Like cargo's own protocol, every object carries a `reason` field, so
+/// wrappers can parse xbuild and cargo messages from the same stream.
+#[derive(Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    /// Active xconfig keys read from `.config.toml`
+    XconfigActive { active: &'a [String] },
+    /// crate → features injected via `--cfg feature="…"`
+    FeatureMap {
+        features: BTreeMap<&'a str, &'a [String]>,
+    },
+    /// crate → optional deps injected via `--extern`
+    ExternMap {
+        externs: BTreeMap<&'a str, Vec<&'a str>>,
+    },
+    /// `--dump-metadata-map`: every crate's xconfig table as parsed
+    MetadataMap { crates: &'a [CrateMapping] },
+    /// `explain <KEY> --json`
+    Explain(&'a Explanation),
+    /// An IDE file was checked and (if `changed`) rewritten
+    IdeSync { file: &'a str, changed: bool },
+    /// `check`: everything a build would inject, with nothing compiled
+    PlanSummary {
+        active: Vec<&'a str>,
+        features: BTreeMap<&'a str, &'a [String]>,
+        externs: BTreeMap<&'a str, Vec<&'a str>>,
+        /// crate → injected plus explicit `--features`
+        package_features: &'a BTreeMap<String, Vec<String>>,
+    },
+    /// `env --json`: what Phase 2 would set (and unset) for cargo
+    Env {
+        env: BTreeMap<&'a str, &'a str>,
+        unset: &'a [&'a str],
+    },
+    /// Phase 2 cargo finished
+    BuildFinished { success: bool, exit_code: Option<i32> },
+}
+
+impl<'a> Message<'a> {
+    pub fn feature_map(feature_map: &'a HashMap<String, Vec<String>>) -> Self {
+        Message::FeatureMap {
+            features: sorted_features(feature_map),
+        }
+    }
+
+    pub fn extern_map(extern_map: &'a HashMap<String, Vec<ExternDep>>) -> Self {
+        Message::ExternMap {
+            externs: sorted_externs(extern_map),
+        }
+    }
+
+    pub fn plan_summary(plan: &'a BuildPlan) -> Self {
+        let mut active: Vec<&str> = plan.active.iter().map(|s| s.as_str()).collect();
+        active.sort();
+        Message::PlanSummary {
+            active,
+            features: sorted_features(&plan.feature_map),
+            externs: sorted_externs(&plan.extern_map),
+            package_features: &plan.package_features,
+        }
+    }
+}
+
+/// crate → features, ordered by crate name.
+pub fn sorted_features(feature_map: &HashMap<String, Vec<String>>) -> BTreeMap<&str, &[String]> {
+    feature_map
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_slice()))
+        .collect()
+}
+
+/// crate → extern crate names, ordered by crate name.
+pub fn sorted_externs(extern_map: &HashMap<String, Vec<ExternDep>>) -> BTreeMap<&str, Vec<&str>> {
+    extern_map
+        .iter()
+        .map(|(k, deps)| (k.as_str(), deps.iter().map(|d| d.crate_name.as_str()).collect()))
+        .collect()
+}
+
+impl MessageFormat {
+    /// Print `msg` as a JSON line on stdout in JSON mode; no-op otherwise.
+    pub fn emit(self, msg: &Message) -> Result<()> {
+        if self == MessageFormat::Json {
+            println!("{}", serde_json::to_string(msg)?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, ConfigPaths};
+
+/// Named `.config.toml` snapshots live in `configs/<name>.toml`, like the
+/// kernel's per-board defconfig files, so they can be version-controlled.
+fn preset_path(root: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        bail!("invalid preset name `{name}` (expected a plain file stem like `qemu`)");
+    }
+    Ok(root.join("configs").join(format!("{name}.toml")))
+}
+
+/// `save-config <name>`: copy the current `.config.toml` to `configs/<name>.toml`.
+pub fn save_config(paths: &ConfigPaths, name: &str) -> Result<()> {
+    let root = &paths.root;
+    let label = paths.config_label();
+    let content = std::fs::read_to_string(&paths.config)
+        .with_context(|| format!("read {}", paths.config.display()))?;
+    config::validate_config_content(paths, &content, &label)?;
+
+    let preset = preset_path(root, name)?;
+    std::fs::create_dir_all(root.join("configs"))?;
+    std::fs::write(&preset, &content)
+        .with_context(|| format!("write {}", preset.display()))?;
+    info!("saved {label} as configs/{name}.toml");
+    Ok(())
+}
+
+/// `load-config <name>`: replace `.config.toml` with `configs/<name>.toml`.
+/// The preset is validated first, so an invalid one never overwrites
+/// the current config.
+pub fn load_config(paths: &ConfigPaths, name: &str) -> Result<()> {
+    let preset = preset_path(&paths.root, name)?;
+    let content = std::fs::read_to_string(&preset)
+        .with_context(|| format!("read {}", preset.display()))?;
+    let label = format!("configs/{name}.toml");
+    config::validate_config_content(paths, &content, &label)?;
+
+    std::fs::write(&paths.config, &content)
+        .with_context(|| format!("write {}", paths.config.display()))?;
+    info!("loaded {label} into {}", paths.config_label());
+    Ok(())
+}
@@ -1,79 +1,398 @@
 use anyhow::{bail, Context, Result};
-use std::collections::HashMap;
+use log::{debug, warn};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::process::{Command, Stdio};
 
+use crate::cargo_args::CargoArgs;
 use crate::types::*;
 
+/// Result of resolving a feature_map against `cargo metadata`.
+#[derive(Default)]
+pub struct Resolved {
+    /// target_crate → optional deps to inject via `--extern`
+    pub extern_map: HashMap<String, Vec<ExternDep>>,
+    /// target_crate → version it was resolved against, recorded only when
+    /// several packages share that name so the wrapper can tell them apart
+    pub versions: HashMap<String, String>,
+    /// Whether every crate in the feature_map is a workspace member, so
+    /// `RUSTC_WORKSPACE_WRAPPER` reaches all of them
+    pub members_only: bool,
+}
+
 /// Given a feature_map (crate → features to enable), resolve the
 /// optional dependencies that each feature activates by parsing the
 /// target crate's `[features]` table and dependency metadata.
 ///
-/// Returns extern_map: target_crate → Vec<ExternDep>
+/// `cargo` is the user's cargo command: its network flags
+/// (`--offline`/`--frozen`/`--locked`) are passed on to `cargo metadata`,
+/// and dev-dependencies are only considered when it builds tests.
+///
+/// `cargo metadata --no-deps` is tried first. It lists every workspace
+/// member, also in a virtual workspace (no root package), so it is enough
+/// whenever every mapped crate is a member; only a crate from outside
+/// the workspace (a git or registry dependency) takes the full metadata.
 pub fn resolve_extern_map(
     root: &Path,
     feature_map: &HashMap<String, Vec<String>>,
-) -> Result<HashMap<String, Vec<ExternDep>>> {
+    cargo: &CargoArgs,
+) -> Result<Resolved> {
     if feature_map.is_empty() {
-        return Ok(HashMap::new());
+        return Ok(Resolved {
+            members_only: true,
+            ..Resolved::default()
+        });
     }
 
     // Try --no-deps first, fall back to full if needed
-    let try_metadata = |extra: &[&str]| -> Result<Vec<u8>> {
-        let mut args = vec!["metadata", "--format-version=1"];
-        args.extend_from_slice(extra);
-        let output = Command::new("cargo")
-            .args(&args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .current_dir(root)
-            .output()
-            .context("cargo metadata")?;
-        if !output.status.success() {
-            bail!(
-                "cargo metadata failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
-        Ok(output.stdout)
-    };
+    let try_metadata = |extra: &[&str]| cargo_metadata(root, extra, cargo);
 
     // Full metadata (includes transitive deps like git crates)
     let metadata_bytes = match try_metadata(&["--no-deps"]) {
         Ok(bytes) => {
             let meta: CargoMetadata = serde_json::from_slice(&bytes)?;
-            let has_all = feature_map
-                .keys()
-                .all(|k| meta.packages.iter().any(|p| &p.name == k));
-            if has_all {
+            if lists_every_crate(&meta, feature_map) {
                 bytes
             } else {
                 try_metadata(&[])?
             }
         }
-        Err(_) => try_metadata(&[])?,
+        Err(e) => {
+            debug!("{e:#}\nretrying with full metadata");
+            try_metadata(&[])?
+        }
     };
 
-    resolve_extern_map_from_metadata(&metadata_bytes, feature_map)
+    resolve_extern_map_from_metadata(&metadata_bytes, feature_map, cargo.builds_tests())
+}
+
+/// Run `cargo metadata --format-version=1` with `extra` args and the
+/// network flags of `cargo`, returning its JSON.
+fn cargo_metadata(root: &Path, extra: &[&str], cargo: &CargoArgs) -> Result<Vec<u8>> {
+    let args = metadata_args(extra, cargo);
+    let output = Command::new("cargo")
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .current_dir(root)
+        .output()
+        .context("cargo metadata")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("`cargo {}` failed ({}):\n{stderr}", args.join(" "), output.status);
+    }
+    // cargo may still warn (e.g. unused [patch] entries)
+    if !stderr.trim().is_empty() {
+        debug!("`cargo {}` stderr:\n{}", args.join(" "), stderr.trim_end());
+    }
+    Ok(output.stdout)
+}
+
+/// The args of `cargo_metadata`'s cargo invocation.
+fn metadata_args<'a>(extra: &[&'a str], cargo: &'a CargoArgs) -> Vec<&'a str> {
+    let mut args = vec!["metadata", "--format-version=1"];
+    args.extend_from_slice(extra);
+    args.extend(cargo.network_flags.iter().map(String::as_str));
+    args
+}
+
+/// The crates `cargo` can build given its `-p` selections: the selected
+/// packages plus everything they depend on in the resolved graph, names
+/// normalized with underscores. Dev-dependencies count only for the
+/// selected packages themselves, and only when tests are built.
+///
+/// `None` without `-p` (or with `--workspace`): any crate may be built.
+pub fn package_closure(root: &Path, cargo: &CargoArgs) -> Result<Option<HashSet<String>>> {
+    if cargo.packages.is_empty() || cargo.workspace {
+        return Ok(None);
+    }
+    let bytes = cargo_metadata(root, &[], cargo)?;
+    let meta: CargoMetadata = serde_json::from_slice(&bytes).context("parse cargo metadata")?;
+    let Some(resolve) = &meta.resolve else {
+        bail!("cargo metadata returned no dependency graph");
+    };
+
+    let names: HashMap<&str, &str> = meta
+        .packages
+        .iter()
+        .map(|p| (p.id.as_str(), p.name.as_str()))
+        .collect();
+    let nodes: HashMap<&str, &MetadataNode> =
+        resolve.nodes.iter().map(|n| (n.id.as_str(), n)).collect();
+
+    // `-p name@1.0` / `-p name:1.0` select by name; the version only
+    // disambiguates for cargo itself
+    let selected: HashSet<String> = cargo
+        .packages
+        .iter()
+        .map(|p| p.split(['@', ':']).next().unwrap_or(p).replace('-', "_"))
+        .collect();
+    let mut stack: Vec<(&str, bool)> = meta
+        .packages
+        .iter()
+        .filter(|p| selected.contains(&p.name.replace('-', "_")))
+        .map(|p| (p.id.as_str(), true))
+        .collect();
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    while let Some((id, is_root)) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let Some(node) = nodes.get(id) else { continue };
+        for dep in &node.deps {
+            let kinds = &dep.dep_kinds;
+            let dev_only =
+                !kinds.is_empty() && kinds.iter().all(|k| k.kind.as_deref() == Some("dev"));
+            if !dev_only || (is_root && cargo.builds_tests()) {
+                stack.push((dep.pkg.as_str(), false));
+            }
+        }
+    }
+
+    Ok(Some(
+        seen.into_iter()
+            .filter_map(|id| names.get(id))
+            .map(|name| name.replace('-', "_"))
+            .collect(),
+    ))
+}
+
+/// The packages a bare `--features feat` applies to when no `-p` is
+/// given: those cargo selects itself, the workspace's default members
+/// (the root package, or every member of a virtual workspace, unless
+/// `default-members` says otherwise), or every member with `--workspace`.
+///
+/// Empty with `-p`, or when every feature names its crate: cargo
+/// metadata is not run then.
+pub fn default_packages(root: &Path, cargo: &CargoArgs) -> Result<Vec<String>> {
+    let bare = cargo.features.iter().any(|f| !f.contains('/'));
+    if !cargo.packages.is_empty() || !bare {
+        return Ok(Vec::new());
+    }
+    let bytes = cargo_metadata(root, &["--no-deps"], cargo)?;
+    let meta: CargoMetadata = serde_json::from_slice(&bytes).context("parse cargo metadata")?;
+    Ok(selected_by_default(&meta, cargo.workspace))
+}
+
+/// Names of the packages `meta` selects without `-p` (see
+/// [`default_packages`]).
+fn selected_by_default(meta: &CargoMetadata, workspace: bool) -> Vec<String> {
+    let members = meta
+        .packages
+        .iter()
+        .filter(|p| meta.workspace_members.contains(&p.id));
+    let selected: Vec<&MetadataPackage> = if workspace {
+        members.collect()
+    } else if !meta.workspace_default_members.is_empty() {
+        let default = &meta.workspace_default_members;
+        members.filter(|p| default.contains(&p.id)).collect()
+    } else {
+        // cargo before 1.71 does not list them: the root package if
+        // there is one, otherwise the whole (virtual) workspace
+        let root_manifest = Path::new(&meta.workspace_root).join("Cargo.toml");
+        let is_root = |p: &&MetadataPackage| Path::new(&p.manifest_path) == root_manifest;
+        match members.clone().find(is_root) {
+            Some(root) => vec![root],
+            None => members.collect(),
+        }
+    };
+    selected.into_iter().map(|p| p.name.clone()).collect()
+}
+
+/// Why cargo enables features that `-crate/feat` specs keep off
+/// (`disabled`: crate → features), one problem per feature it enables
+/// anyway. Cargo's feature resolution is never changed, only checked: a
+/// feature it enables stays enabled, with its `dep:` activations and
+/// sub-features, whatever cfgs rustc gets. The causes found are named:
+/// a dependent asking for it or for its crate's default features, a
+/// `crate/feat` entry in another package's `[features]`, or one of the
+/// crate's own enabled features listing it (`default` included).
+///
+/// The resolved graph is the whole workspace's, dev-dependencies
+/// included, so it may name a cause a narrower build would not have.
+pub fn disabled_problems(
+    root: &Path,
+    disabled: &HashMap<String, Vec<String>>,
+    cargo: &CargoArgs,
+) -> Result<Vec<String>> {
+    if disabled.is_empty() {
+        return Ok(Vec::new());
+    }
+    let bytes = cargo_metadata(root, &[], cargo)?;
+    let meta: CargoMetadata = serde_json::from_slice(&bytes).context("parse cargo metadata")?;
+    disabled_problems_in(&meta, disabled)
+}
+
+/// `disabled_problems` against an already parsed `cargo metadata`.
+fn disabled_problems_in(
+    meta: &CargoMetadata,
+    disabled: &HashMap<String, Vec<String>>,
+) -> Result<Vec<String>> {
+    let Some(resolve) = &meta.resolve else {
+        bail!("cargo metadata returned no dependency graph");
+    };
+    let nodes: HashMap<&str, &MetadataNode> =
+        resolve.nodes.iter().map(|n| (n.id.as_str(), n)).collect();
+
+    let mut problems = Vec::new();
+    let mut crates: Vec<&String> = disabled.keys().collect();
+    crates.sort();
+    for krate in crates {
+        for pkg in find_packages(meta, krate) {
+            let Some(node) = nodes.get(pkg.id.as_str()) else {
+                continue;
+            };
+            for feat in disabled[krate].iter().filter(|f| node.features.contains(f)) {
+                let mut why = enablers(meta, &nodes, pkg, feat);
+                if why.is_empty() {
+                    why.push("cargo's feature resolution turns it on".to_string());
+                }
+                problems.push(format!(
+                    "`-{krate}/{feat}` keeps feature `{feat}` of {} {} off, but cargo \
+                     enables it: {}",
+                    pkg.name,
+                    pkg.version,
+                    why.join("; ")
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// What in the resolved graph enables `feat` on `pkg`, for
+/// `disabled_problems`.
+fn enablers(
+    meta: &CargoMetadata,
+    nodes: &HashMap<&str, &MetadataNode>,
+    pkg: &MetadataPackage,
+    feat: &str,
+) -> Vec<String> {
+    fn enabled<'p>(
+        nodes: &HashMap<&str, &MetadataNode>,
+        p: &'p MetadataPackage,
+    ) -> Vec<&'p String> {
+        let on: &[String] = nodes.get(p.id.as_str()).map_or(&[], |n| &n.features);
+        let mut features: Vec<&String> = p.features.keys().filter(|f| on.contains(f)).collect();
+        features.sort();
+        features
+    }
+
+    let mut why = Vec::new();
+    for f in enabled(nodes, pkg) {
+        if pkg.features[f].iter().any(|e| e == feat) {
+            why.push(format!("its feature `{f}` lists it"));
+        }
+    }
+    let in_default = implied(&pkg.features, "default").contains(feat);
+    for other in &meta.packages {
+        let depends = nodes
+            .get(other.id.as_str())
+            .is_some_and(|n| n.deps.iter().any(|d| d.pkg == pkg.id));
+        if !depends {
+            continue;
+        }
+        let edges = other
+            .dependencies
+            .iter()
+            .filter(|d| normalized(&d.name) == normalized(&pkg.name));
+        for dep in edges {
+            let kind = match dep.kind.as_deref() {
+                Some(kind) => format!(" ({kind}-dependency)"),
+                None => String::new(),
+            };
+            if dep.features.iter().any(|f| f == feat) {
+                why.push(format!("`{}` asks for it{kind}", other.name));
+            } else if dep.uses_default_features && in_default {
+                why.push(format!("`{}` uses its default features{kind}", other.name));
+            }
+            let name = &dep.name;
+            let entries = [format!("{name}/{feat}"), format!("{name}?/{feat}")];
+            for f in enabled(nodes, other) {
+                if other.features[f].iter().any(|e| entries.contains(e)) {
+                    why.push(format!("feature `{f}` of `{}` lists it", other.name));
+                }
+            }
+        }
+    }
+    why
+}
+
+/// The features `root` turns on within its own `[features]` table,
+/// itself included, following plain feature names (not `dep:` or
+/// `crate/feat` entries).
+fn implied<'t>(table: &'t HashMap<String, Vec<String>>, root: &'t str) -> HashSet<&'t str> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut stack = vec![root];
+    while let Some(feat) = stack.pop() {
+        if !seen.insert(feat) {
+            continue;
+        }
+        let plain = |e: &&String| !e.contains([':', '/']);
+        let entries = table.get(feat).into_iter().flatten().filter(plain);
+        stack.extend(entries.map(String::as_str));
+    }
+    seen
 }
 
 fn resolve_extern_map_from_metadata(
     metadata_json: &[u8],
     feature_map: &HashMap<String, Vec<String>>,
-) -> Result<HashMap<String, Vec<ExternDep>>> {
+    include_dev: bool,
+) -> Result<Resolved> {
     let meta: CargoMetadata =
         serde_json::from_slice(metadata_json).context("parse cargo metadata")?;
 
-    let pkg_lookup: HashMap<String, &MetadataPackage> =
-        meta.packages.iter().map(|p| (p.name.clone(), p)).collect();
+    let mut name_counts: HashMap<String, usize> = HashMap::new();
+    for p in &meta.packages {
+        *name_counts.entry(normalized(&p.name)).or_default() += 1;
+    }
 
     let mut extern_map: HashMap<String, Vec<ExternDep>> = HashMap::new();
+    let mut versions: HashMap<String, String> = HashMap::new();
+    let mut missing: Vec<String> = Vec::new();
+    let mut members_only = true;
 
     for (crate_name, features) in feature_map {
-        let pkg = match pkg_lookup.get(crate_name) {
-            Some(p) => p,
-            None => continue,
+        // `crate_b@0.2` names the instance itself; `crate_b` gets the
+        // preferred one
+        let (name, qualifier) = split_version(crate_name);
+        let pkg = match qualifier {
+            Some(q) => {
+                let matching: Vec<&MetadataPackage> = find_packages(&meta, crate_name).collect();
+                match matching.as_slice() {
+                    [p] => *p,
+                    [] => {
+                        missing.push(format!("`{crate_name}`: no {name} {q}.* in the graph"));
+                        continue;
+                    }
+                    several => {
+                        let found: Vec<&str> = several.iter().map(|p| p.version.as_str()).collect();
+                        missing.push(format!(
+                            "`{crate_name}` is ambiguous ({name} {}); give more of the version",
+                            found.join(", ")
+                        ));
+                        continue;
+                    }
+                }
+            }
+            None => match pick_package(&meta, name) {
+                Ok(Some(p)) => p,
+                Ok(None) => {
+                    members_only = false;
+                    continue;
+                }
+                Err(problem) => {
+                    missing.push(problem);
+                    continue;
+                }
+            },
         };
+        members_only &= meta.workspace_members.contains(&pkg.id);
+        if qualifier.is_some() || name_counts.get(&normalized(name)).is_some_and(|&n| n > 1) {
+            versions.insert(crate_name.clone(), pkg.version.clone());
+        }
 
         // Parse [features] table from the manifest
         let content = std::fs::read_to_string(&pkg.manifest_path)
@@ -81,63 +400,59 @@ fn resolve_extern_map_from_metadata(
         let dep_toml: DepCargoToml =
             toml::from_str(&content).with_context(|| format!("parse {}", pkg.manifest_path))?;
 
-        let feat_table = match &dep_toml.features {
-            Some(f) => f,
-            None => continue,
+        let no_features = HashMap::new();
+        let feat_table = dep_toml.features.as_ref().unwrap_or(&no_features);
+
+        // A mapped feature must exist, or its `--cfg feature=".."` would
+        // enable nothing it gates. Optional deps no `dep:` entry refers to
+        // are implicit features.
+        let named_by_dep = |name: &str| {
+            feat_table
+                .values()
+                .flatten()
+                .any(|e| e.strip_prefix("dep:") == Some(name))
         };
+        for feat_name in features {
+            let implicit = pkg
+                .dependencies
+                .iter()
+                .any(|d| d.optional && &d.name == feat_name && !named_by_dep(&d.name));
+            if !feat_table.contains_key(feat_name) && !implicit {
+                missing.push(format!(
+                    "`{crate_name}/{feat_name}`: {crate_name} has no feature `{feat_name}` ({})",
+                    pkg.manifest_path
+                ));
+            }
+        }
 
-        // Build dep name → source lookup from metadata dependencies
-        let dep_source_lookup: HashMap<String, DepSource> = pkg
-            .dependencies
-            .iter()
-            .filter(|d| d.optional)
-            .map(|d| {
-                let source = if let Some(src) = &d.source {
-                    if src.starts_with("git+") {
-                        let url = src.strip_prefix("git+").unwrap();
-                        let url = url.split('#').next().unwrap_or(url);
-                        DepSource::Git(url.to_string())
-                    } else {
-                        // crates.io registry dep — carry version/features/default_features
-                        let version = d.req.clone().unwrap_or_else(|| "*".to_string());
-                        DepSource::Registry {
-                            version,
-                            features: d.features.clone(),
-                            default_features: d.uses_default_features,
-                        }
-                    }
-                } else if let Some(path) = &d.path {
-                    DepSource::Path(path.clone())
-                } else {
-                    let version = d.req.clone().unwrap_or_else(|| "*".to_string());
-                    DepSource::Registry {
-                        version,
-                        features: d.features.clone(),
-                        default_features: d.uses_default_features,
-                    }
-                };
-                (d.name.clone(), source)
-            })
-            .collect();
+        let dep_source_lookup = optional_dep_sources(pkg, include_dev);
 
         for feat_name in features {
             if let Some(activates) = feat_table.get(feat_name) {
                 for entry in activates {
                     if let Some(dep_name) = entry.strip_prefix("dep:") {
                         let normalized = dep_name.replace('-', "_");
-                        let source = dep_source_lookup
-                            .get(dep_name)
-                            .cloned()
-                            .unwrap_or(DepSource::Registry {
-                                version: "*".to_string(),
-                                features: vec![],
-                                default_features: true,
-                            });
+                        let source = match dep_source_lookup.get(dep_name) {
+                            Some(source) => source.clone(),
+                            None => {
+                                warn!(
+                                    "`{crate_name}/{feat_name}`: `dep:{dep_name}` is not an \
+                                     optional dependency in cargo metadata; assuming the \
+                                     latest registry version with default features"
+                                );
+                                DepSource::Registry {
+                                    version: "*".to_string(),
+                                    features: vec![],
+                                    default_features: true,
+                                }
+                            }
+                        };
                         extern_map.entry(crate_name.clone()).or_default().push(
                             ExternDep {
                                 crate_name: normalized,
                                 pkg_name: dep_name.to_string(),
                                 source,
+                                feature: feat_name.clone(),
                             },
                         );
                     }
@@ -146,5 +461,407 @@ fn resolve_extern_map_from_metadata(
         }
     }
 
-    Ok(extern_map)
+    missing.sort();
+    crate::config::report_errors(&missing, "mapped feature check")?;
+
+    Ok(Resolved {
+        extern_map,
+        versions,
+        members_only,
+    })
+}
+
+/// Whether `meta` has a package for every crate of `feature_map`.
+fn lists_every_crate(meta: &CargoMetadata, feature_map: &HashMap<String, Vec<String>>) -> bool {
+    feature_map
+        .keys()
+        .all(|k| find_packages(meta, k).next().is_some())
+}
+
+/// The packages a feature_map crate (`crate_b`, `crate-b@0.2`) may be:
+/// same name, `-` and `_` alike, and a version matching the qualifier.
+fn find_packages<'m>(
+    meta: &'m CargoMetadata,
+    target: &str,
+) -> impl Iterator<Item = &'m MetadataPackage> {
+    let (name, qualifier) = split_version(target);
+    let name = normalized(name);
+    meta.packages.iter().filter(move |p| {
+        normalized(&p.name) == name && qualifier.is_none_or(|q| version_matches(&p.version, q))
+    })
+}
+
+/// The package an unqualified feature_map crate means when several share
+/// its name (two versions, or a git fork next to the registry crate),
+/// chosen by package id: the workspace member, else the one candidate
+/// that members depend on directly. Anything else is ambiguous, and the
+/// problem asks for an `@version` qualifier. `None` if no package has
+/// the name.
+fn pick_package<'m>(
+    meta: &'m CargoMetadata,
+    name: &str,
+) -> Result<Option<&'m MetadataPackage>, String> {
+    let candidates: Vec<&MetadataPackage> = find_packages(meta, name).collect();
+    if let [p] = candidates.as_slice() {
+        return Ok(Some(*p));
+    }
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let members = &meta.workspace_members;
+    let member: Vec<&MetadataPackage> = candidates
+        .iter()
+        .copied()
+        .filter(|p| members.contains(&p.id))
+        .collect();
+    if let [p] = member.as_slice() {
+        return Ok(Some(*p));
+    }
+    let direct: HashSet<&str> = meta
+        .resolve
+        .iter()
+        .flat_map(|r| &r.nodes)
+        .filter(|n| members.contains(&n.id))
+        .flat_map(|n| &n.deps)
+        .map(|d| d.pkg.as_str())
+        .collect();
+    let used: Vec<&MetadataPackage> = candidates
+        .iter()
+        .copied()
+        .filter(|p| direct.contains(p.id.as_str()))
+        .collect();
+    if let [p] = used.as_slice() {
+        return Ok(Some(*p));
+    }
+
+    let versions: Vec<&str> = candidates.iter().map(|p| p.version.as_str()).collect();
+    Err(format!(
+        "`{name}` is ambiguous ({name} {}); write `{name}@<version>` to pick one",
+        versions.join(", ")
+    ))
+}
+
+/// A crate or package name with `-` spelled `_`, as rustc sees it.
+fn normalized(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// A feature_map crate and its `@version` qualifier, if any:
+/// `crate_b@0.2` → (`crate_b`, `Some("0.2")`).
+pub fn split_version(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (target, None),
+    }
+}
+
+/// Whether `version` is `qualifier` or extends it by whole components:
+/// `0.2` matches 0.2.0 and 0.2.5, but not 0.20.0.
+fn version_matches(version: &str, qualifier: &str) -> bool {
+    version
+        .strip_prefix(qualifier)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '-', '+']))
+}
+
+/// Dep name → source of `pkg`'s optional dependencies. Build-deps are
+/// compiled for the host, so never injected; dev-deps only when tests
+/// are built (`include_dev`). A normal edge wins over a dev edge of the
+/// same name.
+fn optional_dep_sources(pkg: &MetadataPackage, include_dev: bool) -> HashMap<&str, DepSource> {
+    let mut sources: HashMap<&str, DepSource> = HashMap::new();
+    for d in pkg.dependencies.iter().filter(|d| d.optional) {
+        match d.kind.as_deref() {
+            None => {
+                sources.insert(&d.name, dep_source(d));
+            }
+            Some("dev") if include_dev => {
+                sources.entry(&d.name).or_insert_with(|| dep_source(d));
+            }
+            _ => {}
+        }
+    }
+    sources
+}
+
+/// How to re-declare an optional dependency in the xdeps manifest so it
+/// is built the way the dependency edge asks for. Registry deps carry the
+/// edge's version requirement, `features` and `default-features`.
+fn dep_source(d: &MetadataDep) -> DepSource {
+    match (&d.source, &d.path) {
+        (Some(src), _) if src.starts_with("git+") => {
+            let url = &src["git+".len()..];
+            let url = url.split('#').next().unwrap_or(url);
+            DepSource::Git(url.to_string())
+        }
+        (None, Some(path)) => DepSource::Path(path.clone()),
+        // crates.io (or another registry), or an edge with neither source
+        // nor path
+        _ => DepSource::Registry {
+            version: d.req.clone().unwrap_or_else(|| "*".to_string()),
+            features: d.features.clone(),
+            default_features: d.uses_default_features,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, manifest_path: &str) -> MetadataPackage {
+        MetadataPackage {
+            id: format!("{name} 0.1.0"),
+            name: name.to_string(),
+            version: "0.1.0".to_string(),
+            manifest_path: manifest_path.to_string(),
+            dependencies: Vec::new(),
+            features: HashMap::new(),
+        }
+    }
+
+    /// A workspace at `/ws` with `members` under `/ws/crates`, plus a
+    /// root package named `root` when `with_root`.
+    fn workspace(members: &[&str], with_root: bool) -> CargoMetadata {
+        let mut packages: Vec<MetadataPackage> = members
+            .iter()
+            .map(|m| package(m, &format!("/ws/crates/{m}/Cargo.toml")))
+            .collect();
+        if with_root {
+            packages.push(package("root", "/ws/Cargo.toml"));
+        }
+        packages.push(package("registry_dep", "/registry/dep/Cargo.toml"));
+        CargoMetadata {
+            workspace_members: packages[..packages.len() - 1]
+                .iter()
+                .map(|p| p.id.clone())
+                .collect(),
+            workspace_default_members: Vec::new(),
+            workspace_root: "/ws".to_string(),
+            packages,
+            resolve: None,
+        }
+    }
+
+    /// `app` depending on `log`, whose default feature `std` is kept off
+    /// (`-log/std`); `log` ends up with `enabled` features.
+    fn app_using_log(default_features: bool, enabled: &[&str]) -> CargoMetadata {
+        let mut app = package("app", "/ws/app/Cargo.toml");
+        app.dependencies.push(MetadataDep {
+            name: "log".to_string(),
+            kind: None,
+            source: None,
+            optional: false,
+            path: None,
+            req: Some("^0.1".to_string()),
+            features: Vec::new(),
+            uses_default_features: default_features,
+        });
+        let mut log = package("log", "/registry/log/Cargo.toml");
+        log.features = [
+            ("default".to_string(), vec!["std".to_string()]),
+            ("std".to_string(), Vec::new()),
+        ]
+        .into();
+        let node = |p: &MetadataPackage, deps: Vec<NodeDep>, features: &[&str]| MetadataNode {
+            id: p.id.clone(),
+            deps,
+            features: features.iter().map(|f| f.to_string()).collect(),
+        };
+        let to_log = NodeDep {
+            pkg: log.id.clone(),
+            dep_kinds: Vec::new(),
+        };
+        let nodes = vec![
+            node(&app, vec![to_log], &[]),
+            node(&log, Vec::new(), enabled),
+        ];
+        CargoMetadata {
+            workspace_members: vec![app.id.clone()],
+            workspace_default_members: Vec::new(),
+            workspace_root: "/ws".to_string(),
+            packages: vec![app, log],
+            resolve: Some(MetadataResolve { nodes }),
+        }
+    }
+
+    fn keep_off_log_std() -> HashMap<String, Vec<String>> {
+        [("log".to_string(), vec!["std".to_string()])].into()
+    }
+
+    #[test]
+    fn disabled_default_feature_cargo_leaves_off_is_no_problem() {
+        let meta = app_using_log(false, &[]);
+        let problems = disabled_problems_in(&meta, &keep_off_log_std()).unwrap();
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn disabled_feature_cargo_enables_anyway_is_reported_with_its_causes() {
+        let meta = app_using_log(true, &["default", "std"]);
+        let problems = disabled_problems_in(&meta, &keep_off_log_std()).unwrap();
+        let expected = "`-log/std` keeps feature `std` of log 0.1.0 off, but cargo enables \
+                        it: its feature `default` lists it; `app` uses its default features";
+        assert_eq!(problems, [expected]);
+    }
+
+    #[test]
+    fn registry_dep_keeps_its_requirement_features_and_defaults() {
+        let edge = MetadataDep {
+            name: "log".to_string(),
+            kind: None,
+            source: Some("registry+https://github.com/rust-lang/crates.io-index".to_string()),
+            optional: true,
+            path: None,
+            req: Some("^0.4".to_string()),
+            features: vec!["kv".to_string()],
+            uses_default_features: false,
+        };
+        let source = dep_source(&edge);
+        let DepSource::Registry {
+            version,
+            features,
+            default_features,
+        } = source
+        else {
+            panic!("not a registry source: {source:?}");
+        };
+        assert_eq!(version, "^0.4");
+        assert_eq!(features, ["kv"]);
+        assert!(!default_features);
+    }
+
+    #[test]
+    fn network_flags_reach_cargo_metadata() {
+        let words = crate::test_util::words("build --frozen -p app --offline -- --locked");
+        let cargo = CargoArgs::parse(&words);
+        let args = metadata_args(&["--no-deps"], &cargo);
+        assert_eq!(args[..2], ["metadata", "--format-version=1"]);
+        assert_eq!(args[2..], ["--no-deps", "--frozen", "--offline"]);
+    }
+
+    #[test]
+    fn optional_dev_dependency_counts_only_for_tests() {
+        let optional = |name: &str, kind: Option<&str>| MetadataDep {
+            name: name.to_string(),
+            kind: kind.map(str::to_string),
+            source: None,
+            optional: true,
+            path: Some(format!("/ws/{name}")),
+            req: None,
+            features: Vec::new(),
+            uses_default_features: true,
+        };
+        let mut app = package("app", "/ws/app/Cargo.toml");
+        app.dependencies = vec![
+            optional("netdep", None),
+            optional("mock_net", Some("dev")),
+            optional("codegen", Some("build")),
+        ];
+        let names = |include_dev: bool| {
+            let mut names: Vec<&str> = optional_dep_sources(&app, include_dev)
+                .into_keys()
+                .collect();
+            names.sort();
+            names.join(",")
+        };
+        assert_eq!(names(false), "netdep");
+        assert_eq!(names(true), "mock_net,netdep");
+    }
+
+    #[test]
+    fn virtual_workspace_members_need_no_full_metadata() {
+        // `--no-deps` output: the members only, and no root package
+        let mut meta = workspace(&["crate_b", "net-stack"], false);
+        meta.packages.retain(|p| p.name != "registry_dep");
+        let mapped = |crates: &[&str]| -> HashMap<String, Vec<String>> {
+            crates.iter().map(|c| (c.to_string(), Vec::new())).collect()
+        };
+        let members = mapped(&["crate-b", "crate_b@0.1", "net_stack"]);
+        assert!(lists_every_crate(&meta, &members));
+        let with_dependency = mapped(&["crate_b", "registry_dep"]);
+        assert!(!lists_every_crate(&meta, &with_dependency));
+    }
+
+    /// `app` (the only member) and two versions of `log`, `0.3.0` reached
+    /// through `other` only and `0.4.0` through `app_deps` of `app`.
+    fn two_logs(app_deps: &[&str]) -> CargoMetadata {
+        let versioned = |name: &str, version: &str| MetadataPackage {
+            id: format!("{name} {version}"),
+            version: version.to_string(),
+            ..package(name, &format!("/registry/{name}-{version}/Cargo.toml"))
+        };
+        let packages = vec![
+            package("app", "/ws/Cargo.toml"),
+            versioned("other", "1.0.0"),
+            versioned("log", "0.3.0"),
+            versioned("log", "0.4.0"),
+        ];
+        let node = |id: &str, deps: &[&str]| MetadataNode {
+            id: id.to_string(),
+            deps: deps
+                .iter()
+                .map(|d| NodeDep {
+                    pkg: d.to_string(),
+                    dep_kinds: Vec::new(),
+                })
+                .collect(),
+            features: Vec::new(),
+        };
+        let nodes = vec![
+            node("app 0.1.0", app_deps),
+            node("other 1.0.0", &["log 0.3.0"]),
+            node("log 0.3.0", &[]),
+            node("log 0.4.0", &[]),
+        ];
+        CargoMetadata {
+            packages,
+            workspace_members: vec!["app 0.1.0".to_string()],
+            workspace_default_members: Vec::new(),
+            workspace_root: "/ws".to_string(),
+            resolve: Some(MetadataResolve { nodes }),
+        }
+    }
+
+    fn picked(meta: &CargoMetadata, name: &str) -> Result<Option<String>, String> {
+        pick_package(meta, name).map(|p| p.map(|p| p.id.clone()))
+    }
+
+    #[test]
+    fn workspace_member_wins_over_a_dependency_of_the_same_name() {
+        let mut meta = two_logs(&["other 1.0.0"]);
+        meta.workspace_members.push("log 0.3.0".to_string());
+        assert_eq!(picked(&meta, "log"), Ok(Some("log 0.3.0".to_string())));
+    }
+
+    #[test]
+    fn direct_dependency_of_a_member_wins() {
+        let meta = two_logs(&["other 1.0.0", "log 0.4.0"]);
+        assert_eq!(picked(&meta, "log"), Ok(Some("log 0.4.0".to_string())));
+        assert_eq!(picked(&meta, "log@0.3"), Ok(Some("log 0.3.0".to_string())));
+        assert_eq!(picked(&meta, "missing"), Ok(None));
+    }
+
+    #[test]
+    fn no_single_candidate_is_ambiguous() {
+        let meta = two_logs(&["other 1.0.0", "log 0.3.0", "log 0.4.0"]);
+        let expected = "`log` is ambiguous (log 0.3.0, 0.4.0); write `log@<version>` to pick one";
+        assert_eq!(picked(&meta, "log"), Err(expected.to_string()));
+    }
+
+    #[test]
+    fn default_members_are_selected_without_p() {
+        let mut meta = workspace(&["app", "net"], true);
+        meta.workspace_default_members = vec!["net 0.1.0".to_string()];
+        assert_eq!(selected_by_default(&meta, false), ["net"]);
+        assert_eq!(selected_by_default(&meta, true), ["app", "net", "root"]);
+    }
+
+    #[test]
+    fn older_cargo_selects_the_root_package_or_the_whole_workspace() {
+        let with_root = workspace(&["app", "net"], true);
+        assert_eq!(selected_by_default(&with_root, false), ["root"]);
+        let virtual_ws = workspace(&["app", "net"], false);
+        assert_eq!(selected_by_default(&virtual_ws, false), ["app", "net"]);
+    }
 }
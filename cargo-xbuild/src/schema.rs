@@ -0,0 +1,160 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+/// `schema`: print a JSON Schema (draft-07) for `defconfig.toml`, so a
+/// TOML language server can validate and complete it. With Taplo (Even
+/// Better TOML), save it next to the file and start `defconfig.toml` with
+///
+/// ```toml
+/// #:schema ./defconfig.schema.json
+/// ```
+///
+/// Fragments in `defconfig.d/` take the same `[xconfig.*]` and
+/// `[virtual]` tables, and validate against it too. The schema is
+/// stricter than xbuild, which ignores unknown fields: an editor flags
+/// them, as they are most likely typos.
+pub fn print_schema() -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&schema())?);
+    Ok(())
+}
+
+/// The schema itself. It mirrors `types::DefConfig`, `XBuildSettings` and
+/// `XConfigDef` field for field; a new field there belongs here too.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "defconfig.toml",
+        "description": "cargo-xbuild config definitions: every switch .config.toml may set",
+        "type": "object",
+        "properties": {
+            "groups": {
+                "description": "Display order of the `group` sections, then the rest A-Z",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "xbuild": xbuild_settings(),
+            "virtual": {
+                "description": "Derived cfgs: SMP_NET = \"SMP && NET\"",
+                "type": "object",
+                "additionalProperties": { "type": "string" }
+            },
+            "xconfig": {
+                "description": "The config switches, one table per key",
+                "type": "object",
+                "additionalProperties": xconfig_def()
+            }
+        }
+    })
+}
+
+/// `[xbuild]`: `types::XBuildSettings`.
+fn xbuild_settings() -> Value {
+    json!({
+        "description": "Settings for cargo-xbuild itself",
+        "type": "object",
+        "properties": {
+            "sync_cargo_config": {
+                "description": "Regenerate .cargo/config.toml",
+                "type": "boolean",
+                "default": true
+            },
+            "sync_vscode": {
+                "description": "Regenerate .vscode/settings.json",
+                "type": "boolean",
+                "default": true
+            },
+            "vscode_extra_env": {
+                "description": "Also set rust-analyzer.cargo.extraEnv (XBUILD_CONFIG)",
+                "type": "boolean",
+                "default": false
+            },
+            "sync_idea": {
+                "description": "Update .idea/workspace.xml (when .idea/ exists or --idea)",
+                "type": "boolean",
+                "default": true
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+/// One `[xconfig.<KEY>]` table: `types::XConfigDef`.
+fn xconfig_def() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "description": {
+                "description": "Human-readable description of this config switch",
+                "type": "string"
+            },
+            "type": {
+                "description": "Value type",
+                "enum": ["bool", "int", "string", "list"],
+                "default": "bool"
+            },
+            "element": {
+                "description": "Element type of a list",
+                "enum": ["string", "int"],
+                "default": "string"
+            },
+            "default": {
+                "description": "Default value, matching `type`, or computed: { expr = \"..\" }",
+                "anyOf": [
+                    { "type": ["boolean", "integer", "string"] },
+                    { "type": "array", "items": { "type": ["integer", "string"] } },
+                    {
+                        "type": "object",
+                        "properties": { "expr": { "type": "string" } },
+                        "required": ["expr"]
+                    }
+                ]
+            },
+            "aliases": {
+                "description": "Extra cfg names set along with the key's own (bool, list)",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "required_if": {
+                "description": "Condition under which this key must have a non-empty value",
+                "type": "string"
+            },
+            "tristate": {
+                "description": "A bool key that may also be \"m\", setting <KEY>_MODULE instead",
+                "type": "boolean",
+                "default": false
+            },
+            "host": {
+                "description": "Whether the key's cfgs also reach proc-macros and build scripts",
+                "type": "boolean",
+                "default": true
+            },
+            "emit": {
+                "description": "Whether the key sets its cfg, the features it maps to, or both",
+                "enum": ["cfg", "feature", "both"],
+                "default": "both"
+            },
+            "conflicts_with": {
+                "description": "Keys that must not be enabled together with this one",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "group": {
+                "description": "Section this key is listed under",
+                "type": "string"
+            },
+            "menu": {
+                "description": "Alias of `group`",
+                "type": "string"
+            },
+            "min": {
+                "description": "Inclusive lower bound of an int",
+                "type": "integer"
+            },
+            "max": {
+                "description": "Inclusive upper bound of an int",
+                "type": "integer"
+            }
+        },
+        "additionalProperties": false
+    })
+}
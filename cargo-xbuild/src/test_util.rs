@@ -0,0 +1,28 @@
+//! Helpers shared by the unit tests.
+
+use std::path::PathBuf;
+
+/// A fresh directory under the system temp dir, unique to this process
+/// and `name` (use the test's name), holding `files`: path relative to
+/// it → content. Parent directories are created as needed.
+pub fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xbuild-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (path, content) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+/// `names` as owned strings, for key lists.
+pub fn strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+/// A command line split on whitespace, as the args cargo-xbuild gets.
+pub fn words(line: &str) -> Vec<String> {
+    line.split_whitespace().map(str::to_string).collect()
+}
@@ -0,0 +1,51 @@
+use log::{log, Level};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Whether `--timings` was given: stage times are logged at info level
+/// rather than debug.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Every stage timed so far, in the order they ended.
+static STAGES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// `--timings`: log stage times, and the summary, at info level.
+pub fn show() {
+    SHOWN.store(true, Ordering::Relaxed);
+}
+
+fn level() -> Level {
+    match SHOWN.load(Ordering::Relaxed) {
+        true => Level::Info,
+        false => Level::Debug,
+    }
+}
+
+/// Run `f` as the pipeline stage `name`, logging and recording how long
+/// it took, e.g. `time("manifest scan", || collect_all_metadata(..))`.
+pub fn time<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let out = f();
+    let took = start.elapsed();
+    log!(level(), "{name}: {took:.1?}");
+    let mut stages = STAGES.lock().unwrap_or_else(|e| e.into_inner());
+    stages.push((name, took));
+    out
+}
+
+/// Log each stage's share of the run as an aligned table, ending with
+/// the total. Nothing when no stage was timed.
+pub fn summary() {
+    let stages = STAGES.lock().unwrap_or_else(|e| e.into_inner());
+    if stages.is_empty() {
+        return;
+    }
+    let width = stages.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let total: Duration = stages.iter().map(|(_, took)| *took).sum();
+    log!(level(), "timings:");
+    for (name, took) in stages.iter() {
+        log!(level(), "  {name:<width$}  {took:>9.1?}");
+    }
+    log!(level(), "  {:<width$}  {total:>9.1?}", "total");
+}
@@ -1,33 +1,208 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 /// `defconfig.toml` schema — defines all xconfig keys with metadata.
 #[derive(Deserialize)]
 pub struct DefConfig {
+    /// Display order of the `group` sections. Groups not listed follow in
+    /// alphabetical order, then the ungrouped keys.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// `[xbuild]`: settings for cargo-xbuild itself
+    #[serde(default)]
+    pub xbuild: XBuildSettings,
+    /// `[virtual]`: derived cfgs, e.g. `SMP_NET = "SMP && NET"`, set when
+    /// the expression holds over the active keys
+    #[serde(default, rename = "virtual")]
+    pub virtuals: HashMap<String, String>,
     pub xconfig: Option<HashMap<String, XConfigDef>>,
 }
 
-/// A single xconfig definition entry in `defconfig.toml`.
+/// A `defconfig.d/*.toml` fragment: more keys and virtual cfgs for
+/// `defconfig.toml`, typically one subsystem's.
+#[derive(Deserialize)]
+pub struct DefConfigFragment {
+    #[serde(default, rename = "virtual")]
+    pub virtuals: HashMap<String, String>,
+    #[serde(default)]
+    pub xconfig: HashMap<String, XConfigDef>,
+}
+
+/// `[xbuild]` section of `defconfig.toml`. Every IDE file is synced
+/// unless switched off here, e.g. when it is maintained by hand.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct XBuildSettings {
+    /// Regenerate `.cargo/config.toml`
+    #[serde(default = "default_true")]
+    pub sync_cargo_config: bool,
+    /// Regenerate `.vscode/settings.json`
+    #[serde(default = "default_true")]
+    pub sync_vscode: bool,
+    /// Also set `rust-analyzer.cargo.extraEnv` there to the env build
+    /// scripts see under xbuild (`XBUILD_CONFIG`), so rust-analyzer runs
+    /// them the same way. Off by default.
+    #[serde(default)]
+    pub vscode_extra_env: bool,
+    /// Update `.idea/workspace.xml` (when `.idea/` exists or `--idea`)
+    #[serde(default = "default_true")]
+    pub sync_idea: bool,
+}
+
+impl Default for XBuildSettings {
+    fn default() -> Self {
+        XBuildSettings {
+            sync_cargo_config: true,
+            sync_vscode: true,
+            vscode_extra_env: false,
+            sync_idea: true,
+        }
+    }
+}
+
+/// A single xconfig definition entry in `defconfig.toml`. Its fields
+/// are described to editors by `schema::schema`, which must follow them.
 #[derive(Deserialize, Clone)]
 pub struct XConfigDef {
     /// Human-readable description of this config switch
     #[serde(default)]
     pub description: Option<String>,
-    /// Value type (currently only "bool"), reserved for future extension
+    /// Value type: "bool" (the default), "int", "string" or "list"
     #[serde(rename = "type", default = "default_type")]
     pub typ: String,
-    /// Default value when generating .config.toml
+    /// Element type of a "list": "string" (the default) or "int"
+    #[serde(default)]
+    pub element: Option<String>,
+    /// Default value when generating .config.toml; must match `typ`.
+    /// `{ expr = "if smp then 4 else 1" }` computes it from other keys
+    /// instead (see `config::resolve_values`).
+    #[serde(default)]
+    pub default: Option<toml::Value>,
+    /// Extra cfg names set along with the key's own, e.g. an old name
+    /// kept during a rename (`aliases = ["CONFIG_SMP"]`); bool and list
+    /// keys only
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Condition over bool/list keys (`"NET"`, `"NET && !DHCP"`) under
+    /// which this key must have a non-empty value; int, string and list
+    /// keys only
+    #[serde(default)]
+    pub required_if: Option<String>,
+    /// A bool key that may also be `"m"` (module), as in Kconfig, besides
+    /// `"y"`/`true` (built in) and `"n"`/`false` (off). A module is not
+    /// built in: the key's own cfg, and the features it maps to, stay off.
+    /// Instead `<KEY>_MODULE` is set as a cfg (see
+    /// `config::module_name`) and can be mapped to features in
+    /// `[package.metadata.xconfig]` like any key, e.g. to turn on a crate
+    /// that builds the module as a `cdylib`. Building and loading that
+    /// artifact is up to the project.
     #[serde(default)]
-    pub default: bool,
+    pub tristate: bool,
+    /// Whether the key's cfgs also reach host compilations: proc-macros
+    /// and build scripts. `host = false` keeps a target-only cfg (say, a
+    /// board or architecture switch) out of code that runs on the build
+    /// machine.
+    #[serde(default = "default_true")]
+    pub host: bool,
+    /// How the key reaches the code when it is on: `"cfg"` sets only its
+    /// cfg (`#[cfg(SMP)]`), `"feature"` only the crate features it maps
+    /// to in `[package.metadata.xconfig]` (`#[cfg(feature = "smp")]`), and
+    /// `"both"` (the default) does both. With `"feature"` the cfg is not
+    /// even declared to `--check-cfg`, so a stray `#[cfg(SMP)]` warns.
+    /// Covers the key's aliases and module cfg too.
+    #[serde(default = "default_emit")]
+    pub emit: String,
+    /// Keys that must not be enabled together with this one
+    #[serde(default)]
+    pub conflicts_with: Vec<String>,
+    /// Section this key is listed under (`menu` is accepted as an alias)
+    #[serde(default, alias = "menu")]
+    pub group: Option<String>,
+    /// Inclusive bounds for `type = "int"`; absent means unbounded
+    #[serde(default)]
+    pub min: Option<i64>,
+    #[serde(default)]
+    pub max: Option<i64>,
 }
 
 fn default_type() -> String {
     "bool".to_string()
 }
 
+fn default_emit() -> String {
+    "both".to_string()
+}
+
+impl XConfigDef {
+    /// Why an int `val` is out of `min`/`max`, e.g. `0 below min 4096`.
+    /// `None` when in range, unbounded, or not an int.
+    pub fn range_error(&self, val: &toml::Value) -> Option<String> {
+        let n = val.as_integer()?;
+        match (self.min, self.max) {
+            (Some(min), _) if n < min => Some(format!("{n} below min {min}")),
+            (_, Some(max)) if n > max => Some(format!("{n} above max {max}")),
+            _ => None,
+        }
+    }
+
+    /// Whether the key sets its cfg (`emit` is not `"feature"`).
+    pub fn emits_cfg(&self) -> bool {
+        self.emit != "feature"
+    }
+
+    /// Whether the key enables the features it maps to (`emit` is not
+    /// `"cfg"`).
+    pub fn emits_features(&self) -> bool {
+        self.emit != "cfg"
+    }
+
+    /// Element type of a list key; "string" unless `element` says otherwise.
+    pub fn element_type(&self) -> &str {
+        self.element.as_deref().unwrap_or("string")
+    }
+
+    /// The expression of a computed default (`default = { expr = ".." }`).
+    pub fn default_expr(&self) -> Option<&str> {
+        self.default.as_ref()?.get("expr")?.as_str()
+    }
+
+    /// Whether `val` is a tristate key's `"m"`.
+    pub fn is_module(&self, val: &toml::Value) -> bool {
+        self.tristate && val.as_str() == Some("m")
+    }
+
+    /// `val` as the bool it stands for in conditions and cfgs: for a
+    /// tristate key, whether it is built in (`true` or `"y"`). Other
+    /// values are returned as-is.
+    pub fn built_in(&self, val: &toml::Value) -> toml::Value {
+        match val.as_str() {
+            Some(s) if self.tristate => toml::Value::Boolean(s == "y"),
+            _ => val.clone(),
+        }
+    }
+
+    /// The declared literal default, or the type's zero value (`false`,
+    /// `0`, `""`, `[]`) when `default` is omitted or computed.
+    pub fn default_value(&self) -> toml::Value {
+        match &self.default {
+            Some(v) if !v.is_table() => v.clone(),
+            _ => match self.typ.as_str() {
+                "int" => toml::Value::Integer(0),
+                "string" => toml::Value::String(String::new()),
+                "list" => toml::Value::Array(Vec::new()),
+                _ => toml::Value::Boolean(false),
+            },
+        }
+    }
+}
+
 /// `.config.toml` schema — uses `toml::Value` for type validation.
 #[derive(Deserialize)]
 pub struct ProjectConfig {
+    /// Files merged in before this one's own keys, relative to it
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Values by key, as written: a nested table groups keys under a
+    /// common prefix until flattened (see `config::flatten_xconfig`)
     pub xconfig: Option<HashMap<String, toml::Value>>,
 }
 
@@ -35,18 +210,186 @@ pub struct ProjectConfig {
 #[derive(Deserialize)]
 pub struct CargoToml {
     pub package: Option<Package>,
+    #[serde(flatten)]
+    pub deps: DepTables,
+    /// `[target.'cfg(..)'.dependencies]` and friends
+    #[serde(default)]
+    pub target: HashMap<String, DepTables>,
+    /// `[features]` (only the names are used)
+    #[serde(default)]
+    pub features: HashMap<String, Vec<String>>,
+}
+
+/// The dependency tables of a manifest (only the names are used).
+#[derive(Deserialize, Default)]
+pub struct DepTables {
+    #[serde(default)]
+    pub dependencies: HashMap<String, toml::Value>,
+    #[serde(default, rename = "dev-dependencies")]
+    pub dev_dependencies: HashMap<String, toml::Value>,
+    #[serde(default, rename = "build-dependencies")]
+    pub build_dependencies: HashMap<String, toml::Value>,
+}
+
+impl CargoToml {
+    /// Every dependency name declared anywhere in the manifest,
+    /// normalized with underscores.
+    pub fn dependency_names(&self) -> HashSet<String> {
+        std::iter::once(&self.deps)
+            .chain(self.target.values())
+            .flat_map(|t| {
+                t.dependencies
+                    .keys()
+                    .chain(t.dev_dependencies.keys())
+                    .chain(t.build_dependencies.keys())
+            })
+            .map(|name| name.replace('-', "_"))
+            .collect()
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Package {
-    #[allow(dead_code)]
     pub name: Option<String>,
     pub metadata: Option<Metadata>,
 }
 
 #[derive(Deserialize)]
 pub struct Metadata {
-    pub xconfig: Option<HashMap<String, Vec<String>>>,
+    pub xconfig: Option<XConfigMetadata>,
+}
+
+/// `[package.metadata.xconfig]`: the key mappings, plus the reserved
+/// `override` sub-table.
+#[derive(Deserialize)]
+pub struct XConfigMetadata {
+    #[serde(default, rename = "override")]
+    pub overrides: XConfigOverride,
+    #[serde(flatten)]
+    pub keys: HashMap<String, XConfigMapping>,
+}
+
+/// `[package.metadata.xconfig.override]`: this crate's escape hatches
+/// from mappings declared elsewhere.
+///
+/// ```toml
+/// [package.metadata.xconfig.override]
+/// exclude = ["smp"]   # never inject this crate's `smp` feature
+/// ```
+#[derive(Deserialize, Default)]
+pub struct XConfigOverride {
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// What one xconfig key maps to in `[package.metadata.xconfig]`:
+///
+/// ```toml
+/// net = "net"                                     # a single spec
+/// smp = ["smp", "crate_b/smp"]                    # a list of specs
+/// log = { crate_a = "log", crate_b = ["log", "trace"] }  # grouped by crate
+/// drivers = "drivers/{}"                          # one per list element
+/// fs = { target = "crate_a/fat", why = "root fs is FAT" }  # with a reason
+/// ```
+///
+/// Lists may mix plain specs, grouped tables and described specs.
+/// Everything expands to `"crate/feature"` (or bare `"feature"`) spec
+/// strings. A table with exactly `target` and `why` is a described spec,
+/// not crates named so.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum XConfigMapping {
+    One(String),
+    Many(Vec<MappingEntry>),
+    Described(Described),
+    Grouped(HashMap<String, OneOrMany>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum MappingEntry {
+    Spec(String),
+    Described(Described),
+    Grouped(HashMap<String, OneOrMany>),
+}
+
+/// `{ target = "crate/feat", why = "..." }`: specs along with why the
+/// mapping exists, shown by `explain` and `--dump-metadata-map`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Described {
+    pub target: OneOrMany,
+    pub why: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            OneOrMany::One(s) => std::slice::from_ref(s),
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+impl XConfigMapping {
+    /// Expand into spec strings, grouped tables in crate-name order.
+    pub fn specs(&self) -> Vec<String> {
+        fn expand(group: &HashMap<String, OneOrMany>, out: &mut Vec<String>) {
+            let mut crates: Vec<&String> = group.keys().collect();
+            crates.sort();
+            for krate in crates {
+                for feat in group[krate].as_slice() {
+                    out.push(format!("{krate}/{feat}"));
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        match self {
+            XConfigMapping::One(spec) => out.push(spec.clone()),
+            XConfigMapping::Many(entries) => {
+                for entry in entries {
+                    match entry {
+                        MappingEntry::Spec(spec) => out.push(spec.clone()),
+                        MappingEntry::Described(d) => out.extend_from_slice(d.target.as_slice()),
+                        MappingEntry::Grouped(group) => expand(group, &mut out),
+                    }
+                }
+            }
+            XConfigMapping::Described(d) => out.extend_from_slice(d.target.as_slice()),
+            XConfigMapping::Grouped(group) => expand(group, &mut out),
+        }
+        out
+    }
+
+    /// The reason given for each described spec, by spec.
+    pub fn reasons(&self) -> BTreeMap<String, String> {
+        let described: Vec<&Described> = match self {
+            XConfigMapping::Described(d) => vec![d],
+            XConfigMapping::Many(entries) => entries
+                .iter()
+                .filter_map(|entry| match entry {
+                    MappingEntry::Described(d) => Some(d),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut out = BTreeMap::new();
+        for d in described {
+            for spec in d.target.as_slice() {
+                out.insert(spec.clone(), d.why.clone());
+            }
+        }
+        out
+    }
 }
 
 /// Partial `Cargo.toml` – for reading `[features]` of a dependency
@@ -74,19 +417,38 @@ pub struct CargoTarget {
 #[derive(Deserialize)]
 pub struct CargoMetadata {
     pub packages: Vec<MetadataPackage>,
+    /// Package ids of the workspace members
+    #[serde(default)]
+    pub workspace_members: Vec<String>,
+    /// Package ids cargo selects without `-p` (cargo 1.71+)
+    #[serde(default)]
+    pub workspace_default_members: Vec<String>,
+    #[serde(default)]
+    pub workspace_root: String,
+    /// The resolved dependency graph; absent with `--no-deps`
+    #[serde(default)]
+    pub resolve: Option<MetadataResolve>,
 }
 
 #[derive(Deserialize)]
 pub struct MetadataPackage {
+    pub id: String,
     pub name: String,
+    pub version: String,
     pub manifest_path: String,
     #[serde(default)]
     pub dependencies: Vec<MetadataDep>,
+    /// The `[features]` table
+    #[serde(default)]
+    pub features: HashMap<String, Vec<String>>,
 }
 
 #[derive(Deserialize)]
 pub struct MetadataDep {
     pub name: String,
+    /// `null` for normal deps, `"dev"` or `"build"` otherwise
+    #[serde(default)]
+    pub kind: Option<String>,
     #[serde(default)]
     pub source: Option<String>,
     #[serde(default)]
@@ -104,12 +466,43 @@ pub struct MetadataDep {
     pub uses_default_features: bool,
 }
 
+#[derive(Deserialize)]
+pub struct MetadataResolve {
+    pub nodes: Vec<MetadataNode>,
+}
+
+/// One package in the resolved graph, with the edges cargo activated
+#[derive(Deserialize)]
+pub struct MetadataNode {
+    pub id: String,
+    #[serde(default)]
+    pub deps: Vec<NodeDep>,
+    /// The features cargo enables on the package
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NodeDep {
+    /// Package id of the dependency
+    pub pkg: String,
+    #[serde(default)]
+    pub dep_kinds: Vec<DepKindInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct DepKindInfo {
+    /// `null` for normal deps, `"dev"` or `"build"` otherwise
+    #[serde(default)]
+    pub kind: Option<String>,
+}
+
 fn default_true() -> bool {
     true
 }
 
 /// Info about an optional dep that needs extern injection.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExternDep {
     /// Crate name (normalized with underscores)
     pub crate_name: String,
@@ -117,9 +510,11 @@ pub struct ExternDep {
     pub pkg_name: String,
     /// Dependency source spec for Cargo.toml
     pub source: DepSource,
+    /// Feature of the target crate whose `dep:` entry pulled this in
+    pub feature: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DepSource {
     Git(String),
     Path(String),
@@ -129,3 +524,67 @@ pub enum DepSource {
         default_features: bool,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `type = "int"` key with `default`, bounded by `min` and `max`.
+    fn int_key(default: i64, min: Option<i64>, max: Option<i64>) -> XConfigDef {
+        XConfigDef {
+            description: None,
+            typ: "int".to_string(),
+            element: None,
+            default: Some(toml::Value::Integer(default)),
+            aliases: Vec::new(),
+            required_if: None,
+            tristate: false,
+            host: true,
+            emit: default_emit(),
+            conflicts_with: Vec::new(),
+            group: None,
+            min,
+            max,
+        }
+    }
+
+    #[test]
+    fn int_range() {
+        let stack_size = int_key(8192, Some(4096), Some(65536));
+        let check = |n: i64| stack_size.range_error(&toml::Value::Integer(n));
+        assert_eq!(check(0).as_deref(), Some("0 below min 4096"));
+        assert_eq!(check(65537).as_deref(), Some("65537 above max 65536"));
+        assert_eq!(check(4096), None);
+        assert_eq!(check(65536), None);
+        assert_eq!(stack_size.range_error(&toml::Value::Boolean(true)), None);
+    }
+
+    #[test]
+    fn absent_bounds_are_unbounded() {
+        let min = toml::Value::Integer(i64::MIN);
+        let max = toml::Value::Integer(i64::MAX);
+        assert_eq!(int_key(0, None, None).range_error(&min), None);
+        assert_eq!(int_key(1, Some(1), None).range_error(&max), None);
+    }
+
+    #[test]
+    fn described_specs_parse_alongside_plain_ones() {
+        let metadata = r#"
+fs = { target = "crate_a/fat", why = "root fs is FAT" }
+net = ["net", { target = ["crate_b/tcp", "crate_b/udp"], why = "sockets" }]
+smp = "crate_b/smp"
+"#;
+        let mappings: HashMap<String, XConfigMapping> = toml::from_str(metadata).unwrap();
+        let fs = &mappings["fs"];
+        assert_eq!(fs.specs(), ["crate_a/fat"]);
+        let why = |spec: &str, why: &str| (spec.to_string(), why.to_string());
+        let fat = [why("crate_a/fat", "root fs is FAT")];
+        assert_eq!(fs.reasons(), BTreeMap::from(fat));
+        let net = &mappings["net"];
+        assert_eq!(net.specs(), ["net", "crate_b/tcp", "crate_b/udp"]);
+        let sockets = [why("crate_b/tcp", "sockets"), why("crate_b/udp", "sockets")];
+        assert_eq!(net.reasons(), BTreeMap::from(sockets));
+        assert_eq!(mappings["smp"].specs(), ["crate_b/smp"]);
+        assert!(mappings["smp"].reasons().is_empty());
+    }
+}
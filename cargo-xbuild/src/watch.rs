@@ -0,0 +1,91 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::cli::Options;
+use crate::config::ConfigPaths;
+
+/// How long the config files must stay quiet before a re-sync: editors
+/// often save in several writes (truncate, write, rename).
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// `watch`: re-run validation and the IDE sync whenever `.config.toml`,
+/// a file it includes, `defconfig.toml` or a `defconfig.d/` fragment
+/// changes, until killed. A failed sync is reported and watching goes on.
+///
+/// The directories are watched rather than the files, so editors that
+/// save by replacing the file are still seen. The include chain is
+/// resolved again after every sync, so includes added later are watched
+/// too.
+pub fn watch(paths: &ConfigPaths, opts: &Options) -> Result<()> {
+    let fragments = paths.defconfig.with_file_name("defconfig.d");
+    let mut dirs: Vec<PathBuf> = [&paths.config, &paths.defconfig]
+        .iter()
+        .filter_map(|p| p.parent().map(Path::to_path_buf))
+        .collect();
+    if fragments.is_dir() {
+        dirs.push(fragments.clone());
+    }
+    dirs.sort();
+    dirs.dedup();
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("start file watcher")?;
+    for dir in &dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("watch {}", dir.display()))?;
+    }
+
+    resync(paths, opts);
+    info!("watching {} for changes", paths.config_label());
+    loop {
+        let includes = crate::config::include_chain(&paths.config);
+        for dir in includes.iter().filter_map(|p| p.parent()) {
+            if dirs.iter().any(|d| d == dir) {
+                continue;
+            }
+            // Tried once: a missing directory is not retried on every event
+            dirs.push(dir.to_path_buf());
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                error!("watch {}: {e}", dir.display());
+            }
+        }
+        let relevant = |path: &Path| {
+            path == paths.config
+                || path == paths.defconfig
+                || path.parent() == Some(&*fragments)
+                || includes.iter().any(|i| i == path)
+        };
+
+        let event = rx.recv().context("file watcher stopped")?;
+        if !is_change(&event, &relevant) {
+            continue;
+        }
+        // Wait out the rest of a burst of writes
+        while let Ok(_event) = rx.recv_timeout(DEBOUNCE) {}
+        resync(paths, opts);
+    }
+}
+
+/// Whether `event` modified one of the files `relevant` accepts.
+fn is_change(event: &notify::Result<notify::Event>, relevant: &impl Fn(&Path) -> bool) -> bool {
+    match event {
+        Ok(event) => !event.kind.is_access() && event.paths.iter().any(|p| relevant(p)),
+        Err(e) => {
+            error!("file watcher: {e}");
+            false
+        }
+    }
+}
+
+/// One validation + IDE sync, reporting rather than returning its error.
+fn resync(paths: &ConfigPaths, opts: &Options) {
+    match crate::sync(paths, opts) {
+        Ok(()) => info!("synced with {}", paths.config_label()),
+        Err(e) => error!("{e:#}"),
+    }
+}
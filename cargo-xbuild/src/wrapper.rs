@@ -1,28 +1,107 @@
-use anyhow::{Context, Result};
-use std::process::Command;
+use anyhow::{bail, Context, Result};
+use log::trace;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+
+use crate::types::ExternDep;
 
 /// RUSTC_WRAPPER mode: intercept rustc invocations to inject
 /// `--cfg feature="…"` and `--extern name=/path/to/rlib`.
+///
+/// Env format, `;`-separated entries keyed by crate:
+///   - `XCONFIG_FEATURES`: `crate[@version]:feat1,feat2`
+///   - `XCONFIG_EXTERNS`:  `crate[@version]:extern_name=/path/to/lib.rlib`
+///
+/// Either may instead come from the file named by `XCONFIG_FEATURES_FILE`
+/// / `XCONFIG_EXTERNS_FILE`, used for values too big for the environment.
+///
+/// `crate` is matched against `--crate-name`, with `-` and `_` treated as
+/// the same character (manifests may use either; rustc always sees `_`).
+/// The optional `@version` is
+/// matched against `CARGO_PKG_VERSION` (set by cargo for every rustc it
+/// spawns) so that, when two versions of a crate are compiled, only the
+/// intended one receives the injection.
+///
+/// Flags are added in env order, which `encode_features` and
+/// `encode_externs` keep sorted, so a given plan always produces the same
+/// rustc command line on every machine (and shared caches can hit).
+///
+/// `XCONFIG_TARGET_ONLY` lists (`,`-separated) cfgs that must not reach
+/// host compilations; they are removed from the command line of
+/// proc-macros (`--crate-type proc-macro`) and build scripts (which
+/// cargo compiles as `--crate-name build_script_*`). Without `--target`
+/// cargo passes the same RUSTFLAGS to those as to everything else. With
+/// it, host crates get no RUSTFLAGS at all, and this finds nothing to do.
+///
+/// `XCONFIG_INNER_WRAPPER`, when set, is the user's own `RUSTC_WRAPPER`
+/// (e.g. sccache) that this one replaced: it is run as
+/// `$XCONFIG_INNER_WRAPPER <rustc> <args...>` with the injected args, so
+/// both wrappers see every compilation.
+///
+/// `XCONFIG_TRACE_FILE`, when set, names a file each command is appended
+/// to, as it is finally run (see `trace_command`).
+///
+/// On Unix, a compilation with nothing injected or dropped execs rustc
+/// (or the inner wrapper) in place of this process instead of spawning
+/// it as a child.
 pub fn wrapper_main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let rustc = &args[1];
-    let rustc_args = &args[2..];
+    crate::logging::init(
+        std::env::var(crate::logging::LOG_ENV)
+            .ok()
+            .and_then(|l| l.parse().ok()),
+    );
+
+    // cargo invokes `$RUSTC_WRAPPER <rustc> <args...>`. Keep everything as
+    // OsString so the rustc path (which may contain spaces or be a shim)
+    // and its args are forwarded byte-for-byte; Command never goes
+    // through a shell, so no quoting is involved.
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let (rustc, rustc_args) = match args.get(1..) {
+        Some([rustc, rest @ ..]) => (rustc, rest),
+        _ => bail!("cargo-xbuild in wrapper mode expects `<rustc> [args...]`, got no rustc path"),
+    };
 
-    let mut cmd = Command::new(rustc);
-    cmd.args(rustc_args);
+    let inner = std::env::var_os("XCONFIG_INNER_WRAPPER").filter(|w| !w.is_empty());
+    let mut cmd = match &inner {
+        Some(inner) => {
+            let mut cmd = Command::new(inner);
+            cmd.arg(rustc);
+            cmd
+        }
+        None => Command::new(rustc),
+    };
 
     let crate_name = rustc_args
         .windows(2)
         .find(|w| w[0] == "--crate-name")
-        .map(|w| w[1].as_str());
+        .and_then(|w| w[1].to_str());
+    // Set by cargo for every rustc it spawns
+    let version = std::env::var("CARGO_PKG_VERSION").ok();
+    let version = version.as_deref();
+
+    // 0) Drop target-only cfgs from host compilations
+    let target_only = std::env::var("XCONFIG_TARGET_ONLY").unwrap_or_default();
+    let target_only: Vec<&str> = target_only.split(',').filter(|s| !s.is_empty()).collect();
+    let mut injected = false;
+    if !target_only.is_empty() && is_host_compilation(rustc_args, crate_name) {
+        trace!("host compilation of {crate_name:?}: dropping cfgs {target_only:?}");
+        cmd.args(without_cfgs(rustc_args, &target_only));
+        injected = true;
+    } else {
+        cmd.args(rustc_args);
+    }
 
     // 1) Inject --cfg feature="…"
-    if let (Some(name), Ok(feat_env)) = (crate_name, std::env::var("XCONFIG_FEATURES")) {
+    if let (Some(name), Some(feat_env)) = (crate_name, wrapper_env_var("XCONFIG_FEATURES")?) {
         for entry in feat_env.split(';').filter(|s| !s.is_empty()) {
             if let Some((cn, feats)) = entry.split_once(':') {
-                if cn == name {
+                if matches_crate(cn, name, version) {
                     for f in feats.split(',').filter(|s| !s.is_empty()) {
                         cmd.arg("--cfg").arg(format!("feature=\"{f}\""));
+                        injected = true;
                     }
                 }
             }
@@ -30,18 +109,308 @@ pub fn wrapper_main() -> Result<()> {
     }
 
     // 2) Inject --extern name=/path/to/rlib
-    if let (Some(name), Ok(extern_env)) = (crate_name, std::env::var("XCONFIG_EXTERNS")) {
+    if let (Some(name), Some(extern_env)) = (crate_name, wrapper_env_var("XCONFIG_EXTERNS")?) {
         for entry in extern_env.split(';').filter(|s| !s.is_empty()) {
             if let Some((cn, ext_spec)) = entry.split_once(':') {
-                if cn == name {
+                if matches_crate(cn, name, version) {
                     if let Some((ext_name, rlib_path)) = ext_spec.split_once('=') {
                         cmd.arg("--extern").arg(format!("{ext_name}={rlib_path}"));
+                        injected = true;
                     }
                 }
             }
         }
     }
 
-    let status = cmd.status().context("failed to execute rustc")?;
-    std::process::exit(status.code().unwrap_or(1));
+    trace!("rustc command: {cmd:?}");
+    if let Some(path) = std::env::var_os("XCONFIG_TRACE_FILE").filter(|p| !p.is_empty()) {
+        trace_command(&path, crate_name, &cmd)?;
+    }
+    let (what, program) = match &inner {
+        Some(inner) => ("RUSTC_WRAPPER", inner),
+        None => ("rustc", rustc),
+    };
+    let program = Path::new(program).display();
+    // Nothing to inject (most crates): become rustc rather than wait on
+    // it as a child, saving a process per compilation
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        if !injected {
+            let err = cmd.exec();
+            return Err(err).with_context(|| format!("failed to exec {what} `{program}`"));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = injected;
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to spawn {what} `{program}`"))?;
+    std::process::exit(exit_code(status));
+}
+
+/// The value of `var`, or the content of the file `<var>_FILE` names.
+fn wrapper_env_var(var: &str) -> Result<Option<String>> {
+    env_var_or_file(var, |name| std::env::var(name).ok())
+}
+
+/// `wrapper_env_var`, reading the environment through `get`.
+fn env_var_or_file(var: &str, get: impl Fn(&str) -> Option<String>) -> Result<Option<String>> {
+    let path = get(&format!("{var}_FILE")).unwrap_or_default();
+    if path.is_empty() {
+        return Ok(get(var));
+    }
+    let value =
+        std::fs::read_to_string(&path).with_context(|| format!("read {var} from {path}"))?;
+    Ok(Some(value))
+}
+
+/// Append `cmd` to the trace file at `path` as one line, `crate: program
+/// args...` (`?` for rustc runs without a crate, such as cargo's `-vV`
+/// probe). The line goes out in a single write to a file opened for
+/// appending, so lines from rustc processes running in parallel never
+/// interleave.
+fn trace_command(path: &OsStr, crate_name: Option<&str>, cmd: &Command) -> Result<()> {
+    let mut line = format!("{}:", crate_name.unwrap_or("?"));
+    for part in std::iter::once(cmd.get_program()).chain(cmd.get_args()) {
+        line.push(' ');
+        line.push_str(&part.to_string_lossy());
+    }
+    line.push('\n');
+    let path = Path::new(path);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open trace file {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("write trace file {}", path.display()))
+}
+
+/// Whether rustc is building something that runs on the host: a
+/// proc-macro or a build script.
+fn is_host_compilation(args: &[OsString], crate_name: Option<&str>) -> bool {
+    let proc_macro = args.windows(2).any(|w| w[0] == "--crate-type" && w[1] == "proc-macro")
+        || args.iter().any(|a| a == "--crate-type=proc-macro");
+    proc_macro || crate_name.is_some_and(|n| n.starts_with("build_script_"))
+}
+
+/// `args` without any `--cfg=NAME` / `--cfg NAME` setting one of `cfgs`.
+fn without_cfgs(args: &[OsString], cfgs: &[&str]) -> Vec<OsString> {
+    let names = |a: &OsString| a.to_str().is_some_and(|a| cfgs.contains(&a));
+    let mut out = Vec::with_capacity(args.len());
+    let mut iter = args.iter().peekable();
+    while let Some(arg) = iter.next() {
+        let joined = arg.to_str().and_then(|a| a.strip_prefix("--cfg="));
+        if joined.is_some_and(|name| cfgs.contains(&name)) {
+            continue;
+        }
+        if arg == "--cfg" && iter.peek().is_some_and(|next| names(next)) {
+            iter.next();
+            continue;
+        }
+        out.push(arg.clone());
+    }
+    out
+}
+
+/// The exit code to report for rustc's `status`. A rustc killed by a
+/// signal (e.g. SIGKILL from the OOM killer) has no code; report it the
+/// way shells do, as 128 + the signal number, rather than a plain 1.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Does an env entry key (`crate` or `crate@version`) refer to the crate
+/// rustc is compiling right now, `crate_name` at `version`?
+fn matches_crate(key: &str, crate_name: &str, version: Option<&str>) -> bool {
+    let same_name = |cn: &str| cn.replace('-', "_") == crate_name.replace('-', "_");
+    match key.split_once('@') {
+        Some((cn, pinned)) => same_name(cn) && version == Some(pinned),
+        None => same_name(key),
+    }
+}
+
+/// Env key for `crate` (or `crate@0.2`): rustc's spelling of the name
+/// (`-` → `_`), pinned to the exact version when `versions` has one for
+/// it.
+fn env_key(crate_name: &str, versions: &HashMap<String, String>) -> String {
+    let (name, _) = crate::resolve::split_version(crate_name);
+    let name = name.replace('-', "_");
+    match versions.get(crate_name) {
+        Some(v) => format!("{name}@{v}"),
+        None => name,
+    }
+}
+
+/// Encode `XCONFIG_FEATURES` (`crate[@version]:feat1,feat2;…`). Each
+/// crate's features are sorted and deduplicated, so overlapping specs
+/// never inject a feature twice and equal maps always encode the same.
+pub fn encode_features(
+    feature_map: &HashMap<String, Vec<String>>,
+    versions: &HashMap<String, String>,
+) -> String {
+    let mut entries: Vec<String> = feature_map
+        .iter()
+        .map(|(cn, fs)| {
+            let mut fs: Vec<&str> = fs.iter().map(String::as_str).collect();
+            fs.sort_unstable();
+            fs.dedup();
+            format!("{}:{}", env_key(cn, versions), fs.join(","))
+        })
+        .collect();
+    entries.sort();
+    entries.join(";")
+}
+
+/// Encode `XCONFIG_EXTERNS` (`crate[@version]:name=/path/to/lib.rlib;…`),
+/// one entry per extern whose rlib was built in Phase 1.
+pub fn encode_externs(
+    extern_map: &HashMap<String, Vec<ExternDep>>,
+    rlib_paths: &HashMap<String, String>,
+    versions: &HashMap<String, String>,
+) -> String {
+    let mut entries = Vec::new();
+    for (crate_name, deps) in extern_map {
+        for dep in deps {
+            if let Some(rlib_path) = rlib_paths.get(&dep.crate_name) {
+                entries.push(format!(
+                    "{}:{}={rlib_path}",
+                    env_key(crate_name, versions),
+                    dep.crate_name
+                ));
+            }
+        }
+    }
+    entries.sort();
+    entries.join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch;
+
+    #[test]
+    fn pinned_key_matches_only_its_version() {
+        let versions: HashMap<String, String> =
+            [("foo@0.2".to_string(), "0.2.5".to_string())].into();
+        let old = env_key("foo@0.2", &versions);
+        let new = env_key("foo", &versions);
+        assert_eq!((old.as_str(), new.as_str()), ("foo@0.2.5", "foo"));
+
+        assert!(matches_crate(&old, "foo", Some("0.2.5")));
+        assert!(!matches_crate(&old, "foo", Some("1.0.0")));
+        assert!(!matches_crate(&old, "foo", None));
+        assert!(matches_crate(&new, "foo", Some("1.0.0")));
+        assert!(!matches_crate(&old, "bar", Some("0.2.5")));
+    }
+
+    #[test]
+    fn dashes_and_underscores_are_the_same_name() {
+        assert_eq!(env_key("net-stack", &HashMap::new()), "net_stack");
+        assert!(matches_crate("net-stack", "net_stack", None));
+        assert!(matches_crate("net_stack@0.1.0", "net_stack", Some("0.1.0")));
+    }
+
+    #[test]
+    fn hyphenated_package_reaches_its_crate() {
+        let features = HashMap::from([("crate-net".to_string(), vec!["tcp".to_string()])]);
+        let encoded = encode_features(&features, &HashMap::new());
+        assert_eq!(encoded, "crate_net:tcp");
+        let (key, _) = encoded.split_once(':').unwrap();
+        assert!(matches_crate(key, "crate_net", Some("0.1.0")));
+    }
+
+    #[test]
+    fn feature_encoding_is_stable_across_runs() {
+        let entries = [("net", "tcp,smp"), ("app", "net,log,smp"), ("log", "std")];
+        // Each map has its own hash seed, so iterates in its own order
+        let encode = |entries: &[(&str, &str)]| {
+            let split = |feats: &str| feats.split(',').map(str::to_string).collect();
+            let map: HashMap<String, Vec<String>> = entries
+                .iter()
+                .map(|(k, feats)| (k.to_string(), split(feats)))
+                .collect();
+            encode_features(&map, &HashMap::new())
+        };
+        let mut reversed = entries;
+        reversed.reverse();
+        let expected = "app:log,net,smp;log:std;net:smp,tcp";
+        for _ in 0..8 {
+            assert_eq!(encode(&entries), expected);
+            assert_eq!(encode(&reversed), expected);
+        }
+    }
+
+    #[test]
+    fn target_only_cfgs_are_dropped_from_host_compilations() {
+        let args = |line: &str| -> Vec<OsString> { line.split(' ').map(OsString::from).collect() };
+        let proc_macro = args("--crate-name serde_derive --crate-type proc-macro --cfg BOARD");
+        let joined = args("--crate-name serde_derive --crate-type=proc-macro --cfg=BOARD");
+        let lib = args("--crate-name app --crate-type lib --cfg BOARD");
+        assert!(is_host_compilation(&proc_macro, Some("serde_derive")));
+        assert!(is_host_compilation(&joined, Some("serde_derive")));
+        assert!(is_host_compilation(&[], Some("build_script_build")));
+        assert!(!is_host_compilation(&lib, Some("app")));
+
+        let stripped = without_cfgs(&proc_macro, &["BOARD"]);
+        let expected = args("--crate-name serde_derive --crate-type proc-macro");
+        assert_eq!(stripped, expected);
+        let cfgs = args("--cfg BOARD --cfg SMP --cfg=ARCH_X86 --cfg feature=\"BOARD\"");
+        let expected = args("--cfg SMP --cfg feature=\"BOARD\"");
+        assert_eq!(without_cfgs(&cfgs, &["BOARD", "ARCH_X86"]), expected);
+    }
+
+    /// What `wrapper_env` passes for `value`: the variables left set, and
+    /// the value as the wrapper reads it back.
+    fn round_trip(name: &str, value: &str) -> (Vec<String>, Option<String>) {
+        let root = scratch(name, &[]);
+        let env = crate::inline_or_file(&root, "0123abcd", "XCONFIG_FEATURES", value.into());
+        let env: HashMap<String, String> = env.unwrap().into();
+        let mut set: Vec<String> = env.keys().cloned().collect();
+        set.retain(|k| !env[k].is_empty());
+        set.sort();
+        let read = env_var_or_file("XCONFIG_FEATURES", |var| env.get(var).cloned()).unwrap();
+        (set, read)
+    }
+
+    #[test]
+    fn small_map_is_passed_inline() {
+        let value = "app:net,smp;log:std";
+        let (set, read) = round_trip("inline-env", value);
+        assert_eq!(set, ["XCONFIG_FEATURES"]);
+        assert_eq!(read.as_deref(), Some(value));
+    }
+
+    #[test]
+    fn map_over_8_kib_is_passed_in_a_file() {
+        let entries: Vec<String> = (0..1000).map(|i| format!("crate_{i}:f{i}")).collect();
+        let value = entries.join(";");
+        assert!(value.len() > 8 * 1024);
+        let (set, read) = round_trip("file-env", &value);
+        assert_eq!(set, ["XCONFIG_FEATURES_FILE"]);
+        assert_eq!(read, Some(value));
+    }
+
+    #[test]
+    fn each_rustc_command_is_traced_on_one_line() {
+        let path = scratch("trace-command", &[]).join("rustc-commands.txt");
+        let mut lib = Command::new("/bin/rustc");
+        lib.args(["--crate-name", "app", "--cfg", "feature=\"net\""]);
+        let mut probe = Command::new("/bin/rustc");
+        probe.arg("-vV");
+        trace_command(path.as_os_str(), Some("app"), &lib).unwrap();
+        trace_command(path.as_os_str(), None, &probe).unwrap();
+        let expected = "app: /bin/rustc --crate-name app --cfg feature=\"net\"\n\
+                        ?: /bin/rustc -vV\n";
+        assert_eq!(std::fs::read_to_string(path).unwrap(), expected);
+    }
 }
@@ -0,0 +1,241 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::types::{CargoMessage, DepSource, ExternDep};
+
+/// Artifacts of the Phase 1 xdeps build.
+pub struct XDeps {
+    /// extern crate name → compiled `.rlib`
+    pub rlib_paths: HashMap<String, String>,
+    /// Every directory holding an xdeps artifact, so transitive deps of the
+    /// injected crates are found via -Ldependency too
+    pub dep_dirs: BTreeSet<String>,
+}
+
+/// Phase 1: auto-generate `target/xdeps/Cargo.toml` listing every optional
+/// dep in `extern_map`, build it, and collect the resulting rlib paths.
+///
+/// `cargo_flags` (see `CargoArgs::xdeps_flags`) are added to the build.
+/// Of the user's network flags only `--offline` belongs there: the xdeps
+/// lockfile is generated here, so `--locked`/`--frozen` would reject it.
+pub fn build_xdeps(
+    root: &Path,
+    extern_map: &HashMap<String, Vec<ExternDep>>,
+    cargo_flags: &[String],
+) -> Result<XDeps> {
+    // Collect all unique ExternDeps we need rlibs for
+    let all_extern_deps: Vec<ExternDep> = extern_map
+        .values()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let needed_externs: Vec<String> = all_extern_deps
+        .iter()
+        .map(|d| d.crate_name.clone())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut rlib_paths: HashMap<String, String> = HashMap::new();
+    let mut dep_dirs: BTreeSet<String> = BTreeSet::new();
+
+    if !needed_externs.is_empty() {
+        // Generate target/xdeps/Cargo.toml from resolved deps
+        let xdeps_dir = root.join("target").join("xdeps");
+        std::fs::create_dir_all(xdeps_dir.join("src"))?;
+
+        // Deduplicate by pkg_name
+        let mut seen = HashSet::new();
+        let mut dep_lines = Vec::new();
+        for dep in &all_extern_deps {
+            if seen.insert(dep.pkg_name.clone()) {
+                let spec = match &dep.source {
+                    DepSource::Git(url) => format!("{} = {{ git = \"{}\" }}", dep.pkg_name, url),
+                    DepSource::Path(p) => format!("{} = {{ path = \"{}\" }}", dep.pkg_name, p),
+                    DepSource::Registry { version, features, default_features } => {
+                        let mut parts = vec![format!("version = \"{}\"", version)];
+                        if !features.is_empty() {
+                            let feat_list = features.iter()
+                                .map(|f| format!("\"{}\"", f))
+                                .collect::<Vec<_>>()
+                                .join(", ");
+                            parts.push(format!("features = [{}]", feat_list));
+                        }
+                        if !default_features {
+                            parts.push("default-features = false".to_string());
+                        }
+                        format!("{} = {{ {} }}", dep.pkg_name, parts.join(", "))
+                    }
+                };
+                dep_lines.push(spec);
+            }
+        }
+        dep_lines.sort();
+
+        let xdeps_toml = format!(
+            "# AUTO-GENERATED by cargo-xbuild — do not edit manually\n\
+             [workspace]\n\
+             \n\
+             [package]\n\
+             name = \"xdeps\"\n\
+             version = \"0.1.0\"\n\
+             edition = \"2024\"\n\
+             \n\
+             [dependencies]\n\
+             {}\n",
+            dep_lines.join("\n")
+        );
+
+        let xdeps_toml_path = xdeps_dir.join("Cargo.toml");
+        let existing = std::fs::read_to_string(&xdeps_toml_path).unwrap_or_default();
+        if existing != xdeps_toml {
+            std::fs::write(&xdeps_toml_path, &xdeps_toml)?;
+            info!("regenerated xdeps/Cargo.toml");
+        }
+
+        // Ensure lib.rs exists
+        let lib_rs = xdeps_dir.join("src").join("lib.rs");
+        if !lib_rs.exists() {
+            std::fs::write(&lib_rs, "// Auto-generated: ensures optional deps are compiled.\n")?;
+        }
+
+        info!("Phase 1: building xdeps for rlibs: {needed_externs:?}");
+
+        let xdeps_manifest = xdeps_dir.join("Cargo.toml");
+        let mut cmd = Command::new("cargo");
+        cmd.args([
+            "build",
+            "--manifest-path",
+            &xdeps_manifest.to_string_lossy(),
+            "--message-format=json",
+        ]);
+        cmd.args(cargo_flags);
+        let output = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .current_dir(root)
+            .output()
+            .context("failed to run cargo build for xdeps")?;
+
+        if !output.status.success() {
+            bail!("Phase 1 (build xdeps) failed");
+        }
+
+        (rlib_paths, dep_dirs) =
+            scan_artifacts(&String::from_utf8_lossy(&output.stdout), &needed_externs);
+
+        for ext in &needed_externs {
+            if !rlib_paths.contains_key(ext) {
+                bail!("rlib for extern `{ext}` not found after building xdeps");
+            }
+        }
+
+        debug!("rlib paths: {rlib_paths:?}");
+        debug!("dependency dirs: {dep_dirs:?}");
+    } else {
+        // No externs needed — write an empty target/xdeps/Cargo.toml
+        let xdeps_dir = root.join("target").join("xdeps");
+        std::fs::create_dir_all(xdeps_dir.join("src"))?;
+        let xdeps_toml = "# AUTO-GENERATED by cargo-xbuild — do not edit manually\n\
+             [workspace]\n\
+             \n\
+             [package]\n\
+             name = \"xdeps\"\n\
+             version = \"0.1.0\"\n\
+             edition = \"2024\"\n\
+             \n\
+             [dependencies]\n";
+        let xdeps_toml_path = xdeps_dir.join("Cargo.toml");
+        let existing = std::fs::read_to_string(&xdeps_toml_path).unwrap_or_default();
+        if existing != xdeps_toml {
+            std::fs::write(&xdeps_toml_path, xdeps_toml)?;
+        }
+        let lib_rs = xdeps_dir.join("src").join("lib.rs");
+        if !lib_rs.exists() {
+            std::fs::write(&lib_rs, "// Auto-generated: ensures optional deps are compiled.\n")?;
+        }
+    }
+
+    Ok(XDeps { rlib_paths, dep_dirs })
+}
+
+/// Scan `cargo build --message-format=json` output for the `.rlib` of
+/// every crate in `needed` (matched by target name, `-` → `_`), and the
+/// directory of every artifact produced along the way.
+fn scan_artifacts(
+    json_lines: &str,
+    needed: &[String],
+) -> (HashMap<String, String>, BTreeSet<String>) {
+    let mut rlib_paths = HashMap::new();
+    let mut dep_dirs = BTreeSet::new();
+    for line in json_lines.lines() {
+        let Ok(msg) = serde_json::from_str::<CargoMessage>(line) else {
+            continue;
+        };
+        if msg.reason != "compiler-artifact" {
+            continue;
+        }
+        let (Some(target), Some(filenames)) = (msg.target, msg.filenames) else {
+            continue;
+        };
+        for f in &filenames {
+            if let Some(dir) = Path::new(f).parent() {
+                dep_dirs.insert(dir.display().to_string());
+            }
+        }
+        let name = target.name.replace('-', "_");
+        if needed.contains(&name) {
+            if let Some(rlib) = filenames.iter().find(|f| f.ends_with(".rlib")) {
+                rlib_paths.insert(name, rlib.clone());
+            }
+        }
+    }
+    (rlib_paths, dep_dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::strings;
+
+    /// `cargo build --message-format=json` output for an xdeps build, cut
+    /// down to the fields read: a build script, a proc-macro, and the
+    /// rlibs of `log` and `serde-json`.
+    const MESSAGES: &str = r#"{"reason":"compiler-artifact","package_id":"serde 1.0.200","target":{"name":"build-script-build","kind":["custom-build"]},"filenames":["/t/debug/build/serde-1a/build-script-build"]}
+{"reason":"build-script-executed","package_id":"serde 1.0.200","out_dir":"/t/debug/build/serde-2b/out"}
+{"reason":"compiler-artifact","package_id":"serde_derive 1.0.200","target":{"name":"serde_derive","kind":["proc-macro"]},"filenames":["/t/debug/deps/libserde_derive-3c.so"]}
+{"reason":"compiler-artifact","package_id":"log 0.4.21","target":{"name":"log","kind":["lib"]},"filenames":["/t/x86_64-unknown-none/debug/deps/liblog-4d.rlib","/t/x86_64-unknown-none/debug/deps/liblog-4d.rmeta"]}
+{"reason":"compiler-artifact","package_id":"serde_json 1.0.117","target":{"name":"serde-json","kind":["lib"]},"filenames":["/t/x86_64-unknown-none/debug/deps/libserde_json-5e.rlib"]}
+   Compiling xdeps v0.0.0 (/t/xdeps)
+{"reason":"build-finished","success":true}
+"#;
+
+    #[test]
+    fn rlibs_of_needed_crates_are_found() {
+        let needed = strings(&["log", "serde_json", "serde_derive", "build_script_build"]);
+        let (rlibs, _) = scan_artifacts(MESSAGES, &needed);
+        let rlib = |file: &str| format!("/t/x86_64-unknown-none/debug/deps/{file}");
+        let expected: HashMap<String, String> = [
+            ("log".to_string(), rlib("liblog-4d.rlib")),
+            ("serde_json".to_string(), rlib("libserde_json-5e.rlib")),
+        ]
+        .into();
+        // A proc-macro and a build script have no rlib to pass as --extern
+        assert_eq!(rlibs, expected);
+    }
+
+    #[test]
+    fn every_artifact_directory_is_searched() {
+        let (_, dirs) = scan_artifacts(MESSAGES, &[]);
+        let expected = [
+            "/t/debug/build/serde-1a",
+            "/t/debug/deps",
+            "/t/x86_64-unknown-none/debug/deps",
+        ];
+        assert_eq!(dirs.iter().collect::<Vec<_>>(), expected);
+    }
+}
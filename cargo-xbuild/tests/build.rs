@@ -0,0 +1,68 @@
+//! End to end: `cargo xbuild` on the workspace in `tests/fixtures/workspace`,
+//! through the real cargo and rustc. `app` gets its `net` feature, and the
+//! optional `netdep` behind it, only from xbuild: cargo itself builds it
+//! without features.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A fresh copy of the fixture workspace for `name`, so builds never write
+/// `.config.toml` or `target/` into the source tree.
+fn workspace(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xbuild-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/workspace");
+    copy_dir(&fixture, &dir);
+    dir
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            std::fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// Run `cargo xbuild args...` in `dir` (as cargo runs the subcommand) and
+/// return its stdout, failing with stderr if it fails.
+fn xbuild(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cargo-xbuild"))
+        .arg("xbuild")
+        .args(args)
+        .current_dir(dir)
+        .env_remove("RUSTC_WRAPPER")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .env_remove("CARGO_BUILD_RUSTC_WRAPPER")
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("CARGO_TARGET_DIR")
+        .output()
+        .unwrap();
+    let (status, stderr) = (output.status, String::from_utf8_lossy(&output.stderr));
+    assert!(
+        status.success(),
+        "cargo xbuild {args:?}: {status}\n{stderr}"
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn active_key_injects_the_feature_and_its_optional_dep() {
+    let dir = workspace("build-net-on");
+    let stdout = xbuild(&dir, &["run", "--offline"]);
+    assert_eq!(stdout, "app: net\nentry: net\n");
+}
+
+#[test]
+fn inactive_key_injects_nothing() {
+    let dir = workspace("build-net-off");
+    std::fs::write(dir.join(".config.toml"), "[xconfig]\nnet = false\n").unwrap();
+    let stdout = xbuild(&dir, &["run", "--offline"]);
+    assert_eq!(stdout, "app: no net\nentry: no net\n");
+}
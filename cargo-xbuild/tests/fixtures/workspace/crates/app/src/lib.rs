@@ -0,0 +1,9 @@
+#[cfg(feature = "net")]
+pub fn status() -> &'static str {
+    netdep::status()
+}
+
+#[cfg(not(feature = "net"))]
+pub fn status() -> &'static str {
+    "no net"
+}
@@ -0,0 +1,3 @@
+pub fn status() -> &'static str {
+    "net"
+}
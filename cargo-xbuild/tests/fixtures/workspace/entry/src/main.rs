@@ -0,0 +1,8 @@
+fn main() {
+    println!("app: {}", app::status());
+
+    #[cfg(NET)]
+    println!("entry: net");
+    #[cfg(not(NET))]
+    println!("entry: no net");
+}
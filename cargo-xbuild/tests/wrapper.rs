@@ -0,0 +1,72 @@
+//! The RUSTC_WRAPPER mode of the binary, run the way cargo runs it:
+//! `cargo-xbuild <rustc> <args...>` with `__XCONFIG_WRAPPER` set.
+#![cfg(unix)]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A fresh directory for `name` holding `inner`, a stand-in for the user's
+/// own RUSTC_WRAPPER that writes its arguments to `args.txt`, one per line.
+fn inner_wrapper(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xbuild-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let inner = dir.join("inner");
+    let out = dir.join("args.txt");
+    let script = format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\n", out.display());
+    std::fs::write(&inner, script).unwrap();
+    std::fs::set_permissions(&inner, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+/// Run the wrapper for `rustc args` with `env`, chained to the inner
+/// wrapper in `dir`, and return the arguments the inner wrapper got.
+fn wrap(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Vec<String> {
+    let status = Command::new(env!("CARGO_BIN_EXE_cargo-xbuild"))
+        .arg("/toolchain/bin/rustc")
+        .args(args)
+        .env("__XCONFIG_WRAPPER", "1")
+        .env("XCONFIG_INNER_WRAPPER", dir.join("inner"))
+        .env_remove("XCONFIG_FEATURES_FILE")
+        .env_remove("XCONFIG_EXTERNS_FILE")
+        .env_remove("XCONFIG_TARGET_ONLY")
+        .env_remove("XCONFIG_TRACE_FILE")
+        .envs(env.iter().copied())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let args = std::fs::read_to_string(dir.join("args.txt")).unwrap();
+    args.lines().map(str::to_string).collect()
+}
+
+#[test]
+fn inner_wrapper_gets_rustc_then_its_args_then_the_injected_ones() {
+    let dir = inner_wrapper("inner-wrapper-chained");
+    let rustc_args = ["--crate-name", "app", "--edition=2021", "src/lib.rs"];
+    let env = [
+        ("XCONFIG_FEATURES", "app:smp;other:net"),
+        ("XCONFIG_EXTERNS", "app:log=/deps/liblog.rlib"),
+    ];
+    let expected = [
+        "/toolchain/bin/rustc",
+        "--crate-name",
+        "app",
+        "--edition=2021",
+        "src/lib.rs",
+        "--cfg",
+        "feature=\"smp\"",
+        "--extern",
+        "log=/deps/liblog.rlib",
+    ];
+    assert_eq!(wrap(&dir, &rustc_args, &env), expected);
+}
+
+#[test]
+fn inner_wrapper_gets_the_command_unchanged_when_nothing_is_injected() {
+    let dir = inner_wrapper("inner-wrapper-untouched");
+    let rustc_args = ["--crate-name", "other", "src/lib.rs"];
+    let args = wrap(&dir, &rustc_args, &[("XCONFIG_FEATURES", "app:smp")]);
+    assert_eq!(args[0], "/toolchain/bin/rustc");
+    assert_eq!(args[1..], rustc_args);
+}